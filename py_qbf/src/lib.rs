@@ -1,20 +1,229 @@
-use dashmap::DashMap;
-use itertools::Itertools;
 use pyo3::prelude::*;
-use qb_finder_core::{QBFinder, expand_pattern, parse_shape, solver};
-use rayon::{iter, prelude::*};
-use rustc_hash::FxHashSet;
-use srs_4l::{
-    board_list,
-    brokenboard::BrokenBoard,
-    gameplay::{Board, Shape},
-    queue::Queue,
+use qb_finder_core::{
+    FindEvent, QBFinder, Ranking,
+    advisor::GameState,
+    build::{BuildMode, Setup},
+    expand_pattern, parse_shape,
+    pc::pc_bag_offset,
+    queue::QueueStart,
+    randomizer::{Randomizer, SolveSource},
+    route::RoutePlan,
+    saves::SavePolicy,
+    solver,
+    task::Task,
 };
+use rustc_hash::FxHashSet;
+use srs_4l::{board_list, brokenboard::BrokenBoard, gameplay::Board};
 use std::{
-    collections::{HashMap, HashSet},
+    collections::HashMap,
     io::Cursor,
+    iter,
+    sync::{
+        Arc, Mutex,
+        mpsc::{self, Receiver, RecvTimeoutError},
+    },
+    thread,
+    time::Duration,
 };
 
+fn queue_start(hold: Option<char>, bag_used: &str) -> QueueStart {
+    QueueStart {
+        hold: hold.and_then(parse_shape),
+        bag_used: bag_used.chars().filter_map(parse_shape).collect(),
+    }
+}
+
+/// `skip_4p` leaves at least one build piece held, and `max_left` is the most a setup may leave.
+/// Methods take `max_left` by keyword only.
+fn build_mode(skip_4p: bool, max_left: usize) -> BuildMode {
+    let min_left = skip_4p as usize;
+    BuildMode {
+        min_left,
+        max_left: max_left.max(min_left),
+    }
+}
+
+/// Reads a save policy such as `T,IO,^O,-`, raising `ValueError` if it is not one.
+fn save_policy(saves: &str) -> PyResult<SavePolicy> {
+    SavePolicy::parse(saves).ok_or_else(|| {
+        PyErr::new::<pyo3::exceptions::PyValueError, _>(format!("invalid save policy {saves:?}"))
+    })
+}
+
+/// Reads a randomizer such as `7bag`, `14bag`, `random` or `tgm`, raising `ValueError` if it is
+/// not one.
+fn randomizer(name: Option<&str>) -> PyResult<Option<Randomizer>> {
+    name.map(|name| {
+        Randomizer::parse(name).ok_or_else(|| {
+            PyErr::new::<pyo3::exceptions::PyValueError, _>(format!("invalid randomizer {name:?}"))
+        })
+    })
+    .transpose()
+}
+
+/// `solve_queue` as a pattern, or with a randomizer the solve pieces already seen before it
+/// deals the rest.
+fn solve_source(solve_queue: &str, randomizer: Option<Randomizer>) -> SolveSource<'_> {
+    match randomizer {
+        Some(randomizer) => SolveSource::Dealt {
+            randomizer,
+            seen: solve_queue,
+        },
+        None => SolveSource::Pattern(solve_queue),
+    }
+}
+
+/// Runs `f` on a copy of `qbf` in another thread so Ctrl-C can cancel it with a
+/// `KeyboardInterrupt`. `progress` is called with `(done, total)`; an exception raised from it
+/// also cancels the call and is re-raised.
+fn run_task<T: Send>(
+    py: Python,
+    qbf: &QBFinder,
+    progress: Option<Py<PyAny>>,
+    f: impl FnOnce(&QBFinder) -> T + Send,
+) -> PyResult<T> {
+    let error: Arc<Mutex<Option<PyErr>>> = Arc::default();
+    let mut qbf = qbf.clone();
+    qbf.task = match progress {
+        Some(callback) => {
+            let error = error.clone();
+            Task::new().with_progress(move |done, total| {
+                Python::attach(|py| match callback.call1(py, (done, total)) {
+                    Ok(_) => true,
+                    Err(e) => {
+                        error.lock().unwrap().get_or_insert(e);
+                        false
+                    }
+                })
+            })
+        }
+        None => Task::new(),
+    };
+
+    let res = thread::scope(|scope| {
+        let handle = scope.spawn(|| f(&qbf));
+        while !handle.is_finished() {
+            py.detach(|| thread::sleep(Duration::from_millis(50)));
+            if let Err(e) = py.check_signals() {
+                error.lock().unwrap().get_or_insert(e);
+                qbf.task.cancel();
+            }
+        }
+        handle.join().unwrap()
+    });
+
+    match error.lock().unwrap().take() {
+        Some(e) => Err(e),
+        None => Ok(res),
+    }
+}
+
+/// `(board, held piece, unplaced build pattern)` of a setup.
+type SetupTuple = (String, Option<char>, String);
+
+fn setup_tuple(setup: &Setup) -> SetupTuple {
+    let mut board_str = String::with_capacity(40);
+    solver::print(&setup.board, &mut board_str);
+    (
+        board_str,
+        setup.held.and_then(|s| s.name().chars().next()),
+        setup.unplaced.clone(),
+    )
+}
+
+/// A setup and the queues meeting each save tier, or a board of `None` with the new best counts
+/// when every setup before is superseded.
+type FindItem = (Option<String>, Option<char>, String, Vec<usize>);
+
+/// Setups of a `find_qb_stream` call as `(board, held, unplaced, saves)`, yielded as soon as they
+/// are found. A board of `None` means every board yielded before no longer has the best score.
+#[pyclass]
+struct FindStream {
+    events: Mutex<Receiver<FindItem>>,
+    task: Task,
+}
+
+#[pymethods]
+impl FindStream {
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__(&self, py: Python) -> PyResult<Option<FindItem>> {
+        loop {
+            let event = py.detach(|| {
+                self.events
+                    .lock()
+                    .unwrap()
+                    .recv_timeout(Duration::from_millis(50))
+            });
+            match event {
+                Ok(event) => return Ok(Some(event)),
+                Err(RecvTimeoutError::Disconnected) => return Ok(None),
+                Err(RecvTimeoutError::Timeout) => {
+                    if let Err(e) = py.check_signals() {
+                        self.task.cancel();
+                        return Err(e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Drop for FindStream {
+    /// Stops the search once the generator is dropped.
+    fn drop(&mut self) {
+        self.task.cancel();
+    }
+}
+
+/// `(board, held, unplaced, saves, secondary saves, min count, build queues covered)` for each
+/// setup. The min count is `None` for setups under 3 pieces.
+type ParetoList = Vec<(
+    String,
+    Option<char>,
+    String,
+    usize,
+    usize,
+    Option<usize>,
+    usize,
+)>;
+
+/// `(board, held, unplaced, save score)` of a setup.
+type ScoredSetup = (String, Option<char>, String, Vec<usize>);
+
+/// Setups, their save counts and, with a randomizer, the chance of each getting the PC.
+type FoundSetups = (Vec<SetupTuple>, Vec<usize>, Option<Vec<f64>>);
+
+/// Each setup as a [`ScoredSetup`], the minimal sets as indices into them, and the build queues
+/// no setup covers.
+type BuildMinimalsResult = (Vec<ScoredSetup>, Vec<Vec<usize>>, Vec<String>);
+
+type RouteProbability = (f64, Vec<f64>, Vec<(String, String)>);
+
+/// `(probability, (low, high), [(rate, (low, high))] per save tier, {cause: runs})`, with 95%
+/// confidence intervals.
+type SimulationResult = (
+    f64,
+    (f64, f64),
+    Vec<(f64, (f64, f64))>,
+    HashMap<String, usize>,
+);
+
+type AdviceList = Vec<(String, String, bool, HashMap<String, f64>, f64)>;
+
+/// What a setup can keep on each solve queue.
+#[pyclass(name = "SaveStats", get_all)]
+struct PySaveStats {
+    /// `(tier, count, percent)` for each tier of the save policy.
+    tiers: Vec<(String, usize, f64)>,
+    /// Chance of meeting each tier first.
+    probabilities: Vec<f64>,
+    /// `(queue, keepable pieces, index of the first tier met)` for each solve queue.
+    queues: Vec<(String, String, Option<usize>)>,
+}
+
 /// Contains (**All Solves**, **All Minimal Sets**, **All Groups of Equivalent Cover**).
 type PySetupMinimals = (Vec<String>, Vec<Vec<String>>, Vec<Vec<String>>);
 
@@ -52,18 +261,76 @@ impl QBSolver {
         self.qbf.hold = hold;
     }
 
-    #[pyo3(signature = (queue, save=None, garb=None))]
+    fn set_cache_size(&self, size: usize) {
+        self.qbf.set_cache_size(size);
+    }
+
+    /// Keeps `find_qb` and `all_minimals` results in `path` across sessions.
+    fn set_store(&mut self, path: &str) -> PyResult<()> {
+        self.qbf.set_store(path).map_err(|e| {
+            PyErr::new::<pyo3::exceptions::PyRuntimeError, _>(format!(
+                "Failed to open result store: {e}"
+            ))
+        })
+    }
+
+    fn clear_cache(&self) {
+        self.qbf.clear_cache();
+    }
+
+    /// Returns `(hits, misses, entries, capacity)` of the solver cache.
+    fn cache_stats(&self) -> (usize, usize, usize, usize) {
+        let stats = self.qbf.cache_stats();
+        (stats.hits, stats.misses, stats.entries, stats.capacity)
+    }
+
+    /// Build and solve patterns for the `pc`th PC of the game, from 1 to 7, as
+    /// `(build_queue, solve_queue)` to pass to the finders with the same `hold`. `build` is the
+    /// pieces seen that the setup is built from and `solve` the ones seen after them.
+    #[pyo3(signature = (pc, build, solve="", hold=None, bag_used=""))]
+    fn pc_queues(
+        &self,
+        pc: usize,
+        build: &str,
+        solve: &str,
+        hold: Option<char>,
+        bag_used: &str,
+    ) -> PyResult<(String, String)> {
+        let seen: Option<Vec<_>> = build
+            .chars()
+            .chain(solve.chars())
+            .map(parse_shape)
+            .collect();
+        pc_bag_offset(pc)
+            .zip(seen)
+            .and_then(|(offset, seen)| {
+                self.qbf
+                    .pc_queues(offset, &queue_start(hold, bag_used), &seen, build.len())
+            })
+            .map(|pc| (pc.build_queue, pc.solve_queue))
+            .ok_or_else(|| {
+                PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                    "queue can't come from a 7-bag at this PC",
+                )
+            })
+    }
+
+    #[pyo3(signature = (queue, save=None, garb=None, hold=None, bag_used=""))]
     fn compute(
         &self,
         py: Python,
         queue: &str,
         save: Option<char>,
         garb: Option<u64>,
+        hold: Option<char>,
+        bag_used: &str,
     ) -> PyResult<Vec<String>> {
+        let start = queue_start(hold, bag_used);
         let solves = py.detach(|| {
             self.qbf.compute(
                 queue,
                 &BrokenBoard::from_garbage(garb.unwrap_or(0)),
+                &start,
                 save.and_then(parse_shape),
             )
         });
@@ -80,7 +347,15 @@ impl QBSolver {
         Ok(res)
     }
 
-    #[pyo3(signature = (build_queue, solve_queue, saves="", skip_4p=false))]
+    /// Setups as `(board, held, unplaced)`, their save counts, and with a `randomizer` the
+    /// chance of each setup getting the PC, else `None`. `progress` is called with
+    /// `(setups evaluated, total)` while searching. With a `randomizer`, `solve_queue` is the
+    /// solve pieces already seen and it deals the rest.
+    ///
+    /// `saves` tiers are separated by commas: the old `"TIO"`, saving T or else I or O, is
+    /// now `"T,IO"`, while `"TIO"` is a single tier saving any of the three.
+    #[pyo3(signature = (build_queue, solve_queue, saves="", skip_4p=false, hold=None, bag_used="", progress=None, *, max_left=1, randomizer=None))]
+    #[allow(clippy::too_many_arguments)]
     fn find_qb(
         &mut self,
         py: Python,
@@ -88,135 +363,462 @@ impl QBSolver {
         solve_queue: &str,
         saves: &str,
         skip_4p: bool,
-    ) -> PyResult<(Vec<String>, usize)> {
-        self.qbf.skip_4p = skip_4p;
-        let (setups, save_count) =
-            py.detach(|| self.qbf.find(build_queue, None, solve_queue, saves, 1));
+        hold: Option<char>,
+        bag_used: &str,
+        progress: Option<Py<PyAny>>,
+        max_left: usize,
+        randomizer: Option<&str>,
+    ) -> PyResult<FoundSetups> {
+        self.qbf.build_mode = build_mode(skip_4p, max_left);
+        let start = queue_start(hold, bag_used);
+        let saves = save_policy(saves)?;
+        let dist = self::randomizer(randomizer)?.map(|r| {
+            self.qbf
+                .solve_distribution(r, build_queue, &start, solve_queue)
+        });
+        let solve_queue = dist
+            .as_ref()
+            .map_or_else(|| solve_queue.to_owned(), |d| d.pattern());
+        let (setups, save_count) = run_task(py, &self.qbf, progress, |qbf| {
+            qbf.find(build_queue, &start, None, &solve_queue, &saves, 1)
+        })?;
+        let probabilities = dist.map(|dist| {
+            setups
+                .iter()
+                .map(|setup| {
+                    self.qbf
+                        .setup_probabilities(setup, &dist, &saves)
+                        .iter()
+                        .sum()
+                })
+                .collect()
+        });
+
+        Ok((
+            setups.iter().map(setup_tuple).collect(),
+            save_count.0,
+            probabilities,
+        ))
+    }
+
+    /// Setups keeping at least `min_saves` queues, or the `top` setups keeping the most, as
+    /// `(board, held, unplaced, saves)` with the most saves first.
+    #[pyo3(signature = (build_queue, solve_queue, saves="", min_saves=1, top=None, skip_4p=false, hold=None, bag_used="", progress=None, *, max_left=1))]
+    #[allow(clippy::too_many_arguments)]
+    fn find_qb_ranked(
+        &mut self,
+        py: Python,
+        build_queue: &str,
+        solve_queue: &str,
+        saves: &str,
+        min_saves: usize,
+        top: Option<usize>,
+        skip_4p: bool,
+        hold: Option<char>,
+        bag_used: &str,
+        progress: Option<Py<PyAny>>,
+        max_left: usize,
+    ) -> PyResult<Vec<ScoredSetup>> {
+        self.qbf.build_mode = build_mode(skip_4p, max_left);
+        let start = queue_start(hold, bag_used);
+        let saves = save_policy(saves)?;
+        let ranking = match top {
+            Some(k) => Ranking::Top(k),
+            None => Ranking::AtLeast(min_saves),
+        };
+        let ranked = run_task(py, &self.qbf, progress, |qbf| {
+            qbf.find_ranked(build_queue, &start, None, solve_queue, &saves, ranking)
+        })?;
 
-        let res: Vec<String> = setups
+        Ok(ranked
             .iter()
-            .map(|solve| {
-                let mut board_str = String::with_capacity(40);
-                solver::print(solve, &mut board_str);
-                board_str
+            .map(|(setup, save_count)| {
+                let (board, held, unplaced) = setup_tuple(setup);
+                (board, held, unplaced, save_count.0.clone())
             })
-            .collect();
+            .collect())
+    }
+
+    /// Setups no other setup beats on every objective.
+    #[pyo3(signature = (build_queue, solve_queue, saves="", skip_4p=false, hold=None, bag_used="", progress=None, *, max_left=1))]
+    #[allow(clippy::too_many_arguments)]
+    fn find_qb_pareto(
+        &mut self,
+        py: Python,
+        build_queue: &str,
+        solve_queue: &str,
+        saves: &str,
+        skip_4p: bool,
+        hold: Option<char>,
+        bag_used: &str,
+        progress: Option<Py<PyAny>>,
+        max_left: usize,
+    ) -> PyResult<ParetoList> {
+        self.qbf.build_mode = build_mode(skip_4p, max_left);
+        let start = queue_start(hold, bag_used);
+        let saves = save_policy(saves)?;
+        let front = run_task(py, &self.qbf, progress, |qbf| {
+            qbf.find_pareto(build_queue, &start, solve_queue, &saves)
+        })?;
 
-        Ok((res, save_count))
+        Ok(front
+            .iter()
+            .map(|(setup, score)| {
+                let (board, held, unplaced) = setup_tuple(setup);
+                (
+                    board,
+                    held,
+                    unplaced,
+                    score.primary_saves,
+                    score.secondary_saves,
+                    score.min_count,
+                    score.build_cover,
+                )
+            })
+            .collect())
     }
 
-    #[pyo3(signature = (fifth))]
-    fn bestsaves(&mut self, py: Python, fifth: &str) -> PyResult<HashMap<String, Vec<String>>> {
-        if fifth.len() != 2 {
-            return Ok(HashMap::new());
-        }
-        fn bestsaves_queues(setup: &BrokenBoard, queue: &str, qbf: &QBFinder) -> Vec<String> {
-            let pieces = "IJLOSZ";
-            let mut res = HashSet::new();
-            let save = Some(Shape::T);
-            for (i, piece) in pieces.chars().enumerate() {
-                let q = format!("{},T,{}", queue, piece);
-                let solves = qbf.compute(
-                    &q,
-                    &BrokenBoard::from_garbage(setup.to_broken_bitboard().0),
-                    save,
-                );
-                if solves.is_empty() {
-                    return vec![];
-                }
-                let cover: HashSet<String> = solves
-                    .iter()
-                    .flat_map(|solve| {
-                        solve
-                            .supporting_queues(srs_4l::gameplay::Physics::Jstris)
-                            .iter()
-                            .filter_map(|q| {
-                                let mut shapes: Vec<Shape> = q.collect();
-                                if shapes.last().copied() == parse_shape(piece) {
-                                    shapes.pop();
-                                    Some(shapes.into_iter().collect::<Queue>())
-                                } else {
-                                    None
-                                }
-                            })
-                            .flat_map(|q| q.unhold())
-                            .map(|q| q.to_string())
-                            .collect::<Vec<_>>()
-                    })
-                    .collect();
-                if i == 0 {
-                    res = cover;
-                } else {
-                    res.retain(|q| cover.contains(q));
-                    if res.is_empty() {
-                        return vec![];
-                    }
-                }
-            }
-            res.iter().cloned().collect::<Vec<String>>()
-        }
-        let pieces = "TIJLOSZ";
+    /// The fewest setups that together can be built from every build queue. With `by_saves`,
+    /// only the one combination keeping the most queues.
+    #[pyo3(signature = (build_queue, solve_queue, saves="", by_saves=false, skip_4p=false, hold=None, bag_used="", progress=None, *, max_left=1))]
+    #[allow(clippy::too_many_arguments)]
+    fn find_build_minimals(
+        &mut self,
+        py: Python,
+        build_queue: &str,
+        solve_queue: &str,
+        saves: &str,
+        by_saves: bool,
+        skip_4p: bool,
+        hold: Option<char>,
+        bag_used: &str,
+        progress: Option<Py<PyAny>>,
+        max_left: usize,
+    ) -> PyResult<BuildMinimalsResult> {
+        self.qbf.build_mode = build_mode(skip_4p, max_left);
+        let start = queue_start(hold, bag_used);
+        let saves = save_policy(saves)?;
+        let minimals = run_task(py, &self.qbf, progress, |qbf| {
+            qbf.build_minimals(build_queue, &start, solve_queue, &saves, by_saves)
+        })?;
 
-        let perms: Vec<_> = pieces.chars().permutations(3).collect();
+        let setups = minimals
+            .setups
+            .into_iter()
+            .map(|(setup, score)| {
+                let (board, held, unplaced) = setup_tuple(&setup);
+                (board, held, unplaced, score.0)
+            })
+            .collect();
+        Ok((setups, minimals.sets, minimals.uncovered))
+    }
 
-        let res: DashMap<String, Vec<String>> = DashMap::new();
+    /// How often building the best setup for each build queue gets the PC, as
+    /// `(probability, probability per save tier, losing (build queue, solve queue) pairs)`. With
+    /// a `randomizer`, `solve_queue` is the solve pieces already seen and it deals the rest.
+    #[pyo3(signature = (build_queue, solve_queue, saves="", randomizer=None, skip_4p=false, hold=None, bag_used="", progress=None, *, max_left=1))]
+    #[allow(clippy::too_many_arguments)]
+    fn route_probability(
+        &mut self,
+        py: Python,
+        build_queue: &str,
+        solve_queue: &str,
+        saves: &str,
+        randomizer: Option<&str>,
+        skip_4p: bool,
+        hold: Option<char>,
+        bag_used: &str,
+        progress: Option<Py<PyAny>>,
+        max_left: usize,
+    ) -> PyResult<RouteProbability> {
+        self.qbf.build_mode = build_mode(skip_4p, max_left);
+        let start = queue_start(hold, bag_used);
+        let saves = save_policy(saves)?;
+        let solves = solve_source(solve_queue, self::randomizer(randomizer)?);
+        let route = run_task(py, &self.qbf, progress, |qbf| {
+            qbf.route(
+                build_queue,
+                &start,
+                solves,
+                &saves,
+                &RoutePlan::BestAvailable,
+            )
+        })?;
+        Ok((
+            route.probability(),
+            route.tier_probabilities(),
+            route.losing,
+        ))
+    }
 
-        py.detach(|| {
-            perms.into_par_iter().for_each(|p3| {
-                let p3_str: String = p3.iter().collect();
-                let q = format!("{}{}", fifth, p3_str).chars().join(",");
+    /// How often the best build minimals and their first minimal sets get the PC over `runs`
+    /// random sequences drawn from `seed`. With a `randomizer`, `solve_queue` is the solve
+    /// pieces already seen and it deals the rest.
+    #[pyo3(signature = (build_queue, solve_queue, saves="", runs=10000, seed=0, randomizer=None, skip_4p=false, hold=None, bag_used="", progress=None, *, max_left=1))]
+    #[allow(clippy::too_many_arguments)]
+    fn simulate(
+        &mut self,
+        py: Python,
+        build_queue: &str,
+        solve_queue: &str,
+        saves: &str,
+        runs: usize,
+        seed: u64,
+        randomizer: Option<&str>,
+        skip_4p: bool,
+        hold: Option<char>,
+        bag_used: &str,
+        progress: Option<Py<PyAny>>,
+        max_left: usize,
+    ) -> PyResult<SimulationResult> {
+        self.qbf.build_mode = build_mode(skip_4p, max_left);
+        let start = queue_start(hold, bag_used);
+        let saves = save_policy(saves)?;
+        let randomizer = self::randomizer(randomizer)?;
+        let sim = run_task(py, &self.qbf, progress, |qbf| {
+            let pattern = match randomizer {
+                Some(r) => qbf
+                    .solve_distribution(r, build_queue, &start, solve_queue)
+                    .pattern(),
+                None => solve_queue.to_owned(),
+            };
+            let plan = qbf.best_sim_plan(build_queue, &start, &pattern, &saves);
+            let solves = solve_source(solve_queue, randomizer);
+            qbf.simulate(build_queue, &start, solves, &saves, &plan, runs, seed)
+        })?;
+        let failures = sim
+            .failures()
+            .into_iter()
+            .map(|(cause, count)| (cause.name().to_owned(), count))
+            .collect();
+        Ok((
+            sim.probability(),
+            sim.interval(),
+            sim.tier_rates(),
+            failures,
+        ))
+    }
 
-                for save in fifth.chars().chain(p3.iter().copied()).unique() {
-                    let setups =
-                        self.qbf
-                            .compute(&q, &BrokenBoard::from_garbage(0), parse_shape(save));
-                    let remaining: String = pieces.chars().filter(|c| !p3.contains(c)).collect();
-                    let qqq = format!("{},{}", save, remaining);
+    /// The solve queues `randomizer` can deal after the first queue of `build_queue`, each
+    /// starting with `seen`, as `(queue, chance)`. Joining the queues with commas between pieces
+    /// and newlines between queues gives a solve pattern for the other calls.
+    #[pyo3(signature = (randomizer, build_queue, seen="", hold=None, bag_used=""))]
+    fn solve_distribution(
+        &self,
+        randomizer: &str,
+        build_queue: &str,
+        seen: &str,
+        hold: Option<char>,
+        bag_used: &str,
+    ) -> PyResult<Vec<(String, f64)>> {
+        let Some(randomizer) = self::randomizer(Some(randomizer))? else {
+            return Ok(vec![]);
+        };
+        let start = queue_start(hold, bag_used);
+        Ok(self
+            .qbf
+            .solve_distribution(randomizer, build_queue, &start, seen)
+            .queues)
+    }
 
-                    for setup in setups {
-                        let queues = bestsaves_queues(&setup, &qqq, &self.qbf);
-                        if queues.is_empty() {
-                            continue;
+    /// Like `find_qb`, but returns a `FindStream` of setups as they are found.
+    #[pyo3(signature = (build_queue, solve_queue, saves="", skip_4p=false, hold=None, bag_used="", *, max_left=1))]
+    #[allow(clippy::too_many_arguments)]
+    fn find_qb_stream(
+        &mut self,
+        build_queue: &str,
+        solve_queue: &str,
+        saves: &str,
+        skip_4p: bool,
+        hold: Option<char>,
+        bag_used: &str,
+        max_left: usize,
+    ) -> PyResult<FindStream> {
+        let saves = save_policy(saves)?;
+        self.qbf.build_mode = build_mode(skip_4p, max_left);
+        let mut qbf = self.qbf.clone();
+        qbf.task = Task::new();
+        let task = qbf.task.clone();
+        let (tx, rx) = mpsc::channel();
+        let start = queue_start(hold, bag_used);
+        let (build_queue, solve_queue) = (build_queue.to_owned(), solve_queue.to_owned());
+        thread::spawn(move || {
+            qbf.find_with(
+                &build_queue,
+                &start,
+                None,
+                &solve_queue,
+                &saves,
+                1,
+                |event| {
+                    let event = match event {
+                        FindEvent::Setup { setup, saves } => {
+                            let (board, held, unplaced) = setup_tuple(setup);
+                            (Some(board), held, unplaced, saves.0.clone())
+                        }
+                        FindEvent::Superseded { saves } => {
+                            (None, None, String::new(), saves.0.clone())
                         }
+                    };
+                    let _ = tx.send(event);
+                },
+            );
+        });
+
+        Ok(FindStream {
+            events: Mutex::new(rx),
+            task,
+        })
+    }
+
+    #[pyo3(signature = (fifth, saves="T"))]
+    fn bestsaves(
+        &mut self,
+        py: Python,
+        fifth: &str,
+        saves: &str,
+    ) -> PyResult<HashMap<String, Vec<String>>> {
+        let saves = save_policy(saves)?;
+        let res = py.detach(|| self.qbf.bestsaves(fifth, &saves));
 
+        Ok(res
+            .into_iter()
+            .map(|(queue, setups)| {
+                let boards = setups
+                    .iter()
+                    .map(|setup| {
                         let mut board_str = String::with_capacity(40);
-                        solver::print(&setup, &mut board_str);
+                        solver::print(setup, &mut board_str);
+                        board_str
+                    })
+                    .collect();
+                (queue, boards)
+            })
+            .collect())
+    }
 
-                        for queue in queues {
-                            if !queue.starts_with(save) {
-                                continue;
-                            }
+    /// Ranks placements of the current or hold piece as
+    /// `(board, shape, uses_hold, save_rates, pc_rate)`, best first.
+    #[pyo3(signature = (field="", hold=None, previews="", bag_used="", saves="T"))]
+    fn advise(
+        &self,
+        py: Python,
+        field: &str,
+        hold: Option<char>,
+        previews: &str,
+        bag_used: &str,
+        saves: &str,
+    ) -> PyResult<AdviceList> {
+        let field = solver::parse_garbage(field).ok_or_else(|| {
+            PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                "field must be up to 4 rows of 10 cells",
+            )
+        })?;
+        let state = GameState {
+            field,
+            hold: hold.and_then(parse_shape),
+            previews: previews.chars().filter_map(parse_shape).collect(),
+            bag_used: bag_used.chars().filter_map(parse_shape).collect(),
+        };
+        let saves = save_policy(saves)?;
+        let advice = py.detach(|| self.qbf.advise(&state, &saves));
 
-                            let fullq = format!("{}{}", p3_str, queue.replacen(save, "", 1));
+        Ok(advice
+            .into_iter()
+            .map(|option| {
+                let mut board_str = String::with_capacity(40);
+                solver::print(&option.board, &mut board_str);
+                let save_rates = option
+                    .save_rates
+                    .iter()
+                    .map(|(tier, r)| (tier.to_string(), *r))
+                    .collect();
+                (
+                    board_str,
+                    option.shape.name().to_owned(),
+                    option.uses_hold,
+                    save_rates,
+                    option.pc_rate,
+                )
+            })
+            .collect())
+    }
 
-                            res.entry(fullq).or_default().push(board_str.clone());
-                        }
-                    }
-                }
-            });
-        });
-        let res: HashMap<String, Vec<String>> = res.into_iter().collect();
+    /// Every piece kept and the first save tier met on each queue of `pattern`. With a
+    /// `randomizer`, each queue weighs as much as the chance of it dealing the queue after
+    /// `bag_used`.
+    #[pyo3(signature = (pattern, saves="T", garb=None, randomizer=None, bag_used="", progress=None))]
+    #[allow(clippy::too_many_arguments)]
+    fn saves_stats(
+        &self,
+        py: Python,
+        pattern: &str,
+        saves: &str,
+        garb: Option<u64>,
+        randomizer: Option<&str>,
+        bag_used: &str,
+        progress: Option<Py<PyAny>>,
+    ) -> PyResult<PySaveStats> {
+        let saves = save_policy(saves)?;
+        let randomizer = self::randomizer(randomizer)?;
+        let mut stats = run_task(py, &self.qbf, progress, |qbf| {
+            qbf.saves_stats(
+                &BrokenBoard::from_garbage(garb.unwrap_or(0)),
+                pattern,
+                &saves,
+            )
+        })?
+        .ok_or_else(|| {
+            PyErr::new::<pyo3::exceptions::PyValueError, _>("pattern must only have pieces")
+        })?;
+        if let Some(randomizer) = randomizer {
+            let len = stats.queues.first().map_or(0, |q| q.queue.len());
+            let dealt = queue_start(None, bag_used).bag_used;
+            stats.weigh(&randomizer.distribution(&dealt, len));
+        }
 
-        Ok(res)
+        Ok(PySaveStats {
+            probabilities: stats.probabilities(),
+            tiers: stats
+                .tiers
+                .iter()
+                .zip(stats.counts())
+                .zip(stats.percentages())
+                .map(|((tier, count), percent)| (tier.to_string(), count, percent))
+                .collect(),
+            queues: stats
+                .queues
+                .into_iter()
+                .map(|q| {
+                    let keepable = q.keepable.iter().map(|s| s.name()).collect();
+                    (q.queue, keepable, q.tier)
+                })
+                .collect(),
+        })
     }
 
-    #[pyo3(signature = (pattern, saves="", garb=None))]
+    #[pyo3(signature = (pattern, saves="", garb=None, progress=None))]
     fn all_minimals(
         &self,
+        py: Python,
         pattern: &str,
         saves: &str,
         garb: Option<u64>,
+        progress: Option<Py<PyAny>>,
     ) -> PyResult<PySetupMinimals> {
         let mut res_sets = vec![];
         let mut res_equiv = vec![];
         let solve_queues: FxHashSet<String> = expand_pattern(pattern).into_iter().collect();
+        let saves = save_policy(saves)?;
 
-        let (solves, covers, equiv) = self.qbf.all_min_sets(
-            &BrokenBoard::from_garbage(garb.unwrap_or(0)),
-            pattern,
-            &solve_queues,
-            saves,
-        );
+        let (solves, covers, equiv) = run_task(py, &self.qbf, progress, |qbf| {
+            qbf.all_min_sets(
+                &BrokenBoard::from_garbage(garb.unwrap_or(0)),
+                pattern,
+                &solve_queues,
+                &saves,
+            )
+        })?;
 
         let mut common: FxHashSet<usize> = covers[0].iter().cloned().collect();
 
@@ -266,5 +868,7 @@ impl QBSolver {
 #[pymodule]
 fn py_qbf(m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_class::<QBSolver>()?;
+    m.add_class::<FindStream>()?;
+    m.add_class::<PySaveStats>()?;
     Ok(())
 }
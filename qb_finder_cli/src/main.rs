@@ -1,44 +1,462 @@
+mod render;
+mod tui;
+
 use std::{
     fs::File,
     io::{self, Cursor, Read, Write},
+    process,
     time::Instant,
 };
 
 use itertools::Itertools;
-use qb_finder_core::{QBFinder, expand_pattern, solver};
+use qb_finder_core::{
+    QBFinder, Ranking,
+    advisor::{Advice, GameState},
+    build::{BuildMode, Setup},
+    expand_pattern,
+    pareto::SetupScore,
+    parse_shape,
+    pc::pc_bag_offset,
+    queue::QueueStart,
+    randomizer::{QueueDistribution, Randomizer, SolveSource},
+    route::RoutePlan,
+    saves::{SavePolicy, SaveScore},
+    solver,
+};
 use rustc_hash::FxHashSet;
-use srs_4l::{board_list, brokenboard::BrokenBoard, gameplay::Board};
-
-fn emoji_map(c: char) -> char {
-    match c {
-        'I' => '📘',
-        'J' => '🟦',
-        'L' => '🟧',
-        'O' => '🟨',
-        'S' => '🟩',
-        'T' => '🟪',
-        'Z' => '🟥',
-        'G' => '⬜',
-        '_' => '⬛',
-        _ => c,
+use srs_4l::{board_list, gameplay::Board};
+
+use crate::render::{Renderer, SetupEntry, TableFormat, terminal_width};
+
+pub struct Query {
+    pub build_queue: String,
+    pub start: QueueStart,
+    pub solve_queue: String,
+    pub saves: SavePolicy,
+    /// How the solve queues are dealt, which `solve_queue` then expands to. `None` when the
+    /// solve queue is a 7-bag pattern.
+    pub dealt: Option<DealtSolves>,
+}
+
+pub struct DealtSolves {
+    pub randomizer: Randomizer,
+    /// Solve pieces already in the previews.
+    pub seen: String,
+    pub dist: QueueDistribution,
+}
+
+impl Query {
+    pub fn solves(&self) -> SolveSource<'_> {
+        match &self.dealt {
+            Some(dealt) => SolveSource::Dealt {
+                randomizer: dealt.randomizer,
+                seen: &dealt.seen,
+            },
+            None => SolveSource::Pattern(&self.solve_queue),
+        }
+    }
+
+    /// Solve pattern and expanded solve queues for a setup, including the build pieces it leaves.
+    pub fn solve_pattern(&self, setup: &Setup) -> (String, FxHashSet<String>) {
+        let pattern = setup.solve_pattern(&self.solve_queue);
+        let solve_queues = expand_pattern(&pattern).into_iter().collect();
+        (pattern, solve_queues)
+    }
+
+    pub fn min_count(&self, qbf: &QBFinder, setup: &Setup) -> usize {
+        if setup.board.pieces.len() < 3 {
+            return 0;
+        }
+        let (pattern, universe) = self.solve_pattern(setup);
+        qbf.min_count(&setup.board, &pattern, &universe, &self.saves)
     }
 }
 
-fn print_board(board: &BrokenBoard) {
-    let mut str = String::with_capacity(40);
-    solver::print(board, &mut str);
-    str = str
-        .chars()
-        .map(emoji_map)
-        .collect::<Vec<char>>()
-        .chunks(10)
-        .map(|chunk| chunk.iter().collect::<String>())
-        .collect::<Vec<String>>()
-        .join("\n");
-    println!("{str}")
+/// What setups are listed by first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum SortKey {
+    /// Best save score, then lowest min count.
+    Saves,
+    /// Most build queues that can build the setup, then as `Saves`.
+    Cover,
+}
+
+fn prompt(label: &str) -> String {
+    print!("{label}: ");
+    let _ = io::stdout().flush();
+    let mut input = String::new();
+
+    io::stdin()
+        .read_line(&mut input)
+        .expect("Failed to read line");
+    input.trim().to_owned()
+}
+
+fn print_advice(renderer: Renderer, advice: &[Advice]) {
+    for (i, option) in advice.iter().enumerate() {
+        let shape = option.shape.name();
+        let from = if option.uses_hold { "hold" } else { "current" };
+        let rates = option
+            .save_rates
+            .iter()
+            .map(|(tier, r)| format!("{tier}: {:.2}%", r * 100.0))
+            .chain([format!("PC: {:.2}%", option.pc_rate * 100.0)])
+            .join("  ");
+        println!("#{} {shape} from {from}  {rates}", i + 1);
+        for line in renderer.board_lines(&option.board) {
+            println!("{line}");
+        }
+        println!();
+    }
+}
+
+/// Reads mid-game states and ranks the placements of the current or hold piece.
+fn advise(qbf: &QBFinder, renderer: Renderer, saves: &SavePolicy) {
+    loop {
+        println!("Field (rows top to bottom, _ for empty, blank line to finish):");
+        let mut field = String::new();
+        loop {
+            let row = prompt("Row");
+            if row.is_empty() {
+                break;
+            }
+            field.push_str(&row);
+        }
+        let Some(field) = solver::parse_garbage(&field) else {
+            eprintln!("Field must be up to 4 rows of 10 cells");
+            continue;
+        };
+
+        let previews = prompt("Current + Previews");
+        if previews.is_empty() {
+            break;
+        }
+        let hold = prompt("Hold");
+        let bag_used = prompt("Bag used");
+
+        let state = GameState {
+            field,
+            hold: hold.chars().find_map(parse_shape),
+            previews: previews.chars().filter_map(parse_shape).collect(),
+            bag_used: bag_used.chars().filter_map(parse_shape).collect(),
+        };
+
+        let start = Instant::now();
+        let advice = qbf.advise(&state, saves);
+        println!("Found {} options in {:?}", advice.len(), start.elapsed());
+        print_advice(renderer, &advice);
+    }
+}
+
+/// Losing sequences `--route` lists.
+const LOSING_SHOWN: usize = 20;
+
+/// Prints how often building the best setup for each build queue gets the PC.
+fn print_route(qbf: &QBFinder, query: &Query) {
+    let start = Instant::now();
+    let route = qbf.route(
+        &query.build_queue,
+        &query.start,
+        query.solves(),
+        &query.saves,
+        &RoutePlan::BestAvailable,
+    );
+    println!(
+        "PC in {}/{} sequences ({:.2}%) in {:?}",
+        route.wins(),
+        route.total,
+        route.probability() * 100.0,
+        start.elapsed()
+    );
+    for (tier, p) in query.saves.tiers.iter().zip(route.tier_probabilities()) {
+        println!("  {tier}: {:.2}%", p * 100.0);
+    }
+    let unbuildable = route.choices.iter().filter(|(_, s)| s.is_none()).count();
+    if unbuildable > 0 {
+        println!("{unbuildable} build queues can't build any setup");
+    }
+    for (build, solve) in route.losing.iter().take(LOSING_SHOWN) {
+        println!("  lose: {build} {solve}");
+    }
+    if route.losing.len() > LOSING_SHOWN {
+        println!("  and {} more", route.losing.len() - LOSING_SHOWN);
+    }
+}
+
+/// Prints how often the best build minimals and their first minimal sets get the PC over
+/// `runs` random sequences.
+fn print_simulation(qbf: &QBFinder, query: &Query, runs: usize, seed: u64) {
+    let start = Instant::now();
+    let plan = qbf.best_sim_plan(
+        &query.build_queue,
+        &query.start,
+        &query.solve_queue,
+        &query.saves,
+    );
+    let sim = qbf.simulate(
+        &query.build_queue,
+        &query.start,
+        query.solves(),
+        &query.saves,
+        &plan,
+        runs,
+        seed,
+    );
+    let (low, high) = sim.interval();
+    println!(
+        "PC in {}/{} runs ({:.2}%, 95% CI {:.2}-{:.2}%) with {} setups in {:?}",
+        sim.wins(),
+        sim.runs,
+        sim.probability() * 100.0,
+        low * 100.0,
+        high * 100.0,
+        plan.setups.len(),
+        start.elapsed()
+    );
+    for (tier, (rate, (low, high))) in query.saves.tiers.iter().zip(sim.tier_rates()) {
+        println!(
+            "  {tier}: {:.2}% ({:.2}-{:.2}%)",
+            rate * 100.0,
+            low * 100.0,
+            high * 100.0
+        );
+    }
+    for (cause, count) in sim.failures() {
+        if count > 0 {
+            println!("  {}: {count}", cause.name());
+        }
+    }
+    for (cause, build, solve) in &sim.examples {
+        println!("  {}: {build} {solve}", cause.name());
+    }
+}
+
+/// Prints the fewest setups that together can be built from every build queue.
+fn print_build_minimals(qbf: &QBFinder, renderer: Renderer, query: &Query, by_saves: bool) {
+    let start = Instant::now();
+    let minimals = qbf.build_minimals(
+        &query.build_queue,
+        &query.start,
+        &query.solve_queue,
+        &query.saves,
+        by_saves,
+    );
+    let size = minimals.sets.first().map_or(0, Vec::len);
+    println!(
+        "Found {} sets of {size} setups in {:?}",
+        minimals.sets.len(),
+        start.elapsed()
+    );
+
+    for (i, set) in minimals.sets.iter().enumerate() {
+        let setups: Vec<Setup> = set.iter().map(|&j| minimals.setups[j].0.clone()).collect();
+        let covers = qbf.build_covers(&setups, &query.build_queue, &query.start);
+        let entries: Vec<SetupEntry> = set
+            .iter()
+            .zip(covers)
+            .map(|(&j, build_cover)| {
+                let (setup, saves) = &minimals.setups[j];
+                SetupEntry {
+                    setup,
+                    saves: saves.clone(),
+                    queue_count: expand_pattern(&setup.solve_pattern(&query.solve_queue)).len(),
+                    min_count: 0,
+                    build_cover,
+                    probability: None,
+                }
+            })
+            .collect();
+        println!("Set {}:", i + 1);
+        println!("{}", renderer.render(&entries, terminal_width()));
+    }
+    if !minimals.uncovered.is_empty() {
+        println!("No setup builds from: {}", minimals.uncovered.join(" "));
+    }
 }
 
 fn main() {
+    let mut renderer = Renderer::Emoji;
+    let mut interactive = false;
+    let mut advising = false;
+    let mut start = QueueStart::default();
+    let mut cache_size = None;
+    let mut store = None;
+    let mut ranking = None;
+    let mut pareto = false;
+    let mut saves_table = None;
+    let mut bag_offset = None;
+    let mut build_mode = BuildMode::default();
+    let mut sort = SortKey::Saves;
+    let mut build_minimals = None;
+    let mut route = false;
+    let mut simulation = None;
+    let mut randomizer = None;
+    let mut saves = SavePolicy::parse("T").unwrap_or_default();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-r" | "--render" => {
+                renderer = match args.next().as_deref().and_then(Renderer::from_name) {
+                    Some(r) => r,
+                    None => {
+                        eprintln!("Expected one of ansi, ascii, emoji, fumen, json after {arg}");
+                        process::exit(1);
+                    }
+                }
+            }
+            "-i" | "--tui" => interactive = true,
+            "-a" | "--advise" => advising = true,
+            "--pareto" => pareto = true,
+            "--route" => route = true,
+            "--hold" => {
+                start.hold = match args
+                    .next()
+                    .and_then(|s| s.chars().next())
+                    .and_then(parse_shape)
+                {
+                    Some(shape) => Some(shape),
+                    None => {
+                        eprintln!("Expected a piece after {arg}");
+                        process::exit(1);
+                    }
+                }
+            }
+            "--saves" => {
+                saves = match args.next().as_deref().and_then(SavePolicy::parse) {
+                    Some(policy) => policy,
+                    None => {
+                        eprintln!("Expected a save policy such as T,IO,^O,- after {arg}");
+                        process::exit(1);
+                    }
+                }
+            }
+            "--saves-table" => {
+                saves_table = match args.next().as_deref().and_then(TableFormat::from_name) {
+                    Some(format) => Some(format),
+                    None => {
+                        eprintln!("Expected one of csv, json after {arg}");
+                        process::exit(1);
+                    }
+                }
+            }
+            "--cache" => {
+                cache_size = match args.next().and_then(|n| n.parse::<usize>().ok()) {
+                    Some(size) => Some(size),
+                    None => {
+                        eprintln!("Expected a number of entries after {arg}");
+                        process::exit(1);
+                    }
+                }
+            }
+            "--store" => {
+                store = match args.next() {
+                    Some(dir) => Some(dir),
+                    None => {
+                        eprintln!("Expected a directory after {arg}");
+                        process::exit(1);
+                    }
+                }
+            }
+            "--min-saves" | "--top" => {
+                let n = match args.next().and_then(|n| n.parse::<usize>().ok()) {
+                    Some(n) => n,
+                    None => {
+                        eprintln!("Expected a number after {arg}");
+                        process::exit(1);
+                    }
+                };
+                ranking = Some(match arg.as_str() {
+                    "--top" => Ranking::Top(n),
+                    _ => Ranking::AtLeast(n),
+                });
+            }
+            "--pc" | "--bag-offset" => {
+                let n = args.next().and_then(|n| n.parse::<usize>().ok());
+                bag_offset = match (arg.as_str(), n) {
+                    ("--pc", Some(pc)) => pc_bag_offset(pc),
+                    ("--bag-offset", Some(offset)) if offset < 7 => Some(offset),
+                    _ => None,
+                };
+                if bag_offset.is_none() {
+                    eprintln!(
+                        "Expected a PC number from 1 to 7 or a bag offset from 0 to 6 after {arg}"
+                    );
+                    process::exit(1);
+                }
+            }
+            "--sort" => {
+                sort = match args.next().as_deref() {
+                    Some("saves") => SortKey::Saves,
+                    Some("cover") => SortKey::Cover,
+                    _ => {
+                        eprintln!("Expected one of saves, cover after {arg}");
+                        process::exit(1);
+                    }
+                }
+            }
+            "--build-minimals" => {
+                build_minimals = match args.next().as_deref() {
+                    Some("all") => Some(false),
+                    Some("saves") => Some(true),
+                    _ => {
+                        eprintln!("Expected one of all, saves after {arg}");
+                        process::exit(1);
+                    }
+                }
+            }
+            "--simulate" => {
+                // runs, optionally followed by a seed such as 10000:42
+                simulation = args.next().and_then(|n| {
+                    let (runs, seed) = n.split_once(':').unwrap_or((&n, "0"));
+                    Some((runs.parse::<usize>().ok()?, seed.parse::<u64>().ok()?))
+                });
+                if simulation.is_none() {
+                    eprintln!("Expected a number of runs or runs:seed after {arg}");
+                    process::exit(1);
+                }
+            }
+            "--randomizer" => {
+                randomizer = match args.next().as_deref().and_then(Randomizer::parse) {
+                    Some(r) => Some(r),
+                    None => {
+                        let names = Randomizer::ALL.map(Randomizer::name).join(", ");
+                        eprintln!("Expected one of {names} after {arg}");
+                        process::exit(1);
+                    }
+                }
+            }
+            "--leave" => {
+                // either the most pieces to leave, or a range such as 1-2
+                let range = args.next().and_then(|n| {
+                    let (min, max) = n.split_once('-').unwrap_or(("0", &n));
+                    Some((min.parse::<usize>().ok()?, max.parse::<usize>().ok()?))
+                });
+                build_mode = match range {
+                    Some((min_left, max_left)) if min_left <= max_left => {
+                        BuildMode { min_left, max_left }
+                    }
+                    _ => {
+                        eprintln!("Expected a number of pieces or a range such as 1-2 after {arg}");
+                        process::exit(1);
+                    }
+                }
+            }
+            "--bag-used" => {
+                start.bag_used = match args.next() {
+                    Some(pieces) => pieces.chars().filter_map(parse_shape).collect(),
+                    None => {
+                        eprintln!("Expected pieces after {arg}");
+                        process::exit(1);
+                    }
+                }
+            }
+            _ => {
+                eprintln!("Unknown argument: {arg}");
+                process::exit(1);
+            }
+        }
+    }
+
     let mut file = File::open("./legal-boards.leb128").expect("Failed to open legal_boards");
     let mut buffer = Vec::new();
     file.read_to_end(&mut buffer)
@@ -49,7 +467,19 @@ fn main() {
         .into_iter()
         .collect();
 
-    let qbf = QBFinder::new(legal_boards);
+    let mut qbf = QBFinder::new(legal_boards);
+    qbf.build_mode = build_mode;
+    if let Some(size) = cache_size {
+        qbf.set_cache_size(size);
+    }
+    if let Some(dir) = store {
+        qbf.set_store(&dir).expect("Failed to open result store");
+    }
+    if advising {
+        advise(&qbf, renderer, &saves);
+        return;
+    }
+
     loop {
         print!("Build Queue: ");
         let _ = io::stdout().flush();
@@ -60,7 +490,7 @@ fn main() {
             .expect("Failed to read line");
 
         let buildq = input.trim();
-        if buildq.is_empty(){
+        if buildq.is_empty() {
             break;
         }
 
@@ -72,63 +502,165 @@ fn main() {
             .read_line(&mut input)
             .expect("Failed to read line");
         let mut solveq = input.trim().to_owned();
+        let seen = solveq.clone();
+
+        let mut query = match bag_offset {
+            // both queues are the pieces seen, and the bags after them are worked out
+            Some(offset) => {
+                let Some(seen) = format!("{buildq}{solveq}")
+                    .chars()
+                    .map(parse_shape)
+                    .collect::<Option<Vec<_>>>()
+                else {
+                    eprintln!("Expected only pieces when giving the PC number");
+                    continue;
+                };
+                let Some(pc) = qbf.pc_queues(offset, &start, &seen, buildq.len()) else {
+                    eprintln!("Queue can't come from a 7-bag at this PC, or give --bag-used");
+                    continue;
+                };
+                Query {
+                    build_queue: pc.build_queue,
+                    start: pc.start,
+                    solve_queue: pc.solve_queue,
+                    saves: saves.clone(),
+                    dealt: None,
+                }
+            }
+            None => {
+                let pieces = "TIOLJSZ";
+                let remaining = pieces
+                    .chars()
+                    .filter(|&c| !solveq.contains(c))
+                    .collect::<String>();
+                if !remaining.is_empty() {
+                    solveq = format!("{solveq},{remaining}");
+                }
+                Query {
+                    build_queue: buildq.to_owned(),
+                    start: start.clone(),
+                    solve_queue: solveq,
+                    saves: saves.clone(),
+                    dealt: None,
+                }
+            }
+        };
+        if let Some(randomizer) = randomizer {
+            // the solve queue seen so far is followed by whatever the randomizer deals
+            let dist = qbf.solve_distribution(randomizer, &query.build_queue, &query.start, &seen);
+            query.solve_queue = dist.pattern();
+            query.dealt = Some(DealtSolves {
+                randomizer,
+                seen,
+                dist,
+            });
+        }
 
-        let saves = "T";
-        let pieces = "TIOLJSZ";
-        let remaining = pieces
-            .chars()
-            .filter(|&c| !solveq.contains(c))
-            .collect::<String>();
-        if !remaining.is_empty() {
-            solveq = format!("{solveq},{remaining}");
+        if route {
+            print_route(&qbf, &query);
+            continue;
+        }
+        if let Some((runs, seed)) = simulation {
+            print_simulation(&qbf, &query, runs, seed);
+            continue;
+        }
+        if let Some(by_saves) = build_minimals {
+            print_build_minimals(&qbf, renderer, &query, by_saves);
+            continue;
         }
 
         let start = Instant::now();
-        let (setups, _) = qbf.find(buildq, None, &solveq, saves, 1);
+        let found: Vec<(Setup, SaveScore, Option<SetupScore>)> = if pareto {
+            qbf.find_pareto(&query.build_queue, &query.start, &query.solve_queue, &saves)
+                .into_iter()
+                .map(|(b, score)| {
+                    let saves = SaveScore(vec![score.primary_saves, score.secondary_saves]);
+                    (b, saves, Some(score))
+                })
+                .collect()
+        } else {
+            let setups = match ranking {
+                Some(ranking) => qbf.find_ranked(
+                    &query.build_queue,
+                    &query.start,
+                    None,
+                    &query.solve_queue,
+                    &saves,
+                    ranking,
+                ),
+                None => {
+                    let (setups, score) = qbf.find(
+                        &query.build_queue,
+                        &query.start,
+                        None,
+                        &query.solve_queue,
+                        &saves,
+                        1,
+                    );
+                    setups.into_iter().map(|b| (b, score.clone())).collect()
+                }
+            };
+            setups.into_iter().map(|(b, n)| (b, n, None)).collect()
+        };
 
-        println!("Found {:?} setups in {:?}", setups.len(), start.elapsed());
-        // for board in &setups {
-        //     print_board(&board);
-        // }
+        println!("Found {:?} setups in {:?}", found.len(), start.elapsed());
 
-        let solve_queues: FxHashSet<String> = expand_pattern(&solveq).into_iter().collect();
-        for (board, count) in setups
+        let setups: Vec<Setup> = found.iter().map(|(setup, _, _)| setup.clone()).collect();
+        let covers = qbf.build_covers(&setups, &query.build_queue, &query.start);
+        let entries: Vec<SetupEntry> = found
             .iter()
-            .map(|b| {
-                (b, {
-                    if b.pieces.len() < 3 {
-                        0
-                    } else if b.pieces.len() == buildq.replace(",", "").len() - 1 {
-                        let xor = buildq
-                            .replace(",", "")
-                            .chars()
-                            .fold(0, |a, c| a ^ (c as u8));
-                        let r: String = ((xor
-                            ^ b.pieces
-                                .iter()
-                                .map(|p| p.shape.name().chars().next().unwrap_or_default())
-                                .fold(0, |a, c| a ^ (c as u8)))
-                            as char)
-                            .into();
-                        qbf.min_count(
-                            b,
-                            &(r.clone() + &solveq),
-                            &solve_queues.clone().iter().map(|q| r.clone() + q).collect(),
-                            saves,
-                        )
-                    } else {
-                        qbf.min_count(b, &solveq, &solve_queues, saves)
-                    }
-                })
+            .zip(covers)
+            .map(|((setup, saves, score), build_cover)| SetupEntry {
+                setup,
+                saves: saves.clone(),
+                queue_count: expand_pattern(&setup.solve_pattern(&query.solve_queue)).len(),
+                min_count: match score {
+                    Some(score) => score.min_count.unwrap_or(0),
+                    None => query.min_count(&qbf, setup),
+                },
+                build_cover,
+                probability: query.dealt.as_ref().map(|dealt| {
+                    qbf.setup_probabilities(setup, &dealt.dist, &query.saves)
+                        .iter()
+                        .sum()
+                }),
+            })
+            .sorted_by(|a, b| {
+                let by_saves = b.saves.cmp(&a.saves).then(a.min_count.cmp(&b.min_count));
+                match sort {
+                    SortKey::Saves => by_saves,
+                    SortKey::Cover => b.build_cover.cmp_cover(&a.build_cover).then(by_saves),
+                }
             })
-            .sorted_by_key(|(_, count)| *count)
-        {
-            print_board(board);
-            if count > 0 {
-                println!("Min count: {}\n", count);
-            } else {
-                println!();
+            .collect();
+
+        if interactive {
+            if let Err(e) = tui::run(&qbf, &query, &entries) {
+                eprintln!("Terminal error: {e}");
             }
+        } else {
+            println!("{}", renderer.render(&entries, terminal_width()));
+        }
+
+        if let Some(format) = saves_table {
+            let tables: Vec<_> = entries
+                .iter()
+                .filter_map(|e| {
+                    let (pattern, _) = query.solve_pattern(e.setup);
+                    let mut stats = qbf.saves_stats(&e.setup.board, &pattern, &query.saves)?;
+                    if let Some(dealt) = &query.dealt {
+                        stats.weigh(&dealt.dist);
+                    }
+                    Some((&e.setup.board, stats))
+                })
+                .collect();
+            println!("{}", format.render(&tables));
         }
+
+        let stats = qbf.cache_stats();
+        println!(
+            "Cache: {} hits, {} misses, {}/{} entries",
+            stats.hits, stats.misses, stats.entries, stats.capacity
+        );
     }
 }
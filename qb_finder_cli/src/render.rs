@@ -0,0 +1,302 @@
+use qb_finder_core::{
+    build::{BuildCover, Setup},
+    fumen,
+    saves::{SaveScore, SaveStats},
+    solver,
+};
+use serde_json::json;
+use srs_4l::brokenboard::BrokenBoard;
+
+const GAP: usize = 2;
+
+pub struct SetupEntry<'a> {
+    pub setup: &'a Setup,
+    /// Queues meeting each save tier.
+    pub saves: SaveScore,
+    pub queue_count: usize,
+    pub min_count: usize,
+    /// Build queues that can build the setup.
+    pub build_cover: BuildCover,
+    /// Chance of meeting any save tier when a randomizer deals the solve queues.
+    pub probability: Option<f64>,
+}
+
+impl SetupEntry<'_> {
+    fn labels(&self) -> [String; 2] {
+        let mut saves = match self.probability {
+            Some(p) => format!("Saves: {:.2}%", p * 100.0),
+            None => format!("Saves: {}/{}", self.saves, self.queue_count),
+        };
+        if let Some(held) = self.setup.held {
+            saves = format!("{saves}  Hold: {}", held.name());
+        }
+        let mut min = if self.min_count > 0 {
+            format!("Min count: {}", self.min_count)
+        } else {
+            String::new()
+        };
+        if self.build_cover.total > 0 {
+            min = format!("{min}  Builds: {:.1}%", self.build_cover.fraction() * 100.0)
+                .trim()
+                .to_owned();
+        }
+        [saves, min]
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Renderer {
+    Ansi,
+    Ascii,
+    Emoji,
+    Fumen,
+    Json,
+}
+
+impl Renderer {
+    pub fn from_name(name: &str) -> Option<Renderer> {
+        match name.to_ascii_lowercase().as_str() {
+            "ansi" => Some(Renderer::Ansi),
+            "ascii" => Some(Renderer::Ascii),
+            "emoji" => Some(Renderer::Emoji),
+            "fumen" => Some(Renderer::Fumen),
+            "json" => Some(Renderer::Json),
+            _ => None,
+        }
+    }
+
+    /// Terminal columns taken by one row of a board.
+    fn board_width(self) -> usize {
+        match self {
+            Renderer::Ascii => 10,
+            _ => 20,
+        }
+    }
+
+    fn cell(self, c: char, to: &mut String) {
+        match self {
+            Renderer::Ansi => to.push_str(&format!("\x1b[48;5;{}m  \x1b[0m", ansi_colour(c))),
+            Renderer::Ascii => to.push(if c == '_' { '.' } else { c }),
+            _ => to.push(emoji_map(c)),
+        }
+    }
+
+    pub fn board_lines(self, board: &BrokenBoard) -> Vec<String> {
+        let mut cells = String::with_capacity(40);
+        solver::print(board, &mut cells);
+        cells
+            .chars()
+            .collect::<Vec<char>>()
+            .chunks(10)
+            .map(|row| {
+                let mut line = String::new();
+                for &c in row {
+                    self.cell(c, &mut line);
+                }
+                line
+            })
+            .collect()
+    }
+
+    pub fn render(self, setups: &[SetupEntry], width: usize) -> String {
+        match self {
+            Renderer::Fumen => setups
+                .iter()
+                .map(|s| {
+                    let [saves, min] = s.labels();
+                    format!(
+                        "https://fumen.zui.jp/?{}  {saves}  {min}",
+                        fumen::encode(&s.setup.board)
+                    )
+                    .trim_end()
+                    .to_owned()
+                })
+                .collect::<Vec<_>>()
+                .join("\n"),
+            Renderer::Json => {
+                let setups: Vec<_> = setups
+                    .iter()
+                    .map(|s| {
+                        let mut board = String::with_capacity(40);
+                        solver::print(&s.setup.board, &mut board);
+                        json!({
+                            "board": board,
+                            "fumen": fumen::encode(&s.setup.board),
+                            "save_count": s.saves.primary(),
+                            "save_score": s.saves.0,
+                            "queue_count": s.queue_count,
+                            "min_count": s.min_count,
+                            "probability": s.probability,
+                            "build_cover": {
+                                "total": s.build_cover.total,
+                                "with_hold": s.build_cover.with_hold,
+                                "without_hold": s.build_cover.without_hold,
+                                "missing": s.build_cover.missing,
+                            },
+                            "held": s.setup.held.map(|h| h.name()),
+                            "unplaced": s.setup.unplaced,
+                        })
+                    })
+                    .collect();
+                serde_json::Value::from(setups).to_string()
+            }
+            _ => self.render_grid(setups, width),
+        }
+    }
+
+    fn render_grid(self, setups: &[SetupEntry], width: usize) -> String {
+        let col_width = setups
+            .iter()
+            .flat_map(|s| s.labels())
+            .map(|l| l.len())
+            .fold(self.board_width(), usize::max);
+        let per_row = ((width + GAP) / (col_width + GAP)).max(1);
+
+        let mut res = String::new();
+        for row in setups.chunks(per_row) {
+            let boards: Vec<Vec<String>> = row
+                .iter()
+                .map(|s| self.board_lines(&s.setup.board))
+                .collect();
+            let labels: Vec<[String; 2]> = row.iter().map(|s| s.labels()).collect();
+
+            for line in 0..4 {
+                let cols: Vec<String> = boards
+                    .iter()
+                    .map(|b| b[line].clone() + &" ".repeat(col_width - self.board_width()))
+                    .collect();
+                res += cols.join(&" ".repeat(GAP)).trim_end();
+                res.push('\n');
+            }
+            for line in 0..2 {
+                let cols: Vec<String> = labels
+                    .iter()
+                    .map(|l| format!("{:col_width$}", l[line]))
+                    .collect();
+                let cols = cols.join(&" ".repeat(GAP));
+                if !cols.trim().is_empty() {
+                    res += cols.trim_end();
+                    res.push('\n');
+                }
+            }
+            res.push('\n');
+        }
+        res
+    }
+}
+
+/// Formats for the per-queue save tables of `--saves-table`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TableFormat {
+    Csv,
+    Json,
+}
+
+impl TableFormat {
+    pub fn from_name(name: &str) -> Option<TableFormat> {
+        match name.to_ascii_lowercase().as_str() {
+            "csv" => Some(TableFormat::Csv),
+            "json" => Some(TableFormat::Json),
+            _ => None,
+        }
+    }
+
+    /// One row per setup and solve queue, with the pieces kept and the first tier met.
+    pub fn render(self, tables: &[(&BrokenBoard, SaveStats)]) -> String {
+        match self {
+            TableFormat::Csv => {
+                let mut out = String::from("board,queue,keepable,tier");
+                for (board, stats) in tables {
+                    let mut cells = String::with_capacity(40);
+                    solver::print(board, &mut cells);
+                    for q in &stats.queues {
+                        let keepable: String = q.keepable.iter().map(|s| s.name()).collect();
+                        let tier = q.tier.map(|t| stats.tiers[t].to_string());
+                        out.push_str(&format!(
+                            "\n{cells},{},{keepable},{}",
+                            q.queue,
+                            tier.unwrap_or_default()
+                        ));
+                    }
+                }
+                out
+            }
+            TableFormat::Json => {
+                let tables: Vec<_> = tables
+                    .iter()
+                    .map(|(board, stats)| {
+                        let mut cells = String::with_capacity(40);
+                        solver::print(board, &mut cells);
+                        let tiers: Vec<_> = stats
+                            .tiers
+                            .iter()
+                            .zip(stats.counts())
+                            .zip(stats.percentages())
+                            .zip(stats.probabilities())
+                            .map(|(((tier, count), percent), probability)| {
+                                json!({
+                                    "tier": tier.to_string(),
+                                    "count": count,
+                                    "percent": percent,
+                                    "probability": probability,
+                                })
+                            })
+                            .collect();
+                        let queues: Vec<_> = stats
+                            .queues
+                            .iter()
+                            .map(|q| {
+                                let keepable: String =
+                                    q.keepable.iter().map(|s| s.name()).collect();
+                                json!({ "queue": q.queue, "keepable": keepable, "tier": q.tier })
+                            })
+                            .collect();
+                        json!({
+                            "board": cells,
+                            "fumen": fumen::encode(board),
+                            "tiers": tiers,
+                            "queues": queues,
+                        })
+                    })
+                    .collect();
+                serde_json::Value::from(tables).to_string()
+            }
+        }
+    }
+}
+
+/// 256-colour palette index used for a board cell.
+pub fn ansi_colour(c: char) -> u8 {
+    match c {
+        'I' => 39,
+        'J' => 25,
+        'L' => 208,
+        'O' => 220,
+        'S' => 77,
+        'T' => 127,
+        'Z' => 196,
+        'G' => 244,
+        _ => 236,
+    }
+}
+
+fn emoji_map(c: char) -> char {
+    match c {
+        'I' => '📘',
+        'J' => '🟦',
+        'L' => '🟧',
+        'O' => '🟨',
+        'S' => '🟩',
+        'T' => '🟪',
+        'Z' => '🟥',
+        'G' => '⬜',
+        '_' => '⬛',
+        _ => c,
+    }
+}
+
+pub fn terminal_width() -> usize {
+    terminal_size::terminal_size()
+        .map(|(terminal_size::Width(w), _)| w as usize)
+        .unwrap_or(80)
+}
@@ -0,0 +1,423 @@
+use std::io;
+
+use itertools::Itertools;
+use qb_finder_core::{QBFinder, parse_shape, queue::QueueStart, solver};
+use ratatui::{
+    DefaultTerminal, Frame,
+    crossterm::event::{self, Event, KeyCode, KeyEventKind},
+    layout::{Constraint, Layout, Rect},
+    style::{Color, Modifier, Style},
+    text::{Line, Span},
+    widgets::{Block, List, ListItem, ListState, Paragraph},
+};
+use rustc_hash::{FxHashMap, FxHashSet};
+use srs_4l::{brokenboard::BrokenBoard, gameplay::Shape};
+
+use crate::{Query, render::SetupEntry, render::ansi_colour};
+
+const BOARD_WIDTH: u16 = 20;
+const GAP: u16 = 2;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum View {
+    Setups,
+    Minimals,
+    Lookup,
+}
+
+struct Minimals {
+    solves: Vec<BrokenBoard>,
+    sets: Vec<Vec<usize>>,
+    common: Vec<usize>,
+    equiv: Vec<Vec<usize>>,
+}
+
+impl Minimals {
+    fn unique(&self, set: usize) -> Vec<usize> {
+        self.sets
+            .get(set)
+            .into_iter()
+            .flatten()
+            .filter(|i| !self.common.contains(i))
+            .copied()
+            .collect()
+    }
+}
+
+enum Lookup {
+    Invalid,
+    Found(Vec<(usize, Option<Shape>)>),
+    Outside(Vec<(BrokenBoard, Option<Shape>)>),
+}
+
+struct App<'a> {
+    qbf: &'a QBFinder,
+    query: &'a Query,
+    setups: &'a [SetupEntry<'a>],
+    view: View,
+    list: ListState,
+    minimals: FxHashMap<usize, Minimals>,
+    set: usize,
+    scroll: u16,
+    input: String,
+    lookup: Option<Lookup>,
+}
+
+pub fn run(qbf: &QBFinder, query: &Query, setups: &[SetupEntry]) -> io::Result<()> {
+    let mut terminal = ratatui::init();
+    let mut app = App {
+        qbf,
+        query,
+        setups,
+        view: View::Setups,
+        list: ListState::default().with_selected((!setups.is_empty()).then_some(0)),
+        minimals: FxHashMap::default(),
+        set: 0,
+        scroll: 0,
+        input: String::new(),
+        lookup: None,
+    };
+    let res = app.run(&mut terminal);
+    ratatui::restore();
+    res
+}
+
+fn board_lines(board: &BrokenBoard) -> Vec<Line<'static>> {
+    let mut cells = String::with_capacity(40);
+    solver::print(board, &mut cells);
+    cells
+        .chars()
+        .collect::<Vec<char>>()
+        .chunks(10)
+        .map(|row| {
+            Line::from(
+                row.iter()
+                    .map(|&c| Span::styled("  ", Style::new().bg(Color::Indexed(ansi_colour(c)))))
+                    .collect::<Vec<_>>(),
+            )
+        })
+        .collect()
+}
+
+/// Lays boards out side by side to fit `width`, with a label under each.
+fn board_grid(boards: &[(&BrokenBoard, String)], width: u16) -> Vec<Line<'static>> {
+    let per_row = ((width + GAP) / (BOARD_WIDTH + GAP)).max(1) as usize;
+    let mut lines = Vec::new();
+
+    for row in boards.chunks(per_row) {
+        let drawn: Vec<Vec<Line>> = row.iter().map(|(b, _)| board_lines(b)).collect();
+        for i in 0..4 {
+            let mut spans = Vec::new();
+            for board in &drawn {
+                spans.extend(board[i].spans.iter().cloned());
+                spans.push(Span::raw(" ".repeat(GAP as usize)));
+            }
+            lines.push(Line::from(spans));
+        }
+        lines.push(Line::from(
+            row.iter()
+                .map(|(_, label)| format!("{:width$}", label, width = (BOARD_WIDTH + GAP) as usize))
+                .collect::<String>(),
+        ));
+    }
+    lines
+}
+
+fn heading(text: String) -> Line<'static> {
+    Line::styled(text, Style::new().add_modifier(Modifier::BOLD))
+}
+
+impl App<'_> {
+    fn run(&mut self, terminal: &mut DefaultTerminal) -> io::Result<()> {
+        loop {
+            terminal.draw(|frame| self.draw(frame))?;
+
+            let Event::Key(key) = event::read()? else {
+                continue;
+            };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+
+            match (self.view, key.code) {
+                (View::Lookup, KeyCode::Esc) => self.view = View::Minimals,
+                (View::Lookup, KeyCode::Backspace) => {
+                    self.input.pop();
+                }
+                (View::Lookup, KeyCode::Enter) => self.lookup = Some(self.lookup()),
+                (View::Lookup, KeyCode::Char(c)) => {
+                    let c = c.to_ascii_uppercase();
+                    if parse_shape(c).is_some() {
+                        self.input.push(c);
+                    }
+                }
+                (_, KeyCode::Char('q')) => return Ok(()),
+                (View::Setups, KeyCode::Esc) => return Ok(()),
+                (View::Setups, KeyCode::Up | KeyCode::Char('k')) => self.list.select_previous(),
+                (View::Setups, KeyCode::Down | KeyCode::Char('j')) => self.list.select_next(),
+                (View::Setups, KeyCode::Enter) if self.list.selected().is_some() => {
+                    terminal.draw(|frame| {
+                        frame.render_widget(
+                            Paragraph::new("Computing minimal sets...").block(Block::bordered()),
+                            frame.area(),
+                        )
+                    })?;
+                    self.load_minimals();
+                    self.view = View::Minimals;
+                }
+                (View::Minimals, KeyCode::Esc | KeyCode::Backspace) => self.view = View::Setups,
+                (View::Minimals, KeyCode::Left | KeyCode::Char('h')) => {
+                    self.set = self.set.saturating_sub(1)
+                }
+                (View::Minimals, KeyCode::Right | KeyCode::Char('l')) => {
+                    let count = self.current_minimals().map_or(0, |m| m.sets.len());
+                    self.set = (self.set + 1).min(count.saturating_sub(1));
+                }
+                (View::Minimals, KeyCode::Up | KeyCode::Char('k')) => {
+                    self.scroll = self.scroll.saturating_sub(1)
+                }
+                (View::Minimals, KeyCode::Down | KeyCode::Char('j')) => self.scroll += 1,
+                (View::Minimals, KeyCode::Char('/') | KeyCode::Tab) => {
+                    self.view = View::Lookup;
+                    self.lookup = None;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn selected(&self) -> Option<&SetupEntry<'_>> {
+        self.list.selected().and_then(|i| self.setups.get(i))
+    }
+
+    fn current_minimals(&self) -> Option<&Minimals> {
+        self.list.selected().and_then(|i| self.minimals.get(&i))
+    }
+
+    fn load_minimals(&mut self) {
+        let Some(i) = self.list.selected() else {
+            return;
+        };
+        self.set = 0;
+        self.scroll = 0;
+        if self.minimals.contains_key(&i) {
+            return;
+        }
+
+        let setup = self.setups[i].setup;
+        let (pattern, universe) = self.query.solve_pattern(setup);
+        let (solves, sets, equiv) =
+            self.qbf
+                .all_min_sets(&setup.board, &pattern, &universe, &self.query.saves);
+
+        let mut common: FxHashSet<usize> = sets.first().into_iter().flatten().copied().collect();
+        for set in sets.iter().skip(1) {
+            common.retain(|idx| set.contains(idx));
+        }
+
+        self.minimals.insert(
+            i,
+            Minimals {
+                solves,
+                sets,
+                common: common.into_iter().sorted().collect(),
+                equiv: equiv
+                    .into_iter()
+                    .sorted()
+                    .map(|(solve, others)| std::iter::once(solve).chain(others).collect())
+                    .collect(),
+            },
+        );
+    }
+
+    fn lookup(&self) -> Lookup {
+        let (Some(entry), Some(minimals)) = (self.selected(), self.current_minimals()) else {
+            return Lookup::Invalid;
+        };
+        let setup = entry.setup;
+        let held = setup.held.map_or("", |s| s.name());
+        let queue = format!("{held}{}", self.input);
+
+        let (_, universe) = self.query.solve_pattern(setup);
+        if !universe.contains(&queue) {
+            return Lookup::Invalid;
+        }
+
+        let in_set: Vec<usize> = minimals
+            .common
+            .iter()
+            .copied()
+            .chain(minimals.unique(self.set))
+            .collect();
+        let pattern = queue.chars().join(",");
+        let start = BrokenBoard::from_garbage(setup.board.to_broken_bitboard().0);
+
+        let mut outside = Vec::new();
+        for tier in &self.query.saves.tiers {
+            let mut found = Vec::new();
+            for save in tier.saves() {
+                for solve in self
+                    .qbf
+                    .compute(&pattern, &start, &QueueStart::default(), save)
+                {
+                    match in_set.iter().find(|&&i| minimals.solves[i] == solve) {
+                        Some(&i) => found.push((i, save)),
+                        None => outside.push((solve, save)),
+                    }
+                }
+            }
+            if !found.is_empty() {
+                return Lookup::Found(found);
+            }
+        }
+        Lookup::Outside(outside)
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [body, help] =
+            Layout::vertical([Constraint::Fill(1), Constraint::Length(1)]).areas(frame.area());
+
+        let keys = match self.view {
+            View::Setups => "↑/↓ select  enter minimal sets  q quit",
+            View::Minimals => "←/→ set  ↑/↓ scroll  / queue lookup  esc back  q quit",
+            View::Lookup => "type a queue  enter look up  esc back",
+        };
+        frame.render_widget(Paragraph::new(keys), help);
+
+        match self.view {
+            View::Setups => self.draw_setups(frame, body),
+            View::Minimals => self.draw_minimals(frame, body),
+            View::Lookup => self.draw_lookup(frame, body),
+        }
+    }
+
+    fn draw_setups(&mut self, frame: &mut Frame, area: Rect) {
+        let [list_area, preview] =
+            Layout::horizontal([Constraint::Length(32), Constraint::Fill(1)]).areas(area);
+
+        let items: Vec<ListItem> = self
+            .setups
+            .iter()
+            .enumerate()
+            .map(|(i, s)| {
+                let min = if s.min_count > 0 {
+                    format!("  min {}", s.min_count)
+                } else {
+                    String::new()
+                };
+                ListItem::new(format!(
+                    "#{:<3} {}/{} saves{min}",
+                    i + 1,
+                    s.saves,
+                    s.queue_count
+                ))
+            })
+            .collect();
+        let list = List::new(items)
+            .block(Block::bordered().title(format!(" {} setups ", self.setups.len())))
+            .highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(list, list_area, &mut self.list);
+
+        let lines = self
+            .selected()
+            .map(|s| board_lines(&s.setup.board))
+            .unwrap_or_default();
+        frame.render_widget(
+            Paragraph::new(lines).block(Block::bordered().title(" Setup ")),
+            preview,
+        );
+    }
+
+    fn draw_minimals(&self, frame: &mut Frame, area: Rect) {
+        let Some(minimals) = self.current_minimals() else {
+            return;
+        };
+        let width = area.width.saturating_sub(2);
+        let solve_boards = |idxs: &[usize]| -> Vec<(&BrokenBoard, String)> {
+            idxs.iter()
+                .map(|&i| (&minimals.solves[i], String::new()))
+                .collect()
+        };
+
+        let mut lines = Vec::new();
+        if let Some(entry) = self.selected() {
+            lines.extend(board_lines(&entry.setup.board));
+            lines.push(Line::raw(format!(
+                "{}/{} saves  min count {}",
+                entry.saves,
+                entry.queue_count,
+                minimals.sets.first().map_or(0, |s| s.len())
+            )));
+            lines.push(Line::raw(""));
+        }
+
+        lines.push(heading(format!("Common ({})", minimals.common.len())));
+        lines.extend(board_grid(&solve_boards(&minimals.common), width));
+
+        let unique = minimals.unique(self.set);
+        lines.push(heading(format!(
+            "Set {}/{} unique ({})",
+            self.set + 1,
+            minimals.sets.len(),
+            unique.len()
+        )));
+        lines.extend(board_grid(&solve_boards(&unique), width));
+
+        for (i, group) in minimals.equiv.iter().enumerate() {
+            lines.push(heading(format!("Equivalent solves {}", i + 1)));
+            lines.extend(board_grid(&solve_boards(group), width));
+        }
+
+        frame.render_widget(
+            Paragraph::new(lines)
+                .scroll((self.scroll, 0))
+                .block(Block::bordered().title(" Minimal sets ")),
+            area,
+        );
+    }
+
+    fn draw_lookup(&self, frame: &mut Frame, area: Rect) {
+        let [input, result] =
+            Layout::vertical([Constraint::Length(3), Constraint::Fill(1)]).areas(area);
+        frame.render_widget(
+            Paragraph::new(self.input.as_str())
+                .block(Block::bordered().title(format!(" Queue (set {}) ", self.set + 1))),
+            input,
+        );
+
+        let width = result.width.saturating_sub(2);
+        let save_label = |save: &Option<Shape>| match save {
+            Some(s) => format!("save {}", s.name()),
+            None => "no save".to_owned(),
+        };
+        let lines = match (&self.lookup, self.current_minimals()) {
+            (None, _) | (_, None) => vec![],
+            (Some(Lookup::Invalid), _) => vec![Line::raw("Queue is not in the solve pattern")],
+            (Some(Lookup::Found(found)), Some(minimals)) => {
+                let boards: Vec<_> = found
+                    .iter()
+                    .map(|(i, save)| (&minimals.solves[*i], save_label(save)))
+                    .collect();
+                let mut lines = vec![heading("Use".to_owned())];
+                lines.extend(board_grid(&boards, width));
+                lines
+            }
+            (Some(Lookup::Outside(outside)), _) if outside.is_empty() => {
+                vec![Line::raw("No solution for this queue")]
+            }
+            (Some(Lookup::Outside(outside)), _) => {
+                let boards: Vec<_> = outside.iter().map(|(b, s)| (b, save_label(s))).collect();
+                let mut lines = vec![heading(format!(
+                    "Not covered by set {}, other solutions",
+                    self.set + 1
+                ))];
+                lines.extend(board_grid(&boards, width));
+                lines
+            }
+        };
+        frame.render_widget(
+            Paragraph::new(lines).block(Block::bordered().title(" Solution ")),
+            result,
+        );
+    }
+}
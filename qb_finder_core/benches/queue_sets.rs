@@ -0,0 +1,74 @@
+//! Times taking every shape from every reachable set of queue states, as the solver does
+//! for each placement, with the two [`QueueSet`] kinds it picks between for bags of up to
+//! 7 pieces.
+//!
+//! Run with `cargo bench -p qb_finder_core --bench queue_sets`.
+
+use std::{hint::black_box, time::Instant};
+
+use qb_finder_core::queue::{Bag, BitQueues, QueueSet, QueueState};
+use smallvec::SmallVec;
+use srs_4l::gameplay::Shape;
+
+const ROUNDS: u32 = 20;
+
+/// A partly used bag of every subset and count, followed by a full bag.
+fn patterns() -> Vec<Vec<Bag>> {
+    let mut patterns = Vec::new();
+    for subset in 1..(1u8 << 7) {
+        let shapes: Vec<Shape> = Shape::ALL
+            .into_iter()
+            .enumerate()
+            .filter(|&(i, _)| subset & (1 << i) != 0)
+            .map(|(_, s)| s)
+            .collect();
+        for count in 1..=shapes.len() as u8 {
+            patterns.push(vec![Bag::new(&shapes, count), Bag::new(&Shape::ALL, 7)]);
+        }
+    }
+    patterns
+}
+
+/// Walks every pattern, returning the number of sets taken from.
+fn walk<S: QueueSet>(patterns: &[Vec<Bag>], can_hold: bool) -> usize {
+    let mut taken = 0;
+    for bags in patterns {
+        let prepared: Vec<S::Bag> = bags.iter().map(S::prepare).collect();
+        let mut queues = S::init_hold(&prepared[0]);
+        let steps = bags
+            .iter()
+            .zip(&prepared)
+            .flat_map(|(bag, prepared)| (0..bag.count).map(move |i| (prepared, i == 0)))
+            .skip(1);
+        for (bag, is_first) in steps {
+            let mut next = S::default();
+            for shape in Shape::ALL {
+                next.union(&queues.take(bag, shape, is_first, can_hold));
+                taken += 1;
+            }
+            queues = black_box(next);
+        }
+    }
+    taken
+}
+
+fn bench<S: QueueSet>(name: &str, patterns: &[Vec<Bag>]) {
+    let start = Instant::now();
+    let mut taken = 0;
+    for _ in 0..ROUNDS {
+        taken += walk::<S>(black_box(patterns), true);
+        taken += walk::<S>(black_box(patterns), false);
+    }
+    let elapsed = start.elapsed();
+    println!(
+        "{name:>12}: {:>8.2?} total, {:>6.1} ns per take",
+        elapsed,
+        elapsed.as_nanos() as f64 / taken as f64
+    );
+}
+
+fn main() {
+    let patterns = patterns();
+    bench::<SmallVec<[QueueState<u16>; 7]>>("QueueState", &patterns);
+    bench::<BitQueues>("BitQueues", &patterns);
+}
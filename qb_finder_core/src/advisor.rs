@@ -0,0 +1,187 @@
+use std::cmp::Ordering;
+
+use itertools::Itertools;
+use rayon::prelude::{IntoParallelIterator, ParallelIterator};
+use srs_4l::{brokenboard::BrokenBoard, gameplay::Shape, vector::Placements};
+
+use crate::{
+    QBFinder,
+    queue::Bag,
+    saves::{SavePolicy, SaveTier},
+};
+
+/// What a player can see mid-game.
+pub struct GameState {
+    /// Garbage bits of the bottom 4 rows.
+    pub field: u64,
+    pub hold: Option<Shape>,
+    /// Current piece followed by the previews.
+    pub previews: Vec<Shape>,
+    /// Pieces drawn from the current bag before the current piece.
+    pub bag_used: Vec<Shape>,
+}
+
+/// A placement of the current or hold piece and how often it leads to a PC.
+pub struct Advice {
+    pub shape: Shape,
+    pub uses_hold: bool,
+    pub board: BrokenBoard,
+    /// Chance of a PC meeting each save tier, in the order of the policy.
+    pub save_rates: Vec<(SaveTier, f64)>,
+    pub pc_rate: f64,
+}
+
+impl GameState {
+    /// Pieces in the order the solver takes them, with the held piece first.
+    fn known(&self) -> Vec<Shape> {
+        self.hold.iter().chain(&self.previews).copied().collect()
+    }
+
+    /// Pieces of the bag the last preview belongs to that have not been seen yet.
+    pub fn unseen(&self) -> Vec<Shape> {
+        let mut bag: Vec<Shape> = Shape::ALL
+            .into_iter()
+            .filter(|s| !self.bag_used.contains(s))
+            .collect();
+        for shape in &self.previews {
+            if !bag.contains(shape) {
+                bag = Shape::ALL.to_vec();
+            }
+            bag.retain(|s| s != shape);
+        }
+        bag
+    }
+}
+
+/// Every equally likely order of the next `count` pieces, drawing `bag` first and then full bags.
+pub fn unseen_queues(bag: &[Shape], count: usize) -> Vec<Vec<Shape>> {
+    let mut groups: Vec<(Vec<Shape>, usize)> = Vec::new();
+    let mut rest = count;
+    if rest > 0 && !bag.is_empty() {
+        let taken = rest.min(bag.len());
+        groups.push((bag.to_vec(), taken));
+        rest -= taken;
+    }
+    while rest > 0 {
+        let taken = rest.min(7);
+        groups.push((Shape::ALL.to_vec(), taken));
+        rest -= taken;
+    }
+
+    groups
+        .into_iter()
+        .map(|(shapes, taken)| shapes.into_iter().permutations(taken).collect_vec())
+        .multi_cartesian_product()
+        .map(|queue| queue.concat())
+        .collect()
+}
+
+impl QBFinder {
+    /// Ranks every placement of the current or hold piece by the chance of finishing the PC
+    /// meeting each tier of `saves`, averaged over the orders of the unseen pieces.
+    pub fn advise(&self, state: &GameState, saves: &SavePolicy) -> Vec<Advice> {
+        let filled = state.field.count_ones() as usize;
+        if !filled.is_multiple_of(4) || filled >= 40 || state.previews.is_empty() {
+            return vec![];
+        }
+        let start = BrokenBoard::from_garbage(state.field);
+        // without hold only the current piece can be placed, and the held one never comes back
+        let known = match self.hold {
+            true => state.known(),
+            false => state.previews.clone(),
+        };
+        let unseen = state.unseen();
+
+        // pieces after this placement, including the one left in hold
+        let total = (40 - filled) / 4 - 1 + self.hold as usize;
+
+        let mut options: Vec<(Shape, bool, BrokenBoard, Vec<Shape>)> = Vec::new();
+        for i in 0..known.len().min(1 + self.hold as usize) {
+            let mut rest = known.clone();
+            let shape = rest.remove(i);
+            let uses_hold = self.hold && (i == 0) == state.hold.is_some();
+            for (piece, board) in Placements::place(start.board, shape, self.physics).canonical() {
+                if !self.legal_boards.is_empty() && !self.legal_boards.contains(&board) {
+                    continue;
+                }
+                let board = start.place(piece);
+                if options.iter().all(|(_, _, b, _)| *b != board) {
+                    options.push((shape, uses_hold, board, rest.clone()));
+                }
+            }
+        }
+
+        let queues = unseen_queues(&unseen, total.saturating_sub(known.len() - 1));
+        let single_bags = |rest: &[Shape], queue: &[Shape]| -> Vec<Bag> {
+            rest.iter()
+                .chain(queue)
+                .take(total)
+                .map(|&s| Bag::new(&[s], 1))
+                .collect()
+        };
+
+        let mut advice: Vec<Advice> = options
+            .into_par_iter()
+            .filter_map(|(shape, uses_hold, board, rest)| {
+                // skip placements that fail even when the unseen pieces come in their best order
+                let mut optimistic = single_bags(&rest, &[]);
+                if rest.len() < total && !unseen.is_empty() {
+                    optimistic.push(Bag::new(
+                        &unseen,
+                        (total - rest.len()).min(unseen.len()) as u8,
+                    ));
+                }
+                let optimistic_count: usize = optimistic.iter().map(|b| b.count as usize).sum();
+                if optimistic_count == total
+                    && self.compute_bags(&optimistic, &board, None).is_empty()
+                {
+                    return None;
+                }
+
+                let mut pc_count = 0;
+                let mut save_counts = vec![0; saves.tiers.len()];
+                for queue in &queues {
+                    let bags = single_bags(&rest, queue);
+                    if self.compute_bags(&bags, &board, None).is_empty() {
+                        continue;
+                    }
+                    pc_count += 1;
+                    for (j, tier) in saves.tiers.iter().enumerate() {
+                        if self.meets_tier(&board, &bags, tier) {
+                            save_counts[j] += 1;
+                        }
+                    }
+                }
+                if pc_count == 0 {
+                    return None;
+                }
+
+                let total_queues = queues.len() as f64;
+                Some(Advice {
+                    shape,
+                    uses_hold,
+                    board,
+                    save_rates: saves
+                        .tiers
+                        .iter()
+                        .zip(save_counts)
+                        .map(|(tier, c)| (tier.clone(), c as f64 / total_queues))
+                        .collect(),
+                    pc_rate: pc_count as f64 / total_queues,
+                })
+            })
+            .collect();
+
+        advice.sort_by(|a, b| {
+            let rates = |x: &Advice| {
+                x.save_rates
+                    .iter()
+                    .map(|&(_, r)| r)
+                    .chain([x.pc_rate])
+                    .collect_vec()
+            };
+            rates(b).partial_cmp(&rates(a)).unwrap_or(Ordering::Equal)
+        });
+        advice
+    }
+}
@@ -0,0 +1,483 @@
+use std::{cmp::Ordering, ops::RangeInclusive};
+
+use itertools::Itertools;
+use rayon::prelude::{IntoParallelRefIterator, ParallelIterator};
+use rustc_hash::FxHashSet;
+use srs_4l::{base64::base64_decode, brokenboard::BrokenBoard, gameplay::Shape, queue::Queue};
+
+use crate::{
+    QBFinder, Ranking, build_queues, encode_board, expand_pattern, format_group,
+    minimals::{all_min_cover_sets, min_cover_size, min_weight_cover},
+    parse_group, parse_shape, pattern_bags,
+    queue::{Bag, QueueStart},
+    saves::{SavePolicy, SaveScore},
+};
+
+/// How many build pieces a setup may leave unplaced. A setup leaving any holds one of them, and
+/// the rest are the last pieces of the build queue, still to come.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct BuildMode {
+    /// Fewest build pieces to leave.
+    pub min_left: usize,
+    /// Most build pieces to leave, tried in turn only while leaving fewer finds no setups.
+    pub max_left: usize,
+}
+
+impl BuildMode {
+    /// Numbers of pieces to leave, in the order to try them. Holding `build_save` leaves at
+    /// least one.
+    pub(crate) fn lefts(&self, build_save: Option<Shape>) -> RangeInclusive<usize> {
+        let min = self.min_left.max(build_save.is_some() as usize);
+        min..=self.max_left.max(min)
+    }
+}
+
+impl Default for BuildMode {
+    fn default() -> BuildMode {
+        BuildMode {
+            min_left: 0,
+            max_left: 1,
+        }
+    }
+}
+
+/// A setup the build can reach, with the build pieces it leaves for the solve.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Setup {
+    pub board: BrokenBoard,
+    /// Build piece left in hold.
+    pub held: Option<Shape>,
+    /// Pattern of the build pieces still to come after the setup.
+    pub unplaced: String,
+}
+
+impl Setup {
+    /// A setup placing every build piece.
+    pub fn placed(board: BrokenBoard) -> Setup {
+        Setup {
+            board,
+            held: None,
+            unplaced: String::new(),
+        }
+    }
+
+    /// Pattern of the build pieces the setup leaves, held piece first.
+    pub fn left(&self) -> String {
+        self.held
+            .map(|s| s.name())
+            .into_iter()
+            .chain((!self.unplaced.is_empty()).then_some(self.unplaced.as_str()))
+            .join(",")
+    }
+
+    /// `solve_pattern` with the pieces the setup leaves in front of every line.
+    pub fn solve_pattern(&self, solve_pattern: &str) -> String {
+        let left = self.left();
+        if left.is_empty() {
+            return solve_pattern.to_owned();
+        }
+        solve_pattern
+            .lines()
+            .map(|line| format!("{left},{line}"))
+            .join("\n")
+    }
+
+    /// The board as [`BrokenBoard::encode`] in base64, followed by `~` and the held piece and
+    /// `~` and the unplaced pattern with `.` between groups if the setup leaves any pieces. Has
+    /// no commas, spaces or newlines.
+    pub fn encode(&self) -> String {
+        let mut encoded = encode_board(&self.board);
+        if let Some(held) = self.held {
+            encoded.push('~');
+            encoded.push_str(held.name());
+            encoded.push('~');
+            encoded.push_str(&self.unplaced.replace(',', "."));
+        }
+        encoded
+    }
+
+    /// Reads a setup written by [`Setup::encode`].
+    pub fn decode(encoded: &str) -> Option<Setup> {
+        let mut parts = encoded.split('~');
+        let board = BrokenBoard::decode(&base64_decode(parts.next()?)?)?;
+        let held = match parts.next() {
+            Some(held) => Some(parse_shape(held.chars().next()?)?),
+            None => None,
+        };
+        let unplaced = parts.next().unwrap_or_default().replace('.', ",");
+        Some(Setup {
+            board,
+            held,
+            unplaced,
+        })
+    }
+
+    /// Build pieces the setup has drawn, placed or held.
+    pub(crate) fn drawn(&self) -> usize {
+        self.board.pieces.len() + self.held.iter().count()
+    }
+}
+
+/// The first pieces of `bags`, leaving out their last `left`.
+fn draw_first(bags: &[Bag], left: usize) -> Option<Vec<Bag>> {
+    let total: usize = bags.iter().map(|b| b.count as usize).sum();
+    let mut to_draw = total.checked_sub(left)?;
+    let mut drawn = Vec::new();
+    for bag in bags {
+        if to_draw == 0 {
+            break;
+        }
+        let count = (bag.count as usize).min(to_draw);
+        to_draw -= count;
+        drawn.push(Bag {
+            count: count as u8,
+            ..bag.clone()
+        });
+    }
+    Some(drawn)
+}
+
+/// What is left of the pattern `groups` once `drawn` pieces have come out of its front, or
+/// `None` if they can't have.
+fn rest_of_pattern(groups: &[&str], mut drawn: Vec<Shape>) -> Option<String> {
+    let mut rest = Vec::new();
+    for group in groups {
+        let (shapes, mut count) = parse_group(group)?;
+        let mut left = Vec::new();
+        for shape in shapes {
+            match drawn.iter().position(|&s| s == shape) {
+                Some(i) if count > 0 => {
+                    drawn.remove(i);
+                    count -= 1;
+                }
+                _ => left.push(shape),
+            }
+        }
+        if count > 0 {
+            rest.push(format_group(&left, count));
+        }
+    }
+    drawn.is_empty().then(|| rest.join(","))
+}
+
+/// How many queues of a build pattern can build a setup, from [`QBFinder::build_cover`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BuildCover {
+    /// Build queues, each with the starting hold piece first.
+    pub total: usize,
+    /// Build queues that can build the setup using hold.
+    pub with_hold: usize,
+    /// Build queues that can build the setup placing the pieces in the order they come.
+    pub without_hold: usize,
+    /// Build queues that can't build the setup even using hold.
+    pub missing: Vec<String>,
+}
+
+impl BuildCover {
+    /// Share of the build queues that can build the setup using hold, from 0 to 1.
+    pub fn fraction(&self) -> f64 {
+        ratio(self.with_hold, self.total)
+    }
+
+    /// Share of the build queues that can build the setup without hold, from 0 to 1.
+    pub fn fraction_without_hold(&self) -> f64 {
+        ratio(self.without_hold, self.total)
+    }
+
+    /// Orders by the share of build queues covered using hold, then without it, so setups
+    /// can be sorted by how often they can be built.
+    pub fn cmp_cover(&self, other: &BuildCover) -> Ordering {
+        (self.with_hold * other.total)
+            .cmp(&(other.with_hold * self.total))
+            .then((self.without_hold * other.total).cmp(&(other.without_hold * self.total)))
+    }
+}
+
+fn ratio(n: usize, total: usize) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        n as f64 / total as f64
+    }
+}
+
+/// The fewest setups that together can be built from every build queue, from
+/// [`QBFinder::build_minimals`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BuildMinimals {
+    /// Every setup considered, with its save score.
+    pub setups: Vec<(Setup, SaveScore)>,
+    /// Each smallest combination of setups, as indices into `setups`.
+    pub sets: Vec<Vec<usize>>,
+    /// Build queues none of the setups can be built from, left out of the cover.
+    pub uncovered: Vec<String>,
+}
+
+/// Solve queues for `setup`: its left pieces, then each of `solve_queues` cut to the
+/// `p_count` pieces the PC still needs.
+pub(crate) fn setup_solve_queues(
+    setup: &Setup,
+    solve_queues: &[String],
+    p_count: usize,
+) -> Vec<Vec<Bag>> {
+    let unplaced = if setup.unplaced.is_empty() {
+        vec![String::new()]
+    } else {
+        expand_pattern(&setup.unplaced)
+    };
+    unplaced
+        .iter()
+        .cartesian_product(solve_queues)
+        .map(|(first, q)| {
+            setup
+                .held
+                .into_iter()
+                .chain(first.chars().filter_map(parse_shape))
+                .chain(q.chars().take(p_count).filter_map(parse_shape))
+                .map(|s| Bag::new(&[s], 1))
+                .collect()
+        })
+        .collect()
+}
+
+impl QBFinder {
+    /// Setups `build_pattern` can reach leaving exactly `left` of its pieces, counting the one in
+    /// `hold` before it. `held` limits the piece left in hold.
+    pub(crate) fn build_setups(
+        &self,
+        build_pattern: &str,
+        hold: Option<Shape>,
+        left: usize,
+        held: Option<Shape>,
+    ) -> Vec<Setup> {
+        let lines = build_pattern
+            .lines()
+            .map(|l| l.trim())
+            .filter(|l| !l.is_empty());
+        if left == 0 {
+            if held.is_some() {
+                return vec![];
+            }
+            return lines
+                .flat_map(|line| self.solve(&self.start, &pattern_bags(line), hold, None))
+                .unique()
+                .map(Setup::placed)
+                .collect();
+        }
+
+        let mut setups = Vec::new();
+        for line in lines {
+            let bags = pattern_bags(line);
+            let Some(drawn) = draw_first(&bags, left - 1) else {
+                continue;
+            };
+            let groups: Vec<&str> = line.split(',').collect();
+            let candidates = hold
+                .into_iter()
+                .chain(
+                    Shape::ALL
+                        .into_iter()
+                        .filter(|&s| drawn.iter().any(|b| b.contains(s))),
+                )
+                .filter(|&s| held.is_none_or(|h| h == s))
+                .unique();
+            for save in candidates {
+                for board in self.solve(&self.start, &drawn, hold, Some(save)) {
+                    // everything drawn is placed or held, so the rest is what the queue has left
+                    let mut drawn_shapes: Vec<Shape> =
+                        board.pieces.iter().map(|p| p.shape).chain([save]).collect();
+                    if let Some(h) = hold
+                        && let Some(i) = drawn_shapes.iter().position(|&s| s == h)
+                    {
+                        drawn_shapes.remove(i);
+                    }
+                    let Some(unplaced) = rest_of_pattern(&groups, drawn_shapes) else {
+                        continue;
+                    };
+                    setups.push(Setup {
+                        board,
+                        held: Some(save),
+                        unplaced,
+                    });
+                }
+            }
+        }
+        setups.into_iter().unique().collect()
+    }
+
+    /// Which queues of `build_queue` can build `setup`, with `start` before them.
+    pub fn build_cover(&self, setup: &Setup, build_queue: &str, start: &QueueStart) -> BuildCover {
+        self.cover(setup, &build_queues(start, &start.apply(build_queue)))
+    }
+
+    /// The fewest setups keeping the first tier of `saves` in some queue that together can be
+    /// built from every queue of `build_queue`. With `by_saves`, only the combination of that
+    /// size keeping the most queues in the first tier, ties going to the most in any tier;
+    /// otherwise every combination of that size.
+    pub fn build_minimals(
+        &self,
+        build_queue: &str,
+        start: &QueueStart,
+        solve_queue: &str,
+        saves: &SavePolicy,
+        by_saves: bool,
+    ) -> BuildMinimals {
+        let setups = self.find_ranked(
+            build_queue,
+            start,
+            None,
+            solve_queue,
+            saves,
+            Ranking::AtLeast(1),
+        );
+        if self.task.is_cancelled() {
+            return BuildMinimals::default();
+        }
+
+        let build_queues = build_queues(start, &start.apply(build_queue));
+        let covered: Vec<FxHashSet<String>> = setups
+            .par_iter()
+            .map(|(setup, _)| {
+                let missing: FxHashSet<String> = self
+                    .cover(setup, &build_queues)
+                    .missing
+                    .into_iter()
+                    .collect();
+                build_queues
+                    .iter()
+                    .map(|q| q.to_string())
+                    .filter(|q| !missing.contains(q))
+                    .collect()
+            })
+            .collect();
+        let (universe, uncovered): (Vec<String>, Vec<String>) = build_queues
+            .iter()
+            .map(|q| q.to_string())
+            .unique()
+            .partition(|q| covered.iter().any(|c| c.contains(q)));
+        let universe: FxHashSet<String> = universe.into_iter().collect();
+
+        let sets = if universe.is_empty() {
+            vec![]
+        } else if by_saves {
+            // fewest setups first, then the most queues kept in the first tier, then in any
+            let size = min_cover_size(&universe, &covered);
+            let weights: Vec<f64> = setups
+                .iter()
+                .map(|(_, score)| -(score.primary() as f64) - score.total() as f64 / 1e6)
+                .collect();
+            min_weight_cover(&universe, &covered, &weights, size)
+                .into_iter()
+                .collect()
+        } else {
+            all_min_cover_sets(&universe, &covered, &self.task)
+        };
+
+        BuildMinimals {
+            setups,
+            sets,
+            uncovered,
+        }
+    }
+
+    /// [`QBFinder::build_cover`] of each of `setups`, in order.
+    pub fn build_covers(
+        &self,
+        setups: &[Setup],
+        build_queue: &str,
+        start: &QueueStart,
+    ) -> Vec<BuildCover> {
+        let build_queues = build_queues(start, &start.apply(build_queue));
+        setups
+            .par_iter()
+            .map(|setup| self.cover(setup, &build_queues))
+            .collect()
+    }
+
+    /// The queues of the pieces `setup` draws that build it placing them in order, and those
+    /// that build it using hold, each starting with the hold piece.
+    pub(crate) fn build_orders(&self, setup: &Setup) -> (FxHashSet<Queue>, FxHashSet<Queue>) {
+        let orders: FxHashSet<Queue> = setup
+            .board
+            .supporting_queues(self.physics)
+            .iter()
+            .map(|&q| match setup.held {
+                Some(s) => q.push_last(s),
+                None => q,
+            })
+            .collect();
+        let with_hold = orders.iter().flat_map(|q| q.unhold()).collect();
+        (orders, with_hold)
+    }
+
+    /// Which of `build_queues`, each starting with the hold piece, can build `setup`.
+    pub(crate) fn cover(&self, setup: &Setup, build_queues: &[Queue]) -> BuildCover {
+        let (orders, with_hold) = self.build_orders(setup);
+        let drawn = setup.drawn();
+
+        let mut cover = BuildCover {
+            total: build_queues.len(),
+            ..BuildCover::default()
+        };
+        for q in build_queues {
+            // the pieces left unplaced don't matter to the build
+            let prefix: Queue = q.take(drawn).collect();
+            if orders.contains(&prefix) {
+                cover.without_hold += 1;
+            }
+            if with_hold.contains(&prefix) {
+                cover.with_hold += 1;
+            } else {
+                cover.missing.push(q.to_string());
+            }
+        }
+        cover
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shapes(pieces: &str) -> Vec<Shape> {
+        pieces.chars().map(|c| parse_shape(c).unwrap()).collect()
+    }
+
+    #[test]
+    fn setup_round_trip() {
+        let board = BrokenBoard::from_garbage(0b1111);
+        let placed = Setup::placed(board.clone());
+        assert!(!placed.encode().contains('~'));
+        assert_eq!(Setup::decode(&placed.encode()), Some(placed));
+
+        let setup = Setup {
+            board,
+            held: Some(Shape::T),
+            unplaced: "IJ,LOSZTp3".to_owned(),
+        };
+        let encoded = setup.encode();
+        assert!(!encoded.contains([',', ' ', '\n']));
+        assert_eq!(Setup::decode(&encoded), Some(setup));
+    }
+
+    #[test]
+    fn rest_after_drawing() {
+        let groups = ["IJLOSZT", "IJLOSZT"];
+        let rest = |drawn| rest_of_pattern(&groups, shapes(drawn));
+        assert_eq!(rest("").as_deref(), Some("IJLOSZT,IJLOSZT"));
+        assert_eq!(rest("JI").as_deref(), Some("LOSZT,IJLOSZT"));
+        assert_eq!(rest("TIJLOSZ").as_deref(), Some("IJLOSZT"));
+        assert_eq!(rest("II").as_deref(), Some("JLOSZT,JLOSZT"));
+        assert_eq!(rest("III"), None);
+    }
+
+    #[test]
+    fn rest_of_counted_group() {
+        let groups = ["T", "IJLOSZp3"];
+        let rest = |drawn| rest_of_pattern(&groups, shapes(drawn));
+        assert_eq!(rest("TI").as_deref(), Some("JLOSZp2"));
+        assert_eq!(rest("TIJL").as_deref(), Some(""));
+        assert_eq!(rest("TIJLO"), None);
+        assert_eq!(rest("TT"), None);
+    }
+}
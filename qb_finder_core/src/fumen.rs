@@ -0,0 +1,72 @@
+use srs_4l::brokenboard::BrokenBoard;
+
+use crate::solver;
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+const FIELD_BLOCKS: usize = 240;
+
+/// Empty piece with the colour flag set and the lock flag cleared.
+const EMPTY_ACTION: u32 = 153_600;
+
+fn block_value(c: char) -> u32 {
+    match c {
+        'I' => 1,
+        'L' => 2,
+        'O' => 3,
+        'Z' => 4,
+        'T' => 5,
+        'J' => 6,
+        'S' => 7,
+        'G' => 8,
+        _ => 0,
+    }
+}
+
+fn poll(mut value: u32, len: usize, to: &mut String) {
+    for _ in 0..len {
+        to.push(BASE64[(value % 64) as usize] as char);
+        value /= 64;
+    }
+}
+
+/// Encodes a board as single page v115 fumen data, including the `v115@` prefix.
+pub fn encode(board: &BrokenBoard) -> String {
+    let mut cells = String::with_capacity(40);
+    solver::print(board, &mut cells);
+
+    let mut field = [0; FIELD_BLOCKS];
+    for (i, c) in cells.chars().enumerate() {
+        let row = 3 - i / 10;
+        field[(22 - row) * 10 + i % 10] = block_value(c);
+    }
+
+    let mut runs: Vec<(u32, u32)> = Vec::new();
+    for value in field {
+        match runs.last_mut() {
+            Some((diff, count)) if *diff == value + 8 => *count += 1,
+            _ => runs.push((value + 8, 1)),
+        }
+    }
+
+    let mut data = String::new();
+    for &(diff, count) in &runs {
+        poll(diff * FIELD_BLOCKS as u32 + count - 1, 2, &mut data);
+    }
+    if runs.len() == 1 && runs[0].0 == 8 {
+        poll(0, 1, &mut data);
+    }
+    poll(EMPTY_ACTION, 3, &mut data);
+
+    if data.len() >= 41 {
+        let (head, tail) = data.split_at(42.min(data.len()));
+        let mut split = head.to_owned();
+        for chunk in tail.as_bytes().chunks(47) {
+            split.push('?');
+            split.push_str(std::str::from_utf8(chunk).unwrap());
+        }
+        data = split;
+    }
+
+    format!("v115@{data}")
+}
@@ -1,34 +1,83 @@
+pub mod advisor;
+pub mod build;
+pub mod fumen;
+pub mod memo;
 pub mod minimals;
+pub mod pareto;
+pub mod pc;
 pub mod queue;
+pub mod randomizer;
+pub mod route;
+pub mod saves;
+pub mod simulate;
 pub mod solver;
-
-use std::sync::atomic::{AtomicUsize, Ordering};
+pub mod store;
+pub mod task;
+
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    io,
+    path::Path,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+};
 
 use itertools::Itertools;
 use rayon::prelude::{IntoParallelIterator, ParallelIterator};
 
 use rustc_hash::{FxHashMap, FxHashSet};
 use srs_4l::{
+    base64::base64_encode,
     brokenboard::BrokenBoard,
     gameplay::{Board, Physics, Shape},
+    queue::Queue,
 };
 
+use crate::build::{BuildMode, Setup, setup_solve_queues};
+use crate::memo::{CacheStats, ComputeCache, ComputeKey};
 use crate::minimals::{all_min_cover_sets, min_cover_size};
-use crate::queue::Bag;
+use crate::queue::{Bag, QueueStart};
+use crate::saves::{SavePolicy, SaveScore, SaveTier};
+use crate::store::{ResultStore, StoreKey, boards_checksum};
+use crate::task::Task;
 
 fn pattern_bags(pattern: &str) -> Vec<Bag> {
     let mut bags = Vec::new();
     for bag in pattern.split(",") {
-        let shapes = bag
-            .chars()
-            .map(parse_shape)
-            .collect::<Option<Vec<Shape>>>()
-            .unwrap();
-        bags.push(Bag::new(&shapes, bag.len() as u8));
+        let (shapes, count) = parse_group(bag).unwrap();
+        bags.push(Bag::new(&shapes, count as u8));
     }
     bags
 }
 
+/// Reads a group of a pattern: pieces, each drawn once in any order, optionally followed by `p`
+/// and how many of them are drawn, such as `IJLOSZTp3`. Returns the pieces and the count, or
+/// `None` if the group is not one.
+pub fn parse_group(group: &str) -> Option<(Vec<Shape>, usize)> {
+    let (pieces, count) = match group.split_once(['p', 'P']) {
+        Some((pieces, count)) => (pieces, Some(count.parse().ok()?)),
+        None => (group, None),
+    };
+    let shapes = pieces
+        .chars()
+        .map(parse_shape)
+        .collect::<Option<Vec<Shape>>>()?;
+    let count = count.unwrap_or(shapes.len());
+    (count <= shapes.len()).then_some((shapes, count))
+}
+
+/// A group drawing `count` of `shapes`, as read by [`parse_group`].
+pub(crate) fn format_group(shapes: &[Shape], count: usize) -> String {
+    let pieces: String = shapes.iter().map(|s| s.name()).collect();
+    match count == shapes.len() {
+        true => pieces,
+        false => format!("{pieces}p{count}"),
+    }
+}
+
 pub fn expand_pattern(pattern: &str) -> Vec<String> {
     pattern
         .lines()
@@ -37,10 +86,11 @@ pub fn expand_pattern(pattern: &str) -> Vec<String> {
         .flat_map(|line| {
             line.split(",")
                 .map(|group| {
-                    let len = group.len();
-                    group
-                        .chars()
-                        .permutations(len)
+                    let (shapes, count) = parse_group(group).unwrap_or_default();
+                    shapes
+                        .into_iter()
+                        .map(|s| s.name())
+                        .permutations(count)
                         .unique()
                         .map(|p| p.into_iter().collect::<String>())
                         .collect_vec()
@@ -51,6 +101,51 @@ pub fn expand_pattern(pattern: &str) -> Vec<String> {
         .collect()
 }
 
+/// Every build queue `start` can lead to, with its hold piece first.
+fn build_queues(start: &QueueStart, build_pattern: &str) -> Vec<Queue> {
+    expand_pattern(build_pattern)
+        .into_iter()
+        .map(|q| {
+            start
+                .hold
+                .into_iter()
+                .chain(q.chars().filter_map(parse_shape))
+                .collect()
+        })
+        .collect()
+}
+
+/// Pieces a build queue of `build_pattern` takes, counting the one `start` has in hold.
+fn build_len(start: &QueueStart, build_pattern: &str) -> usize {
+    let pieces: usize = build_pattern
+        .lines()
+        .map(str::trim)
+        .find(|l| !l.is_empty())
+        .map_or(0, |line| {
+            line.split(',')
+                .filter_map(parse_group)
+                .map(|(_, count)| count)
+                .sum()
+        });
+    pieces + start.hold.iter().count()
+}
+
+fn encode_board(board: &BrokenBoard) -> String {
+    let mut encoded = String::new();
+    base64_encode(&board.encode(), &mut encoded);
+    encoded
+}
+
+fn encode_shape(shape: Option<Shape>) -> &'static str {
+    shape.map_or("-", Shape::name)
+}
+
+/// The held piece, or `-`, then the pieces already dealt from the bag, as `T:IJO`.
+fn encode_start(start: &QueueStart) -> String {
+    let bag_used: String = start.bag_used.iter().map(|s| s.name()).collect();
+    format!("{}:{bag_used}", encode_shape(start.hold))
+}
+
 pub fn parse_shape(shape: char) -> Option<Shape> {
     match shape {
         'I' => Some(Shape::I),
@@ -64,281 +159,548 @@ pub fn parse_shape(shape: char) -> Option<Shape> {
     }
 }
 
+const DEFAULT_CACHE_SIZE: usize = 4096;
+
+/// Reported by [`QBFinder::find_with`] while it searches.
+#[derive(Clone, Copy, Debug)]
+pub enum FindEvent<'a> {
+    /// A setup scoring `saves`, which is the best score so far.
+    Setup {
+        setup: &'a Setup,
+        saves: &'a SaveScore,
+    },
+    /// The best score rose to `saves`, so every setup reported before is no longer a result.
+    Superseded { saves: &'a SaveScore },
+}
+
+/// Which setups [`QBFinder::find_ranked`] returns. Setups keeping the first save tier in no
+/// queue never are.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Ranking {
+    /// Every setup keeping the first save tier in at least this many queues.
+    AtLeast(usize),
+    /// At most this many setups, with the best save scores.
+    Top(usize),
+}
+
 /// Contains (**All Solves**, **All Minimal Sets**, **Solve -> Equivalent Cover Map**).
-type SetupMinimals = (
+pub type SetupMinimals = (
     Vec<BrokenBoard>,
     Vec<Vec<usize>>,
     FxHashMap<usize, Vec<usize>>,
 );
 
+#[derive(Clone)]
 pub struct QBFinder {
-    legal_boards: FxHashSet<Board>,
+    legal_boards: Arc<FxHashSet<Board>>,
+    cache: Arc<ComputeCache>,
+    store: Option<Arc<ResultStore>>,
+    boards_checksum: u64,
     start: BrokenBoard,
     physics: Physics,
     pub hold: bool,
-    pub skip_4p: bool,
+    /// How many build pieces setups may leave unplaced.
+    pub build_mode: BuildMode,
     pub full_cover: bool,
+    /// Progress and cancellation for `find`, `min_count` and `all_min_sets`.
+    pub task: Task,
 }
 
 impl QBFinder {
     pub fn new(legal_boards: FxHashSet<Board>) -> QBFinder {
         QBFinder {
-            legal_boards,
+            legal_boards: Arc::new(legal_boards),
+            cache: Arc::new(ComputeCache::new(DEFAULT_CACHE_SIZE)),
+            store: None,
+            boards_checksum: 0,
             start: BrokenBoard::from_garbage(0),
             hold: true,
             physics: Physics::Jstris,
-            skip_4p: false,
+            build_mode: BuildMode::default(),
             full_cover: false,
+            task: Task::default(),
         }
     }
 
-    fn good_save_count(
+    /// [`solver::compute`] with this finder's settings, through the cache.
+    fn solve(
         &self,
         setup: &BrokenBoard,
-        solve_queues: &[Vec<Bag>],
-        saves: &[Shape],
-        cur_best: usize,
-    ) -> usize {
-        let p_save = saves.first().copied();
-        let s_saves = saves.get(1..).unwrap_or_default();
-
-        let mut res = 0;
-
-        for (i, q) in solve_queues.iter().enumerate() {
-            if res + (solve_queues.len() - i) < cur_best {
-                return 0;
-            }
-            if !solver::compute(
+        bags: &[Bag],
+        hold: Option<Shape>,
+        save: Option<Shape>,
+    ) -> Vec<BrokenBoard> {
+        let key = ComputeKey {
+            start: setup.clone(),
+            bags: bags.to_vec(),
+            hold,
+            can_hold: self.hold,
+            save,
+        };
+        self.cache.get_or_insert(key, || {
+            solver::compute(
                 &self.legal_boards,
                 setup,
-                q,
+                bags,
+                hold,
                 self.hold,
                 self.physics,
-                p_save,
+                save,
             )
-            .is_empty()
-            {
-                res += 1;
-                continue;
-            }
+        })
+    }
 
-            if s_saves.iter().all(|&s| {
-                solver::compute(
-                    &self.legal_boards,
-                    setup,
-                    q,
-                    self.hold,
-                    self.physics,
-                    Some(s),
-                )
-                .is_empty()
-            }) {
-                return 0;
-            }
-        }
-        res
+    /// Number of [`solver::compute`] results kept between calls, shared with every clone of this
+    /// finder. 0 turns the cache off.
+    pub fn set_cache_size(&self, size: usize) {
+        self.cache.set_capacity(size);
     }
 
-    pub fn saves_stats(&self, setup: &BrokenBoard, solve_queue: &str, saves: &str) -> Vec<usize> {
-        let save_groups: Vec<Vec<Shape>> = saves
-            .split(",")
-            .map(|g| g.chars().unique().flat_map(parse_shape).collect())
-            .collect();
+    pub fn clear_cache(&self) {
+        self.cache.clear();
+    }
 
-        let mut res = vec![0; save_groups.len()];
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
 
-        let solve_queues: Vec<Vec<Bag>> = expand_pattern(solve_queue)
-            .into_iter()
-            .map(|q| {
-                dbg!(&q);
-                q.chars()
-                    .map(|c| {
-                        let shape = parse_shape(c).expect("Invalid solve pattern");
-                        Bag::new(&[shape], 1)
-                    })
-                    .collect()
-            })
-            .collect();
+    /// Keeps `find`, `all_min_sets` and `saves_stats` results in `dir` across runs.
+    pub fn set_store(&mut self, dir: impl AsRef<Path>) -> io::Result<()> {
+        self.store = Some(Arc::new(ResultStore::open(dir)?));
+        self.boards_checksum = boards_checksum(&self.legal_boards);
+        Ok(())
+    }
 
-        for q in solve_queues {
-            'group: for (j, group) in save_groups.iter().enumerate() {
-                for &save in group {
-                    if !solver::compute(
-                        &self.legal_boards,
-                        setup,
-                        &q,
-                        self.hold,
-                        self.physics,
-                        Some(save),
-                    )
-                    .is_empty()
-                    {
-                        res[j] += 1;
-                        break 'group;
-                    }
-                }
-            }
+    /// Loads the result of a query from the store, or runs it and stores the result.
+    fn stored<T>(
+        &self,
+        query: &[&str],
+        load: impl Fn(&ResultStore, &StoreKey) -> Option<T>,
+        save: impl Fn(&ResultStore, &StoreKey, &T),
+        run: impl FnOnce() -> T,
+    ) -> T {
+        let Some(store) = &self.store else {
+            return run();
+        };
+
+        let config = format!(
+            "{} {} {}-{} {} {:016x} {}",
+            self.physics as u8,
+            self.hold,
+            self.build_mode.min_left,
+            self.build_mode.max_left,
+            self.full_cover,
+            self.boards_checksum,
+            encode_board(&self.start),
+        );
+        let key = ResultStore::key(&[&[config.as_str()], query].concat());
+        if let Some(res) = load(store, &key) {
+            return res;
+        }
+        let res = run();
+        if !self.task.is_cancelled() {
+            save(store, &key, &res);
         }
-
         res
     }
 
+    pub fn physics(&self) -> Physics {
+        self.physics
+    }
+
+    pub fn compute_bags(
+        &self,
+        bags: &[Bag],
+        setup: &BrokenBoard,
+        save: Option<Shape>,
+    ) -> Vec<BrokenBoard> {
+        self.solve(setup, bags, None, save)
+    }
+
     pub fn compute(
         &self,
         queue: &str,
         setup: &BrokenBoard,
+        start: &QueueStart,
         save: Option<Shape>,
     ) -> Vec<BrokenBoard> {
-        queue
+        start
+            .apply(queue)
             .lines()
             .map(|l| l.trim())
             .filter(|l| !l.is_empty())
-            .flat_map(|pattern| {
-                solver::compute(
-                    &self.legal_boards,
-                    setup,
-                    &pattern_bags(pattern),
-                    self.hold,
-                    self.physics,
-                    save,
-                )
-            })
+            .flat_map(|pattern| self.solve(setup, &pattern_bags(pattern), start.hold, save))
             .unique()
             .collect()
     }
 
+    /// Setups with the best score over the tiers of `saves`, keeping the first tier in at least
+    /// `min_saves` queues.
+    /// `start` is the hold and bag state before the build queue. `build_save` only finds setups
+    /// holding that piece.
     pub fn find(
         &self,
         build_queue: &str,
+        start: &QueueStart,
         build_save: Option<Shape>,
         solve_queue: &str,
-        saves: &str,
+        saves: &SavePolicy,
         min_saves: usize,
-    ) -> (Vec<BrokenBoard>, usize) {
-        let p_count = 11
-            - (self.start.board.0.count_ones() / 4) as usize
-            - build_queue.replace(",", "").len();
-        let solve_queues: Vec<Vec<Bag>> = expand_pattern(solve_queue)
-            .into_iter()
-            .map(|q| {
-                build_save
-                    .map(|s| Bag::new(&[s], 1))
-                    .into_iter()
-                    .chain(q.chars().take(p_count).map(|c| {
-                        let shape = parse_shape(c).expect("Invalid solve pattern");
-                        Bag::new(&[shape], 1)
-                    }))
-                    .collect()
-            })
-            .collect();
+    ) -> (Vec<Setup>, SaveScore) {
+        self.find_with(
+            build_queue,
+            start,
+            build_save,
+            solve_queue,
+            saves,
+            min_saves,
+            |_| {},
+        )
+    }
 
-        let parsed_saves: Vec<Shape> = saves.chars().unique().filter_map(parse_shape).collect();
+    /// Like [`QBFinder::find`], but calls `on_event` with each setup as soon as its save count
+    /// is known. Events are never reported concurrently.
+    #[allow(clippy::too_many_arguments)]
+    pub fn find_with(
+        &self,
+        build_queue: &str,
+        start: &QueueStart,
+        build_save: Option<Shape>,
+        solve_queue: &str,
+        saves: &SavePolicy,
+        min_saves: usize,
+        on_event: impl Fn(FindEvent) + Sync,
+    ) -> (Vec<Setup>, SaveScore) {
+        let mut fresh = false;
+        let res = self.stored(
+            &[
+                "find",
+                build_queue,
+                &encode_start(start),
+                encode_shape(build_save),
+                solve_queue,
+                &saves.to_string(),
+                &min_saves.to_string(),
+            ],
+            ResultStore::load_find,
+            ResultStore::save_find,
+            || {
+                fresh = true;
+                self.find_unstored(
+                    build_queue,
+                    start,
+                    build_save,
+                    solve_queue,
+                    saves,
+                    SaveScore::at_least(min_saves),
+                    &on_event,
+                )
+            },
+        );
+        if !fresh {
+            let (setups, saves) = &res;
+            for setup in setups {
+                on_event(FindEvent::Setup { setup, saves });
+            }
+        }
+        res
+    }
 
-        let mut setups =
-            if self.skip_4p && build_queue.replace(",", "").len() == 4 && build_save.is_none() {
-                vec![]
-            } else {
-                self.compute(build_queue, &self.start, build_save)
+    /// Every setup chosen by `ranking` with its save score, best first, rather than only the
+    /// ones tied for the best.
+    pub fn find_ranked(
+        &self,
+        build_queue: &str,
+        start: &QueueStart,
+        build_save: Option<Shape>,
+        solve_queue: &str,
+        saves: &SavePolicy,
+        ranking: Ranking,
+    ) -> Vec<(Setup, SaveScore)> {
+        let threshold = SaveScore::at_least(match ranking {
+            Ranking::AtLeast(n) => n.max(1),
+            Ranking::Top(_) => 1,
+        });
+        let mut ranked: Vec<(Setup, SaveScore)> = Vec::new();
+
+        for left in self.build_mode.lefts(build_save) {
+            if !ranked.is_empty() || self.task.is_cancelled() {
+                break;
+            }
+            let (setups, solve_queues) =
+                self.find_candidates(build_queue, start, build_save, solve_queue, left);
+
+            // scores of the best setups so far, lowest first, to skip setups that can't make it
+            let top: Mutex<BinaryHeap<Reverse<SaveScore>>> = Mutex::default();
+            let bound = || match ranking {
+                Ranking::AtLeast(_) => threshold.clone(),
+                Ranking::Top(k) => {
+                    let top = top.lock().unwrap();
+                    match top.peek() {
+                        Some(Reverse(lowest)) if top.len() >= k => {
+                            lowest.clone().max(threshold.clone())
+                        }
+                        _ => threshold.clone(),
+                    }
+                }
             };
+            let evaluated = AtomicUsize::new(0);
+            let total = setups.len();
+            self.task.report(0, total);
 
-        if self.full_cover {
-            let build_queues: Vec<_> = expand_pattern(build_queue)
-                .into_iter()
-                .map(|q| q.chars().filter_map(parse_shape).collect())
-                .collect();
-            setups = setups
+            ranked = setups
                 .into_par_iter()
-                .filter(|setup| {
-                    let scover: Vec<_> = setup
-                        .supporting_queues(self.physics)
-                        .iter()
-                        .flat_map(|&q| match build_save {
-                            Some(s) => q.push_last(s).unhold(),
-                            None => q.unhold(),
-                        })
-                        .collect();
-                    build_queues.iter().all(|q| scover.contains(q))
+                .filter_map(|setup| {
+                    if self.task.is_cancelled() {
+                        return None;
+                    }
+                    let score = self.save_score(
+                        &BrokenBoard::from_garbage(setup.board.to_broken_bitboard().0),
+                        &solve_queues[&setup.left()],
+                        saves,
+                        &bound(),
+                    );
+                    self.task
+                        .report(evaluated.fetch_add(1, Ordering::Relaxed) + 1, total);
+                    let score = score?;
+                    if let Ranking::Top(k) = ranking {
+                        let mut top = top.lock().unwrap();
+                        top.push(Reverse(score.clone()));
+                        if top.len() > k {
+                            top.pop();
+                        }
+                    }
+                    Some((setup, score))
                 })
                 .collect();
         }
 
-        let primary_save_count = AtomicUsize::new(min_saves);
+        ranked.sort_by(|(a, a_saves), (b, b_saves)| b_saves.cmp(a_saves).then_with(|| a.cmp(b)));
+        if let Ranking::Top(k) = ranking {
+            ranked.truncate(k);
+        }
+        ranked
+    }
 
-        let setup_saves: Vec<(usize, BrokenBoard)> = setups
-            .into_par_iter()
-            .map(|setup| {
-                let cur_best = primary_save_count.load(std::sync::atomic::Ordering::Relaxed);
-                let save_count = self.good_save_count(
-                    &BrokenBoard::from_garbage(setup.to_broken_bitboard().0),
-                    &solve_queues,
-                    &parsed_saves,
-                    cur_best,
-                );
-                if save_count > cur_best {
-                    primary_save_count.fetch_max(save_count, Ordering::Relaxed);
-                }
-                (save_count, setup)
+    /// Setups the build can reach leaving `left` of its pieces, with the solve queues to score
+    /// them by for each pattern of pieces a setup leaves.
+    #[allow(clippy::type_complexity)]
+    fn find_candidates(
+        &self,
+        build_queue: &str,
+        start: &QueueStart,
+        build_save: Option<Shape>,
+        solve_queue: &str,
+        left: usize,
+    ) -> (Vec<Setup>, FxHashMap<String, Vec<Vec<Bag>>>) {
+        let build_pattern = start.apply(build_queue);
+        let build_len = build_pattern.replace(",", "").len() + start.hold.iter().count();
+        let p_count = self.pc_piece_count().saturating_sub(build_len);
+        let solve_pattern: Vec<String> = expand_pattern(solve_queue);
+
+        let mut setups = self.build_setups(&build_pattern, start.hold, left, build_save);
+
+        if self.full_cover {
+            let build_queues = build_queues(start, &build_pattern);
+            setups = setups
+                .into_par_iter()
+                .filter(|setup| self.cover(setup, &build_queues).missing.is_empty())
+                .collect();
+        }
+
+        let solve_queues = setups
+            .iter()
+            .map(|setup| setup.left())
+            .unique()
+            .map(|left| {
+                let setup = setups.iter().find(|s| s.left() == left).unwrap();
+                let queues = setup_solve_queues(setup, &solve_pattern, p_count);
+                (left, queues)
             })
             .collect();
 
-        let mut max_save = primary_save_count.load(Ordering::SeqCst);
+        (setups, solve_queues)
+    }
 
-        setups = setup_saves
-            .into_iter()
-            .filter(|(s, _)| *s == max_save)
-            .map(|(_, s)| s)
-            .collect();
+    #[allow(clippy::too_many_arguments)]
+    fn find_unstored(
+        &self,
+        build_queue: &str,
+        start: &QueueStart,
+        build_save: Option<Shape>,
+        solve_queue: &str,
+        saves: &SavePolicy,
+        min_score: SaveScore,
+        on_event: &(impl Fn(FindEvent) + Sync),
+    ) -> (Vec<Setup>, SaveScore) {
+        let mut max_score = min_score;
+
+        for left in self.build_mode.lefts(build_save) {
+            if self.task.is_cancelled() {
+                break;
+            }
+            let (setups, solve_queues) =
+                self.find_candidates(build_queue, start, build_save, solve_queue, left);
 
-        if setups.is_empty() && build_queue.replace(",", "").len() == 4 && build_save.is_none() {
-            for p in build_queue.replace(",", "").chars().unique() {
-                let (subsetup, sub_save) =
-                    self.find(build_queue, parse_shape(p), solve_queue, saves, max_save);
-                if sub_save > max_save {
-                    setups.clear();
-                    max_save = sub_save
-                }
-                if sub_save == max_save {
-                    setups.extend(subsetup);
-                }
+            // locked while reporting so events arrive in the order the best score rose
+            let best = Mutex::new(max_score.clone());
+            let evaluated = AtomicUsize::new(0);
+            let total = setups.len();
+            self.task.report(0, total);
+
+            let scored: Vec<(SaveScore, Setup)> = setups
+                .into_par_iter()
+                .filter_map(|setup| {
+                    if self.task.is_cancelled() {
+                        return None;
+                    }
+                    let bound = best.lock().unwrap().clone();
+                    let score = self.save_score(
+                        &BrokenBoard::from_garbage(setup.board.to_broken_bitboard().0),
+                        &solve_queues[&setup.left()],
+                        saves,
+                        &bound,
+                    );
+                    self.task
+                        .report(evaluated.fetch_add(1, Ordering::Relaxed) + 1, total);
+                    let score = score?;
+                    if !self.task.is_cancelled() {
+                        let mut best = best.lock().unwrap();
+                        if score > *best {
+                            *best = score.clone();
+                            on_event(FindEvent::Superseded { saves: &best });
+                        }
+                        if score == *best {
+                            on_event(FindEvent::Setup {
+                                setup: &setup,
+                                saves: &score,
+                            });
+                        }
+                    }
+                    Some((score, setup))
+                })
+                .collect();
+
+            max_score = best.into_inner().unwrap();
+            let setups: Vec<Setup> = scored
+                .into_iter()
+                .filter(|(score, _)| *score == max_score)
+                .map(|(_, s)| s)
+                .collect();
+            // leaving more pieces is only worth it when leaving fewer finds nothing
+            if !setups.is_empty() {
+                return (setups, max_score);
             }
         }
-        (setups, max_save)
+        (vec![], max_score)
     }
 
+    /// Fewest solutions covering every queue of `universe`, each kept by a tier of `saves`.
     pub fn min_count(
         &self,
         setup: &BrokenBoard,
         pattern: &str,
         universe: &FxHashSet<String>,
-        saves: &str,
+        saves: &SavePolicy,
     ) -> usize {
-        let mut save_groups: Vec<Vec<Shape>> = saves
-            .split(",")
-            .map(|g| g.chars().unique().flat_map(parse_shape).collect())
-            .filter(|g: &Vec<_>| !g.is_empty())
+        let Some((setup_cover_map, _)) = self.solve_covers(setup, pattern, universe, saves) else {
+            return Default::default();
+        };
+        let covering_queues: Vec<Vec<String>> = setup_cover_map
+            .values()
+            .map(|c| c.iter().cloned().collect())
             .collect();
+        min_cover_size(universe, &covering_queues)
+    }
 
-        if save_groups.is_empty() {
-            use Shape::*;
-            save_groups.push(vec![]);
-            save_groups.push(vec![I, J, L, O, S, T, Z]);
-        }
+    pub fn all_min_sets(
+        &self,
+        setup: &BrokenBoard,
+        pattern: &str,
+        universe: &FxHashSet<String>,
+        saves: &SavePolicy,
+    ) -> SetupMinimals {
+        self.stored(
+            &[
+                "all_min_sets",
+                &encode_board(setup),
+                pattern,
+                &universe.iter().sorted().join(","),
+                &saves.to_string(),
+            ],
+            ResultStore::load_min_sets,
+            ResultStore::save_min_sets,
+            || self.all_min_sets_unstored(setup, pattern, universe, saves),
+        )
+    }
+
+    fn all_min_sets_unstored(
+        &self,
+        setup: &BrokenBoard,
+        pattern: &str,
+        universe: &FxHashSet<String>,
+        saves: &SavePolicy,
+    ) -> SetupMinimals {
+        let Some((setup_cover_map, equivalent_map)) =
+            self.solve_covers(setup, pattern, universe, saves)
+        else {
+            return Default::default();
+        };
+        let all_solves: Vec<BrokenBoard> = setup_cover_map.keys().cloned().collect();
+        let covering_queues: Vec<Vec<String>> = all_solves
+            .iter()
+            .map(|s| setup_cover_map[s].iter().cloned().collect())
+            .collect();
+        let solve_index_map: FxHashMap<_, usize> = all_solves
+            .iter()
+            .enumerate()
+            .map(|(i, solve)| (solve, i))
+            .collect();
+        let all_sets = all_min_cover_sets(universe, &covering_queues, &self.task);
+        let used_solves: FxHashSet<usize> = all_sets.iter().flatten().cloned().collect();
+        let mut equivalent_map: FxHashMap<usize, Vec<usize>> = equivalent_map
+            .into_iter()
+            .map(|(key, vector)| {
+                let new_key = solve_index_map[&key];
+                let new_vector = vector.into_iter().map(|b| solve_index_map[&b]).collect();
+                (new_key, new_vector)
+            })
+            .collect();
+        equivalent_map.retain(|k, _| used_solves.contains(k));
+        (all_solves, all_sets, equivalent_map)
+    }
 
+    /// The queues of `universe` each solution of `pattern` covers, counting each queue for the
+    /// first tier of `saves` that covers it, and the solutions covering the same queues as an
+    /// earlier one. `None` if cancelled.
+    #[allow(clippy::type_complexity)]
+    fn solve_covers(
+        &self,
+        setup: &BrokenBoard,
+        pattern: &str,
+        universe: &FxHashSet<String>,
+        saves: &SavePolicy,
+    ) -> Option<(
+        FxHashMap<BrokenBoard, FxHashSet<String>>,
+        FxHashMap<BrokenBoard, Vec<BrokenBoard>>,
+    )> {
         let mut equivalent_map: FxHashMap<BrokenBoard, Vec<BrokenBoard>> = FxHashMap::default();
         let mut setup_cover_map: FxHashMap<BrokenBoard, FxHashSet<String>> = FxHashMap::default();
         let mut already_covered = FxHashSet::default();
+        let total = saves.tiers.iter().map(|tier| tier.saves().len()).sum();
+        let mut done = 0;
+        self.task.report(done, total);
 
-        for group in save_groups {
+        for tier in &saves.tiers {
             let mut new_cover = FxHashSet::default();
-            let group_saves = if group.is_empty() {
-                vec![None]
-            } else {
-                group.into_iter().map(Some).collect()
-            };
-            for save in group_saves {
+            for save in tier.saves() {
+                if self.task.is_cancelled() {
+                    return None;
+                }
                 let solves = self.compute(
                     pattern,
                     &BrokenBoard::from_garbage(setup.to_broken_bitboard().0),
+                    &QueueStart::default(),
                     save,
                 );
 
@@ -377,112 +739,132 @@ impl QBFinder {
                         .extend(cover.clone());
                     new_cover.extend(cover);
                 }
+                done += 1;
+                self.task.report(done, total);
             }
             already_covered.extend(new_cover);
         }
-        let covering_queues: Vec<Vec<String>> = setup_cover_map
-            .values()
-            .map(|c| c.iter().cloned().collect())
-            .collect();
-        min_cover_size(universe, &covering_queues)
+        Some((setup_cover_map, equivalent_map))
     }
 
-    pub fn all_min_sets(
+    /// Orders of `queue` from which `setup` meets `tier` whatever piece comes last.
+    fn bestsaves_queues(&self, setup: &BrokenBoard, queue: &str, tier: &SaveTier) -> Vec<String> {
+        let setup = BrokenBoard::from_garbage(setup.to_broken_bitboard().0);
+        tier.saves()
+            .into_iter()
+            .flat_map(|save| self.bestsaves_keeping(&setup, queue, save))
+            .unique()
+            .collect()
+    }
+
+    fn bestsaves_keeping(
         &self,
         setup: &BrokenBoard,
-        pattern: &str,
-        universe: &FxHashSet<String>,
-        saves: &str,
-    ) -> SetupMinimals {
-        let mut save_groups: Vec<Vec<Shape>> = saves
-            .split(",")
-            .map(|g| g.chars().unique().flat_map(parse_shape).collect())
-            .filter(|g: &Vec<_>| !g.is_empty())
-            .collect();
-
-        if save_groups.is_empty() {
-            use Shape::*;
-            save_groups.push(vec![]);
-            save_groups.push(vec![I, J, L, O, S, T, Z]);
-        }
-
-        let mut equivalent_map: FxHashMap<BrokenBoard, Vec<BrokenBoard>> = FxHashMap::default();
-        let mut setup_cover_map: FxHashMap<BrokenBoard, FxHashSet<String>> = FxHashMap::default();
-        let mut already_covered = FxHashSet::default();
-
-        for group in save_groups {
-            let mut new_cover = FxHashSet::default();
-            let group_saves = if group.is_empty() {
-                vec![None]
-            } else {
-                group.into_iter().map(Some).collect()
+        queue: &str,
+        save: Option<Shape>,
+    ) -> FxHashSet<String> {
+        let pieces = Shape::ALL.into_iter().filter(|&s| Some(s) != save);
+        let mut res = FxHashSet::default();
+        for (i, piece) in pieces.enumerate() {
+            // the kept piece has to be dealt before it can be kept
+            let q = match save {
+                Some(save) => format!("{},{},{}", queue, save.name(), piece.name()),
+                None => format!("{},{}", queue, piece.name()),
             };
-            for save in group_saves {
-                let solves = self.compute(
-                    pattern,
-                    &BrokenBoard::from_garbage(setup.to_broken_bitboard().0),
-                    save,
-                );
-
-                let mut prev_solves: Vec<BrokenBoard> = vec![];
-
-                for solve in solves {
-                    let mut cover: Vec<String> = solve
+            let solves = self.compute(&q, setup, &QueueStart::default(), save);
+            if solves.is_empty() {
+                return FxHashSet::default();
+            }
+            let cover: FxHashSet<String> = solves
+                .iter()
+                .flat_map(|solve| {
+                    solve
                         .supporting_queues(Physics::Jstris)
                         .iter()
-                        .flat_map(|&q| match save {
-                            Some(s) => q.push_last(s).unhold(),
-                            None => q.unhold(),
+                        .filter_map(|q| {
+                            let mut shapes: Vec<Shape> = q.collect();
+                            if shapes.last() == Some(&piece) {
+                                shapes.pop();
+                                Some(shapes.into_iter().collect::<Queue>())
+                            } else {
+                                None
+                            }
                         })
+                        .flat_map(|q| q.unhold())
                         .map(|q| q.to_string())
-                        .filter(|q| universe.contains(q) && !already_covered.contains(q))
-                        .collect();
+                        .collect::<Vec<_>>()
+                })
+                .collect();
+            if i == 0 {
+                res = cover;
+            } else {
+                res.retain(|q| cover.contains(q));
+                if res.is_empty() {
+                    break;
+                }
+            }
+        }
+        res
+    }
 
-                    let mut cover_set: FxHashSet<String> = cover.iter().cloned().collect();
+    /// Maps each 3 piece queue following the two piece `fifth` (plus the rest of the bag) to the
+    /// setups that meet the first tier of `saves` in the next PC.
+    pub fn bestsaves(
+        &self,
+        fifth: &str,
+        saves: &SavePolicy,
+    ) -> FxHashMap<String, Vec<BrokenBoard>> {
+        let Some(tier) = saves.tiers.first() else {
+            return FxHashMap::default();
+        };
+        if fifth.len() != 2 {
+            return FxHashMap::default();
+        }
+        let pieces = "TIJLOSZ";
 
-                    for psolve in &prev_solves {
-                        if cover_set == setup_cover_map[psolve] {
-                            cover.clear();
-                            cover_set.clear();
-                            equivalent_map
-                                .entry(psolve.clone())
-                                .or_default()
-                                .push(solve.clone());
-                            break;
+        let perms: Vec<_> = pieces.chars().permutations(3).collect();
+
+        perms
+            .into_par_iter()
+            .fold(
+                FxHashMap::default,
+                |mut res: FxHashMap<String, Vec<BrokenBoard>>, p3| {
+                    let p3_str: String = p3.iter().collect();
+                    let q = format!("{}{}", fifth, p3_str).chars().join(",");
+
+                    for save in fifth.chars().chain(p3.iter().copied()).unique() {
+                        let setups = self.compute(
+                            &q,
+                            &BrokenBoard::from_garbage(0),
+                            &QueueStart::default(),
+                            parse_shape(save),
+                        );
+                        let remaining: String =
+                            pieces.chars().filter(|c| !p3.contains(c)).collect();
+                        let qqq = format!("{},{}", save, remaining);
+
+                        for setup in setups {
+                            let queues = self.bestsaves_queues(&setup, &qqq, tier);
+
+                            for queue in queues {
+                                if !queue.starts_with(save) {
+                                    continue;
+                                }
+
+                                let fullq = format!("{}{}", p3_str, queue.replacen(save, "", 1));
+
+                                res.entry(fullq).or_default().push(setup.clone());
+                            }
                         }
                     }
-
-                    prev_solves.push(solve.clone());
-                    setup_cover_map
-                        .entry(solve)
-                        .or_default()
-                        .extend(cover.clone());
-                    new_cover.extend(cover);
+                    res
+                },
+            )
+            .reduce(FxHashMap::default, |mut a, b| {
+                for (queue, setups) in b {
+                    a.entry(queue).or_default().extend(setups);
                 }
-            }
-            already_covered.extend(new_cover);
-        }
-        let all_solves: Vec<BrokenBoard> = setup_cover_map.keys().cloned().collect();
-        let covering_queues: Vec<Vec<String>> = all_solves
-            .iter()
-            .map(|s| setup_cover_map[s].iter().cloned().collect())
-            .collect();
-        let solve_index_map: FxHashMap<_, usize> = all_solves
-            .iter()
-            .enumerate()
-            .map(|(i, solve)| (solve, i))
-            .collect();
-        let all_sets = all_min_cover_sets(universe, &covering_queues);
-        let used_solves: FxHashSet<usize> = all_sets.iter().flatten().cloned().collect();
-        let mut equivalent_map: FxHashMap<usize, Vec<usize>> = equivalent_map
-            .into_iter()
-            .map(|(key, vector)| {
-                let new_key = solve_index_map[&key];
-                let new_vector = vector.into_iter().map(|b| solve_index_map[&b]).collect();
-                (new_key, new_vector)
+                a
             })
-            .collect();
-        equivalent_map.retain(|k, _| used_solves.contains(k));
-        (all_solves, all_sets, equivalent_map)
     }
 }
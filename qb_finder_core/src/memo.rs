@@ -0,0 +1,121 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+};
+
+use rustc_hash::FxHashMap;
+use srs_4l::{brokenboard::BrokenBoard, gameplay::Shape};
+
+use crate::queue::Bag;
+
+/// Everything besides physics that [`crate::solver::compute`] depends on.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct ComputeKey {
+    pub start: BrokenBoard,
+    pub bags: Vec<Bag>,
+    pub hold: Option<Shape>,
+    pub can_hold: bool,
+    pub save: Option<Shape>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: usize,
+    pub misses: usize,
+    pub entries: usize,
+    pub capacity: usize,
+}
+
+struct Entries {
+    capacity: usize,
+    map: FxHashMap<ComputeKey, Vec<BrokenBoard>>,
+    order: VecDeque<ComputeKey>,
+}
+
+impl Entries {
+    fn evict(&mut self) {
+        while self.map.len() > self.capacity
+            && let Some(oldest) = self.order.pop_front()
+        {
+            self.map.remove(&oldest);
+        }
+    }
+}
+
+/// Solver results shared between threads, evicting the oldest entry first.
+pub struct ComputeCache {
+    entries: Mutex<Entries>,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+impl ComputeCache {
+    pub fn new(capacity: usize) -> ComputeCache {
+        ComputeCache {
+            entries: Mutex::new(Entries {
+                capacity,
+                map: FxHashMap::default(),
+                order: VecDeque::new(),
+            }),
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns the cached result for `key`, or runs `compute` and caches what it returns.
+    pub fn get_or_insert(
+        &self,
+        key: ComputeKey,
+        compute: impl FnOnce() -> Vec<BrokenBoard>,
+    ) -> Vec<BrokenBoard> {
+        {
+            let entries = self.entries.lock().unwrap();
+            if entries.capacity == 0 {
+                drop(entries);
+                return compute();
+            }
+            if let Some(solves) = entries.map.get(&key) {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return solves.clone();
+            }
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+
+        // computed unlocked so other threads keep going, at the cost of sometimes solving twice
+        let solves = compute();
+
+        let mut entries = self.entries.lock().unwrap();
+        if entries.map.insert(key.clone(), solves.clone()).is_none() {
+            entries.order.push_back(key);
+        }
+        entries.evict();
+        solves
+    }
+
+    pub fn set_capacity(&self, capacity: usize) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.capacity = capacity;
+        entries.evict();
+    }
+
+    pub fn clear(&self) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.map.clear();
+        entries.order.clear();
+        self.hits.store(0, Ordering::Relaxed);
+        self.misses.store(0, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let entries = self.entries.lock().unwrap();
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: entries.map.len(),
+            capacity: entries.capacity,
+        }
+    }
+}
@@ -1,7 +1,28 @@
 use good_lp::{Expression, Solution, SolverModel, microlp, variables};
+use std::hash::Hash;
+
 use rustc_hash::FxHashSet;
 
-pub fn min_cover_size<T: PartialEq>(universe: &FxHashSet<T>, sets: &[Vec<T>]) -> usize {
+use crate::task::Task;
+
+/// A set of elements a cover can pick.
+pub trait CoverSet<T> {
+    fn has(&self, element: &T) -> bool;
+}
+
+impl<T: PartialEq> CoverSet<T> for Vec<T> {
+    fn has(&self, element: &T) -> bool {
+        self.contains(element)
+    }
+}
+
+impl<T: Eq + Hash> CoverSet<T> for FxHashSet<T> {
+    fn has(&self, element: &T) -> bool {
+        self.contains(element)
+    }
+}
+
+pub fn min_cover_size<T, S: CoverSet<T>>(universe: &FxHashSet<T>, sets: &[S]) -> usize {
     let mut vars = variables!();
 
     let set_vars: Vec<_> = sets
@@ -16,7 +37,7 @@ pub fn min_cover_size<T: PartialEq>(universe: &FxHashSet<T>, sets: &[Vec<T>]) ->
         let mut constraint_expr: Expression = 0.into();
 
         for (i, set) in sets.iter().enumerate() {
-            if set.contains(element) {
+            if set.has(element) {
                 constraint_expr += set_vars[i];
             }
         }
@@ -34,9 +55,10 @@ pub fn min_cover_size<T: PartialEq>(universe: &FxHashSet<T>, sets: &[Vec<T>]) ->
     }
 }
 
-pub fn all_min_cover_sets<T: PartialEq>(
+pub fn all_min_cover_sets<T, S: CoverSet<T>>(
     universe: &FxHashSet<T>,
-    sets: &[Vec<T>],
+    sets: &[S],
+    task: &Task,
 ) -> Vec<Vec<usize>> {
     let min_size = min_cover_size(universe, sets);
     let mut res = Vec::new();
@@ -46,7 +68,7 @@ pub fn all_min_cover_sets<T: PartialEq>(
 
     let mut found_sets: Vec<Vec<usize>> = Vec::new();
 
-    loop {
+    while !task.is_cancelled() {
         let mut vars = variables!();
         let set_vars: Vec<_> = sets
             .iter()
@@ -61,7 +83,7 @@ pub fn all_min_cover_sets<T: PartialEq>(
         for element in universe {
             let mut constraint_expr = Expression::from(0.0);
             for (i, set) in sets.iter().enumerate() {
-                if set.contains(element) {
+                if set.has(element) {
                     constraint_expr += set_vars[i];
                 }
             }
@@ -97,3 +119,43 @@ pub fn all_min_cover_sets<T: PartialEq>(
 
     res
 }
+
+/// A cover of `universe` by exactly `size` of `sets` with the lowest total of their `weights`,
+/// as indices into `sets`, or `None` if there is none.
+pub fn min_weight_cover<T, S: CoverSet<T>>(
+    universe: &FxHashSet<T>,
+    sets: &[S],
+    weights: &[f64],
+    size: usize,
+) -> Option<Vec<usize>> {
+    let mut vars = variables!();
+    let set_vars: Vec<_> = sets
+        .iter()
+        .map(|_| vars.add(good_lp::variable().binary()))
+        .collect();
+
+    let count: Expression = set_vars.iter().sum();
+    let objective: Expression = set_vars.iter().zip(weights).map(|(&v, &w)| w * v).sum();
+    let mut problem = vars.minimise(objective).using(microlp);
+    problem.add_constraint(count.eq(size as f64));
+
+    for element in universe {
+        let mut constraint_expr = Expression::from(0.0);
+        for (i, set) in sets.iter().enumerate() {
+            if set.has(element) {
+                constraint_expr += set_vars[i];
+            }
+        }
+        problem.add_constraint(constraint_expr.geq(1.0));
+    }
+
+    let solution = problem.solve().ok()?;
+    Some(
+        set_vars
+            .iter()
+            .enumerate()
+            .filter(|&(_, &var)| solution.value(var) > 0.5)
+            .map(|(i, _)| i)
+            .collect(),
+    )
+}
@@ -0,0 +1,191 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use rayon::prelude::{IntoParallelIterator, ParallelIterator};
+use rustc_hash::FxHashSet;
+use srs_4l::brokenboard::BrokenBoard;
+
+use crate::{
+    QBFinder,
+    build::Setup,
+    build_queues, expand_pattern,
+    queue::QueueStart,
+    saves::{SavePolicy, SaveScore},
+};
+
+/// How a setup does on each objective [`QBFinder::find_pareto`] trades off.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SetupScore {
+    /// Solve queues meeting the first save tier.
+    pub primary_saves: usize,
+    /// Solve queues meeting only a later save tier.
+    pub secondary_saves: usize,
+    /// Fewest solutions covering every solve queue, or `None` for setups under 3 pieces, where
+    /// it is not computed.
+    pub min_count: Option<usize>,
+    /// Build queues that can build the setup.
+    pub build_cover: usize,
+}
+
+impl SetupScore {
+    /// Whether `self` is at least as good as `other` on every objective and better on one. The
+    /// min count only counts when both have one.
+    pub fn dominates(&self, other: &SetupScore) -> bool {
+        let min_counts = self.min_count.zip(other.min_count);
+        let at_least = self.primary_saves >= other.primary_saves
+            && self.secondary_saves >= other.secondary_saves
+            && min_counts.is_none_or(|(a, b)| a <= b)
+            && self.build_cover >= other.build_cover;
+        let better = self.primary_saves > other.primary_saves
+            || self.secondary_saves > other.secondary_saves
+            || min_counts.is_some_and(|(a, b)| a < b)
+            || self.build_cover > other.build_cover;
+        at_least && better
+    }
+}
+
+/// The entries no other entry dominates, in their original order.
+pub fn pareto_front<T>(scored: Vec<(T, SetupScore)>) -> Vec<(T, SetupScore)> {
+    let scores: Vec<SetupScore> = scored.iter().map(|(_, score)| *score).collect();
+    scored
+        .into_iter()
+        .filter(|(_, score)| !scores.iter().any(|other| other.dominates(score)))
+        .collect()
+}
+
+impl QBFinder {
+    /// Every setup the build can reach that no other setup beats on all of save count,
+    /// secondary save count, min count and build queue cover, best primary save count first.
+    pub fn find_pareto(
+        &self,
+        build_queue: &str,
+        start: &QueueStart,
+        solve_queue: &str,
+        saves: &SavePolicy,
+    ) -> Vec<(Setup, SetupScore)> {
+        let mut scored = Vec::new();
+        for left in self.build_mode.lefts(None) {
+            if !scored.is_empty() || self.task.is_cancelled() {
+                break;
+            }
+            scored = self.score_setups(build_queue, start, solve_queue, saves, left);
+        }
+
+        let mut front = pareto_front(scored);
+        front.sort_by(|(a, a_score), (b, b_score)| {
+            (b_score.primary_saves, b_score.secondary_saves)
+                .cmp(&(a_score.primary_saves, a_score.secondary_saves))
+                .then(
+                    a_score
+                        .min_count
+                        .unwrap_or(usize::MAX)
+                        .cmp(&b_score.min_count.unwrap_or(usize::MAX)),
+                )
+                .then(b_score.build_cover.cmp(&a_score.build_cover))
+                .then_with(|| a.cmp(b))
+        });
+        front
+    }
+
+    /// Scores every setup the build can reach leaving `left` of its pieces that meets a save
+    /// tier in some queue.
+    fn score_setups(
+        &self,
+        build_queue: &str,
+        start: &QueueStart,
+        solve_queue: &str,
+        saves: &SavePolicy,
+        left: usize,
+    ) -> Vec<(Setup, SetupScore)> {
+        let (setups, solve_queues) =
+            self.find_candidates(build_queue, start, None, solve_queue, left);
+        let build_queues = build_queues(start, &start.apply(build_queue));
+
+        // min counts report progress of their own, which would drown out the setups'
+        let mut quiet = self.clone();
+        quiet.task = self.task.quiet();
+
+        let evaluated = AtomicUsize::new(0);
+        let total = setups.len();
+        self.task.report(0, total);
+
+        setups
+            .into_par_iter()
+            .filter_map(|setup| {
+                if self.task.is_cancelled() {
+                    return None;
+                }
+                let saves_score = self.save_score(
+                    &BrokenBoard::from_garbage(setup.board.to_broken_bitboard().0),
+                    &solve_queues[&setup.left()],
+                    saves,
+                    &SaveScore::default(),
+                );
+                let saves_score = saves_score.filter(|s| s.total() > 0);
+                let score = saves_score.map(|saves_score| {
+                    // setups leaving build pieces keep them in front of every solve queue
+                    let pattern = setup.solve_pattern(solve_queue);
+                    let universe: FxHashSet<String> =
+                        expand_pattern(&pattern).into_iter().collect();
+                    SetupScore {
+                        primary_saves: saves_score.primary(),
+                        secondary_saves: saves_score.total() - saves_score.primary(),
+                        min_count: (setup.board.pieces.len() >= 3)
+                            .then(|| quiet.min_count(&setup.board, &pattern, &universe, saves)),
+                        build_cover: self.cover(&setup, &build_queues).with_hold,
+                    }
+                });
+                self.task
+                    .report(evaluated.fetch_add(1, Ordering::Relaxed) + 1, total);
+                Some((setup, score?))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn score(saves: usize, secondary: usize, min_count: Option<usize>, cover: usize) -> SetupScore {
+        SetupScore {
+            primary_saves: saves,
+            secondary_saves: secondary,
+            min_count,
+            build_cover: cover,
+        }
+    }
+
+    #[test]
+    fn dominance() {
+        let a = score(10, 2, Some(3), 5);
+        assert!(a.dominates(&score(9, 2, Some(3), 5)));
+        assert!(a.dominates(&score(10, 2, Some(4), 5)));
+        assert!(!a.dominates(&a));
+        assert!(!a.dominates(&score(9, 3, Some(3), 5)));
+        assert!(!a.dominates(&score(10, 2, Some(2), 5)));
+    }
+
+    #[test]
+    fn missing_min_count_is_left_out() {
+        let a = score(10, 2, Some(3), 5);
+        let small = score(10, 2, None, 5);
+        assert!(!a.dominates(&small));
+        assert!(!small.dominates(&a));
+        assert!(a.dominates(&score(9, 2, None, 5)));
+        assert!(!score(9, 2, None, 5).dominates(&a));
+    }
+
+    #[test]
+    fn front_keeps_undominated_in_order() {
+        let scored = vec![
+            ("a", score(10, 0, Some(4), 5)),
+            ("b", score(8, 0, Some(2), 5)),
+            ("c", score(8, 0, Some(5), 5)),
+            ("d", score(10, 0, Some(4), 5)),
+            ("e", score(7, 0, None, 6)),
+            ("f", score(7, 0, None, 4)),
+        ];
+        let front: Vec<&str> = pareto_front(scored).into_iter().map(|(n, _)| n).collect();
+        assert_eq!(front, vec!["a", "b", "d", "e"]);
+    }
+}
@@ -0,0 +1,186 @@
+use itertools::Itertools;
+use srs_4l::gameplay::Shape;
+
+use crate::{QBFinder, format_group, queue::QueueStart};
+
+/// Pieces of the current bag drawn before the first new piece of each PC of a 7-bag game, first
+/// PC first. The first PC draws 11 pieces and every later one 10 more, keeping one in hold.
+pub const PC_BAG_OFFSETS: [usize; 7] = [0, 4, 0, 3, 6, 2, 5];
+
+/// Pieces of the current bag drawn before the `pc`th PC of the game, from 1 to 7.
+pub fn pc_bag_offset(pc: usize) -> Option<usize> {
+    PC_BAG_OFFSETS.get(pc.checked_sub(1)?).copied()
+}
+
+/// The build and solve patterns of a PC, worked out from the pieces the player can see.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PcQueues {
+    pub start: QueueStart,
+    pub build_queue: String,
+    pub solve_queue: String,
+}
+
+impl QBFinder {
+    /// Pieces a PC from this finder's field takes, including the one left over for hold.
+    pub fn pc_piece_count(&self) -> usize {
+        (40 - self.start.board.0.count_ones() as usize) / 4 + self.hold as usize
+    }
+
+    /// Splits `seen`, the pieces after the hold in the order they come, into a build of its
+    /// first `build_len` pieces and a solve pattern of the rest, followed by what is left of
+    /// their bag and as many full bags as the PC needs. `bag_offset` is the number of pieces of
+    /// the bag drawn before `seen`, as given by [`pc_bag_offset`].
+    ///
+    /// Returns `None` if `seen` can't come from a 7-bag at that offset, if the build is too long
+    /// for the PC, or if the pieces left in the bag are unknown because `seen` stops before its
+    /// end and `start.bag_used` doesn't say which pieces were drawn.
+    pub fn pc_queues(
+        &self,
+        bag_offset: usize,
+        start: &QueueStart,
+        seen: &[Shape],
+        build_len: usize,
+    ) -> Option<PcQueues> {
+        let bag_offset = bag_offset % 7;
+        if build_len == 0 || build_len > seen.len() {
+            return None;
+        }
+        if !start.bag_used.is_empty() && start.bag_used.len() != bag_offset {
+            return None;
+        }
+        let pieces = self.pc_piece_count();
+        let build_pieces = build_len + start.hold.iter().count();
+        if build_pieces > pieces {
+            return None;
+        }
+
+        // the pieces of each bag seen so far, the first after the ones already drawn
+        let mut bags: Vec<Vec<Shape>> = vec![start.bag_used.clone()];
+        for (i, &shape) in seen.iter().enumerate() {
+            let bag = (bag_offset + i) / 7;
+            if bag == bags.len() {
+                bags.push(Vec::new());
+            }
+            if bags[bag].contains(&shape) {
+                return None;
+            }
+            bags[bag].push(shape);
+        }
+
+        let next_bag = (bag_offset + seen.len()) / 7;
+        let left: Vec<Shape> = if next_bag == bags.len() {
+            Shape::ALL.to_vec()
+        } else if next_bag == 0 && start.bag_used.len() < bag_offset {
+            return None;
+        } else {
+            Shape::ALL
+                .into_iter()
+                .filter(|s| !bags[next_bag].contains(s))
+                .collect()
+        };
+
+        let mut solve: Vec<String> = seen[build_len..]
+            .iter()
+            .map(|s| s.name().to_owned())
+            .collect();
+        // the last group only draws as many pieces as the PC still needs
+        let mut needed = (pieces - build_pieces).saturating_sub(solve.len());
+        let mut bag = left;
+        while needed > 0 && !bag.is_empty() {
+            let count = needed.min(bag.len());
+            solve.push(format_group(&bag, count));
+            needed -= count;
+            bag = Shape::ALL.to_vec();
+        }
+
+        Some(PcQueues {
+            start: QueueStart {
+                hold: start.hold,
+                bag_used: Vec::new(),
+            },
+            build_queue: seen[..build_len].iter().map(|s| s.name()).join(","),
+            solve_queue: solve.join(","),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rustc_hash::FxHashSet;
+
+    use super::*;
+    use crate::expand_pattern;
+
+    fn shapes(pieces: &str) -> Vec<Shape> {
+        pieces
+            .chars()
+            .map(|c| crate::parse_shape(c).unwrap())
+            .collect()
+    }
+
+    fn all() -> String {
+        Shape::ALL.iter().map(|s| s.name()).collect()
+    }
+
+    #[test]
+    fn bag_offsets_follow_pieces_drawn() {
+        // 11 pieces before the second PC and 10 more before each one after
+        for (i, &offset) in PC_BAG_OFFSETS.iter().enumerate() {
+            let drawn = i * 10 + (i > 0) as usize;
+            assert_eq!(offset, drawn % 7, "PC {}", i + 1);
+            assert_eq!(pc_bag_offset(i + 1), Some(offset));
+        }
+        assert_eq!(pc_bag_offset(0), None);
+        assert_eq!(pc_bag_offset(8), None);
+    }
+
+    #[test]
+    fn last_group_draws_only_what_is_needed() {
+        let qbf = QBFinder::new(FxHashSet::default());
+        let start = QueueStart::default();
+        let pc = qbf.pc_queues(0, &start, &shapes("IJLOSZ"), 4).unwrap();
+        assert_eq!(pc.build_queue, "I,J,L,O");
+        assert_eq!(pc.solve_queue, format!("S,Z,T,{}p4", all()));
+
+        for (seen, build_len) in [("IJLO", 4), ("IJLOSZT", 4), ("IJLOSZTIJ", 5)] {
+            let pc = qbf.pc_queues(0, &start, &shapes(seen), build_len).unwrap();
+            let solve_len = expand_pattern(&pc.solve_queue)[0].len();
+            assert_eq!(build_len + solve_len, qbf.pc_piece_count(), "{seen}");
+        }
+    }
+
+    #[test]
+    fn full_bag_is_not_counted() {
+        let mut qbf = QBFinder::new(FxHashSet::default());
+        qbf.hold = false;
+        let start = QueueStart {
+            hold: None,
+            bag_used: shapes("IJLO"),
+        };
+        let pc = qbf.pc_queues(4, &start, &shapes("SZT"), 3).unwrap();
+        assert_eq!(pc.build_queue, "S,Z,T");
+        assert_eq!(pc.solve_queue, all());
+    }
+
+    #[test]
+    fn impossible_queues() {
+        let qbf = QBFinder::new(FxHashSet::default());
+        let start = QueueStart::default();
+        // no build, or a build longer than the pieces seen
+        assert_eq!(qbf.pc_queues(0, &start, &shapes("IJLO"), 0), None);
+        assert_eq!(qbf.pc_queues(0, &start, &shapes("IJLO"), 5), None);
+        // a piece twice in one bag
+        assert_eq!(qbf.pc_queues(0, &start, &shapes("IJLI"), 4), None);
+        // more build pieces than the PC takes
+        let seen = shapes("IJLOSZTIJLOS");
+        assert_eq!(qbf.pc_queues(0, &start, &seen, 12), None);
+        // the used pieces don't match the offset
+        let used = QueueStart {
+            hold: None,
+            bag_used: shapes("IJ"),
+        };
+        assert_eq!(qbf.pc_queues(3, &used, &shapes("LOSZ"), 4), None);
+        // the seen pieces stop before the end of a bag whose first pieces are unknown
+        assert_eq!(qbf.pc_queues(3, &start, &shapes("IJ"), 2), None);
+    }
+}
@@ -1,18 +1,22 @@
+use std::{fmt::Debug, hash::Hash};
+
 use smallvec::SmallVec;
 
 use srs_4l::gameplay::Shape;
 
+use crate::{format_group, parse_group};
+
 #[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub struct Bag {
     pub count: u8,
-    pub full: u16,
-    pub masks: [u16; 7],
+    pub full: u64,
+    pub masks: [u64; 7],
 }
 
 impl Bag {
     pub fn new(shapes: &[Shape], count: u8) -> Bag {
         assert!(count as usize <= shapes.len());
-        assert!(shapes.len() <= 13);
+        assert!(shapes.len() <= u64::PIECE_BITS as usize);
 
         let mut bag = Bag {
             count,
@@ -27,8 +31,13 @@ impl Bag {
         bag
     }
 
-    pub fn init_hold(&self) -> SmallVec<[QueueState; 7]> {
-        let initial = QueueState(self.full);
+    /// Number of pieces the bag is drawn from.
+    pub fn size(&self) -> u32 {
+        self.full.count_ones()
+    }
+
+    pub fn init_hold<B: QueueBits>(&self) -> SmallVec<[QueueState<B>; 7]> {
+        let initial = QueueState(B::from_u64(self.full));
 
         Shape::ALL
             .iter()
@@ -40,13 +49,13 @@ impl Bag {
         self.masks[shape as usize] != 0
     }
 
-    pub fn take(
+    pub fn take<B: QueueBits>(
         &self,
-        queues: &[QueueState],
+        queues: &[QueueState<B>],
         shape: Shape,
         is_first: bool,
         can_hold: bool,
-    ) -> SmallVec<[QueueState; 7]> {
+    ) -> SmallVec<[QueueState<B>; 7]> {
         let mut states = SmallVec::new();
 
         for &queue in queues {
@@ -72,12 +81,89 @@ impl Bag {
     }
 }
 
-#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
-pub struct QueueState(pub u16);
+/// Queue progress before the first piece of a pattern.
+#[derive(Clone, Debug, Default, Eq, Hash, PartialEq)]
+pub struct QueueStart {
+    /// Piece already in hold.
+    pub hold: Option<Shape>,
+    /// Pieces already drawn from the bag the pattern starts in.
+    pub bag_used: Vec<Shape>,
+}
+
+impl QueueStart {
+    /// Removes the used pieces from the first group of every line of `pattern`.
+    pub fn apply(&self, pattern: &str) -> String {
+        if self.bag_used.is_empty() {
+            return pattern.to_owned();
+        }
+
+        pattern
+            .lines()
+            .map(|line| {
+                let mut groups: Vec<String> = line.split(",").map(str::to_owned).collect();
+                if let Some((mut shapes, mut count)) = parse_group(&groups[0]) {
+                    for shape in &self.bag_used {
+                        if let Some(i) = shapes.iter().position(|s| s == shape)
+                            && count > 0
+                        {
+                            shapes.remove(i);
+                            count -= 1;
+                        }
+                    }
+                    groups[0] = format_group(&shapes, count);
+                }
+                if groups[0].is_empty() && groups.len() > 1 {
+                    groups.remove(0);
+                }
+                groups.join(",")
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+/// Integer a [`QueueState`] is packed into: the pieces left in the bag take the low
+/// `PIECE_BITS` bits and the held shape the 3 bits above them.
+pub trait QueueBits: Copy + Debug + Default + Eq + Hash + Ord + Send + Sync {
+    const PIECE_BITS: u32;
+
+    fn from_u64(bits: u64) -> Self;
+    fn to_u64(self) -> u64;
+}
+
+macro_rules! queue_bits {
+    ($($t:ty),*) => {$(
+        impl QueueBits for $t {
+            const PIECE_BITS: u32 = <$t>::BITS - 3;
+
+            #[inline(always)]
+            fn from_u64(bits: u64) -> Self {
+                bits as $t
+            }
+
+            #[inline(always)]
+            fn to_u64(self) -> u64 {
+                self as u64
+            }
+        }
+    )*};
+}
+
+queue_bits!(u16, u32, u64);
+
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct QueueState<B: QueueBits = u16>(pub B);
+
+impl<B: QueueBits> QueueState<B> {
+    const PIECE_MASK: u64 = (1 << B::PIECE_BITS) - 1;
+
+    /// State before the first bag with `shape` already in hold.
+    pub fn held(shape: Shape) -> QueueState<B> {
+        QueueState(B::from_u64((shape as u64) << B::PIECE_BITS))
+    }
 
-impl QueueState {
     pub fn hold(self) -> Option<Shape> {
-        match self.0 >> 13 {
+        match self.0.to_u64() >> B::PIECE_BITS {
             0 => Some(Shape::I),
             1 => Some(Shape::J),
             2 => Some(Shape::L),
@@ -89,25 +175,435 @@ impl QueueState {
         }
     }
 
-    pub fn next(self, bag: &Bag) -> QueueState {
-        QueueState(self.0 & 0b1110000000000000 | bag.full)
+    pub fn next(self, bag: &Bag) -> QueueState<B> {
+        QueueState(B::from_u64(self.0.to_u64() & !Self::PIECE_MASK | bag.full))
     }
 
-    pub fn take(self, bag: &Bag, shape: Shape) -> Option<QueueState> {
-        let shape_field = self.0 & bag.masks[shape as usize];
+    pub fn take(self, bag: &Bag, shape: Shape) -> Option<QueueState<B>> {
+        let bits = self.0.to_u64();
+        let shape_field = bits & bag.masks[shape as usize];
 
         if shape_field == 0 {
             return None;
         }
 
         let new_shape_field = shape_field & (shape_field - 1);
-        Some(QueueState(self.0 ^ shape_field ^ new_shape_field))
+        Some(QueueState(B::from_u64(
+            bits ^ shape_field ^ new_shape_field,
+        )))
+    }
+
+    pub fn swap(self, bag: &Bag, shape: Shape) -> Option<QueueState<B>> {
+        let new = self.take(bag, shape)?.0.to_u64();
+        Some(QueueState(B::from_u64(
+            new & Self::PIECE_MASK | (shape as u64) << B::PIECE_BITS,
+        )))
+    }
+}
+
+/// Every queue state reachable at one point of the solver.
+pub trait QueueSet: Clone + Debug + Default + Send + Sync {
+    /// A [`Bag`] prepared for taking pieces from sets of this kind.
+    type Bag: Sync;
+
+    fn prepare(bag: &Bag) -> Self::Bag;
+    fn init_hold(bag: &Self::Bag) -> Self;
+    fn held(shape: Shape) -> Self;
+    fn take(&self, bag: &Self::Bag, shape: Shape, is_first: bool, can_hold: bool) -> Self;
+    fn union(&mut self, other: &Self);
+    fn is_empty(&self) -> bool;
+    fn holds(&self, shape: Shape) -> bool;
+}
+
+impl<B: QueueBits> QueueSet for SmallVec<[QueueState<B>; 7]> {
+    type Bag = Bag;
+
+    fn prepare(bag: &Bag) -> Bag {
+        bag.clone()
+    }
+
+    fn init_hold(bag: &Bag) -> Self {
+        bag.init_hold()
+    }
+
+    fn held(shape: Shape) -> Self {
+        SmallVec::from_elem(QueueState::held(shape), 1)
+    }
+
+    fn take(&self, bag: &Bag, shape: Shape, is_first: bool, can_hold: bool) -> Self {
+        bag.take(self, shape, is_first, can_hold)
+    }
+
+    fn union(&mut self, other: &Self) {
+        for &queue in other {
+            if !self.contains(&queue) {
+                self.push(queue);
+            }
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.as_slice().is_empty()
+    }
+
+    fn holds(&self, shape: Shape) -> bool {
+        self.iter().any(|queue| queue.hold() == Some(shape))
+    }
+}
+
+/// Largest bag [`BitQueues`] can track.
+pub const BIT_BAG_SIZE: u32 = 7;
+
+/// A bag of at most [`BIT_BAG_SIZE`] pieces prepared for [`BitQueues`].
+#[derive(Clone, Debug)]
+pub struct BitBag {
+    full: u32,
+    /// For each shape, the piece fields whose lowest piece of that shape is at a position,
+    /// and that position.
+    selectors: [SmallVec<[(u128, u32); 2]>; 7],
+}
+
+/// Set of queue states as one bit per piece field for each held shape, so every state is
+/// advanced at once.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub struct BitQueues([u128; 7]);
+
+impl BitQueues {
+    /// Takes `shape` out of every piece field in `fields`.
+    fn remove(bag: &BitBag, fields: u128, shape: Shape) -> u128 {
+        bag.selectors[shape as usize]
+            .iter()
+            .fold(0, |acc, &(select, pos)| {
+                acc | (fields & select) >> (1 << pos)
+            })
+    }
+}
+
+impl QueueSet for BitQueues {
+    type Bag = BitBag;
+
+    fn prepare(bag: &Bag) -> BitBag {
+        assert!(bag.size() <= BIT_BAG_SIZE);
+
+        let selectors = Shape::ALL.map(|shape| {
+            let mask = bag.masks[shape as usize];
+            (0..64)
+                .filter(|pos| mask & (1 << pos) != 0)
+                .map(|pos| {
+                    let lower = mask & ((1 << pos) - 1);
+                    let select = (0..128u64)
+                        .filter(|field| field & (1 << pos) != 0 && field & lower == 0)
+                        .fold(0u128, |acc, field| acc | 1 << field);
+                    (select, pos)
+                })
+                .collect()
+        });
+
+        BitBag {
+            full: bag.full as u32,
+            selectors,
+        }
+    }
+
+    fn init_hold(bag: &BitBag) -> Self {
+        let full = 1 << bag.full;
+        BitQueues(Shape::ALL.map(|shape| Self::remove(bag, full, shape)))
+    }
+
+    fn held(shape: Shape) -> Self {
+        let mut queues = BitQueues::default();
+        queues.0[shape as usize] = 1;
+        queues
+    }
+
+    fn take(&self, bag: &BitBag, shape: Shape, is_first: bool, can_hold: bool) -> Self {
+        let mut queues = *self;
+        if is_first {
+            for fields in &mut queues.0 {
+                if *fields != 0 {
+                    *fields = 1 << bag.full;
+                }
+            }
+        }
+
+        let mut new = BitQueues::default();
+        let swapped = queues.0[shape as usize];
+        if swapped != 0 {
+            for swap_shape in Shape::ALL {
+                new.0[swap_shape as usize] |= Self::remove(bag, swapped, swap_shape);
+            }
+        }
+        if can_hold {
+            for hold in Shape::ALL.into_iter().filter(|&h| h != shape) {
+                new.0[hold as usize] |= Self::remove(bag, queues.0[hold as usize], shape);
+            }
+        }
+        new
+    }
+
+    fn union(&mut self, other: &Self) {
+        for (fields, other) in self.0.iter_mut().zip(other.0) {
+            *fields |= other;
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.iter().all(|&fields| fields == 0)
+    }
+
+    fn holds(&self, shape: Shape) -> bool {
+        self.0[shape as usize] != 0
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use itertools::Itertools;
+
+    use super::*;
+
+    /// The `u16` only implementation the generic one replaced.
+    mod old {
+        use smallvec::SmallVec;
+        use srs_4l::gameplay::Shape;
+
+        pub struct Bag {
+            pub count: u8,
+            pub full: u16,
+            pub masks: [u16; 7],
+        }
 
-    pub fn swap(self, bag: &Bag, shape: Shape) -> Option<QueueState> {
-        let mut new = self.take(bag, shape)?;
-        new.0 &= 0b1111111111111;
-        new.0 |= (shape as u16) << 13;
-        Some(new)
+        impl Bag {
+            pub fn new(shapes: &[Shape], count: u8) -> Bag {
+                let mut bag = Bag {
+                    count,
+                    full: (1 << shapes.len()) - 1,
+                    masks: [0; 7],
+                };
+                for (i, &shape) in shapes.iter().enumerate() {
+                    bag.masks[shape as usize] |= 1 << i;
+                }
+                bag
+            }
+
+            pub fn init_hold(&self) -> SmallVec<[QueueState; 7]> {
+                let initial = QueueState(self.full);
+                Shape::ALL
+                    .iter()
+                    .filter_map(|&shape| initial.swap(self, shape))
+                    .collect()
+            }
+
+            pub fn take(
+                &self,
+                queues: &[QueueState],
+                shape: Shape,
+                is_first: bool,
+                can_hold: bool,
+            ) -> SmallVec<[QueueState; 7]> {
+                let mut states = SmallVec::new();
+                for &queue in queues {
+                    let queue = if is_first { queue.next(self) } else { queue };
+                    if queue.hold() == Some(shape) {
+                        for swap_shape in Shape::ALL {
+                            if let Some(new) = queue.swap(self, swap_shape)
+                                && !states.contains(&new)
+                            {
+                                states.push(new);
+                            }
+                        }
+                    } else if can_hold
+                        && let Some(new) = queue.take(self, shape)
+                        && !states.contains(&new)
+                    {
+                        states.push(new);
+                    }
+                }
+                states
+            }
+        }
+
+        #[derive(Clone, Copy, PartialEq)]
+        pub struct QueueState(pub u16);
+
+        impl QueueState {
+            pub fn hold(self) -> Option<Shape> {
+                Shape::ALL.get((self.0 >> 13) as usize).copied()
+            }
+
+            pub fn next(self, bag: &Bag) -> QueueState {
+                QueueState(self.0 & 0b1110000000000000 | bag.full)
+            }
+
+            pub fn take(self, bag: &Bag, shape: Shape) -> Option<QueueState> {
+                let shape_field = self.0 & bag.masks[shape as usize];
+                if shape_field == 0 {
+                    return None;
+                }
+                let new_shape_field = shape_field & (shape_field - 1);
+                Some(QueueState(self.0 ^ shape_field ^ new_shape_field))
+            }
+
+            pub fn swap(self, bag: &Bag, shape: Shape) -> Option<QueueState> {
+                let mut new = self.take(bag, shape)?;
+                new.0 &= 0b1111111111111;
+                new.0 |= (shape as u16) << 13;
+                Some(new)
+            }
+        }
+    }
+
+    fn same<B: QueueBits>(old: &[old::QueueState], new: &[QueueState<B>]) -> bool {
+        old.len() == new.len()
+            && old.iter().zip(new).all(|(o, n)| {
+                o.hold() == n.hold()
+                    && (o.0 & 0b1111111111111) as u64 == n.0.to_u64() & QueueState::<B>::PIECE_MASK
+            })
+    }
+
+    /// Every group of distinct pieces from one 7-bag with every count, followed by a full bag.
+    fn patterns() -> Vec<Vec<(Vec<Shape>, u8)>> {
+        let mut patterns = Vec::new();
+        for subset in 1..(1u8 << 7) {
+            let shapes: Vec<Shape> = Shape::ALL
+                .into_iter()
+                .enumerate()
+                .filter(|&(i, _)| subset & (1 << i) != 0)
+                .map(|(_, s)| s)
+                .collect();
+            for count in 1..=shapes.len() as u8 {
+                patterns.push(vec![(shapes.clone(), count), (Shape::ALL.to_vec(), 7)]);
+            }
+        }
+        patterns
+    }
+
+    fn matches_old<B: QueueBits>(can_hold: bool) {
+        for pattern in patterns() {
+            let old_bags: Vec<old::Bag> =
+                pattern.iter().map(|(s, c)| old::Bag::new(s, *c)).collect();
+            let new_bags: Vec<Bag> = pattern.iter().map(|(s, c)| Bag::new(s, *c)).collect();
+
+            let mut old_states = old_bags[0].init_hold();
+            let mut new_states = new_bags[0].init_hold::<B>();
+            assert!(same(&old_states, &new_states));
+
+            let steps = old_bags
+                .iter()
+                .zip(&new_bags)
+                .flat_map(|(o, n)| (0..o.count).map(move |i| (o, n, i == 0)))
+                .skip(1);
+            for (old_bag, new_bag, is_first) in steps {
+                let mut old_next = Vec::new();
+                let mut new_next = Vec::new();
+                for shape in Shape::ALL {
+                    let o = old_bag.take(&old_states, shape, is_first, can_hold);
+                    let n = new_bag.take(&new_states, shape, is_first, can_hold);
+                    assert!(same(&o, &n), "{pattern:?} diverged taking {shape:?}");
+
+                    for (o, n) in o.into_iter().zip(n) {
+                        if !old_next.contains(&o) {
+                            old_next.push(o);
+                            new_next.push(n);
+                        }
+                    }
+                }
+                old_states = old_next.into();
+                new_states = new_next.into();
+            }
+        }
+    }
+
+    #[test]
+    fn u16_matches_old() {
+        matches_old::<u16>(true);
+        matches_old::<u16>(false);
+    }
+
+    #[test]
+    fn u32_matches_old() {
+        matches_old::<u32>(true);
+        matches_old::<u32>(false);
+    }
+
+    #[test]
+    fn u64_matches_old() {
+        matches_old::<u64>(true);
+        matches_old::<u64>(false);
+    }
+
+    /// Queue states of a set as `(hold, piece field)` pairs.
+    fn bit_states(queues: &BitQueues) -> Vec<(u8, u32)> {
+        Shape::ALL
+            .into_iter()
+            .flat_map(|hold| {
+                (0..128)
+                    .filter(move |&field| queues.0[hold as usize] & 1 << field != 0)
+                    .map(move |field| (hold as u8, field))
+            })
+            .collect()
+    }
+
+    fn bits_match_states(can_hold: bool) {
+        for pattern in patterns() {
+            let bags: Vec<Bag> = pattern.iter().map(|(s, c)| Bag::new(s, *c)).collect();
+            let bit_bags: Vec<BitBag> = bags.iter().map(BitQueues::prepare).collect();
+
+            let mut states: SmallVec<[QueueState; 7]> = QueueSet::init_hold(&bags[0]);
+            let mut bits = BitQueues::init_hold(&bit_bags[0]);
+
+            let steps = bags
+                .iter()
+                .zip(&bit_bags)
+                .flat_map(|(b, bb)| (0..b.count).map(move |i| (b, bb, i == 0)))
+                .skip(1);
+            for (bag, bit_bag, is_first) in steps {
+                let mut states_next = SmallVec::new();
+                let mut bits_next = BitQueues::default();
+                for shape in Shape::ALL {
+                    let s = QueueSet::take(&states, bag, shape, is_first, can_hold);
+                    let b = bits.take(bit_bag, shape, is_first, can_hold);
+                    let expected: Vec<(u8, u32)> = s
+                        .iter()
+                        .map(|q| (q.hold().unwrap() as u8, (q.0 & 0b1111111111111) as u32))
+                        .sorted()
+                        .collect();
+                    assert_eq!(
+                        bit_states(&b),
+                        expected,
+                        "{pattern:?} diverged taking {shape:?}"
+                    );
+                    assert_eq!(b.holds(shape), s.holds(shape));
+
+                    states_next.union(&s);
+                    bits_next.union(&b);
+                }
+                states = states_next;
+                bits = bits_next;
+            }
+        }
+    }
+
+    #[test]
+    fn bits_match_queue_states() {
+        bits_match_states(true);
+        bits_match_states(false);
+    }
+
+    #[test]
+    fn bags_wider_than_13() {
+        let shapes: Vec<Shape> = Shape::ALL.into_iter().cycle().take(14).collect();
+        let bag = Bag::new(&shapes, 14);
+        let mut states = bag.init_hold::<u32>();
+        for _ in 1..14 {
+            states = Shape::ALL
+                .into_iter()
+                .flat_map(|shape| bag.take(&states, shape, false, true))
+                .unique()
+                .collect();
+        }
+        // 13 pieces taken after the first one went to hold, so only the held piece remains
+        assert!(states.iter().all(|q| q.0 & 0x1fffffff == 0));
+        assert_eq!(states.len(), 7);
     }
 }
@@ -0,0 +1,430 @@
+use std::fmt;
+
+use itertools::Itertools;
+use rayon::prelude::{IntoParallelRefIterator, ParallelIterator};
+use rustc_hash::FxHashMap;
+use srs_4l::{brokenboard::BrokenBoard, gameplay::Shape};
+
+use crate::{
+    QBFinder,
+    build::{Setup, setup_solve_queues},
+    build_queues, parse_shape,
+    queue::QueueStart,
+    saves::SavePolicy,
+    simulate::Rng,
+};
+
+/// How a game deals its pieces.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Randomizer {
+    /// Each bag of 7 is one of every piece.
+    #[default]
+    Bag7,
+    /// Each bag of 14 is two of every piece. Where its bags start is not known, so each start
+    /// weighs as much as the chance of the pieces dealt coming from it.
+    Bag14,
+    /// Every piece is as likely as any other, every time.
+    Random,
+    /// TGM2 style: up to 6 rolls for a piece not among the last 4 dealt, starting from a
+    /// history of ZSSZ and never with S, Z or O first.
+    Tgm,
+}
+
+/// The pieces a [`Randomizer::Tgm`] history starts with, oldest first.
+const TGM_HISTORY: [Shape; 4] = [Shape::Z, Shape::S, Shape::S, Shape::Z];
+
+/// Rolls a [`Randomizer::Tgm`] makes before taking a piece in its history.
+const TGM_ROLLS: i32 = 6;
+
+impl Randomizer {
+    pub const ALL: [Randomizer; 4] = [
+        Randomizer::Bag7,
+        Randomizer::Bag14,
+        Randomizer::Random,
+        Randomizer::Tgm,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Randomizer::Bag7 => "7bag",
+            Randomizer::Bag14 => "14bag",
+            Randomizer::Random => "random",
+            Randomizer::Tgm => "tgm",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Randomizer> {
+        Randomizer::ALL
+            .into_iter()
+            .find(|r| r.name().eq_ignore_ascii_case(name.trim()))
+    }
+
+    /// Chance of each piece coming next after `dealt`, most recent last. A 7-bag only looks at
+    /// the pieces of the bag in progress, taking `dealt` to start with a bag; TGM at the last 4.
+    pub fn next(self, dealt: &[Shape]) -> Vec<(Shape, f64)> {
+        match self {
+            Randomizer::Bag7 => bag_next(self.state(dealt), 1),
+            Randomizer::Bag14 => bag14_next(dealt),
+            Randomizer::Random => Shape::ALL.into_iter().map(|s| (s, 1.0 / 7.0)).collect(),
+            Randomizer::Tgm if dealt.is_empty() => [Shape::I, Shape::J, Shape::L, Shape::T]
+                .into_iter()
+                .map(|s| (s, 0.25))
+                .collect(),
+            Randomizer::Tgm => {
+                let history: Vec<Shape> = TGM_HISTORY
+                    .iter()
+                    .chain(dealt)
+                    .rev()
+                    .take(TGM_HISTORY.len())
+                    .copied()
+                    .collect();
+                let in_history = Shape::ALL.iter().filter(|s| history.contains(s)).count();
+                let miss = in_history as f64 / 7.0;
+                // a piece outside the history is taken on any roll, one inside it only when
+                // every earlier roll also landed inside
+                let outside = (0..TGM_ROLLS).map(|i| miss.powi(i)).sum::<f64>() / 7.0;
+                let inside = miss.powi(TGM_ROLLS - 1) / 7.0;
+                Shape::ALL
+                    .into_iter()
+                    .map(|s| match history.contains(&s) {
+                        true => (s, inside),
+                        false => (s, outside),
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    /// The end of `dealt` that decides what comes after it: dealing the same pieces after either
+    /// has the same chance.
+    pub fn state(self, dealt: &[Shape]) -> &[Shape] {
+        match self {
+            Randomizer::Bag7 => &dealt[dealt.len() - dealt.len() % 7..],
+            Randomizer::Bag14 => dealt,
+            Randomizer::Random => &[],
+            Randomizer::Tgm => &dealt[dealt.len().saturating_sub(TGM_HISTORY.len())..],
+        }
+    }
+
+    /// Chance of `queue` being dealt next after `dealt`.
+    pub fn probability(self, dealt: &[Shape], queue: &[Shape]) -> f64 {
+        let mut dealt = dealt.to_vec();
+        let mut p = 1.0;
+        for &shape in queue {
+            p *= self
+                .next(&dealt)
+                .into_iter()
+                .find_map(|(s, p)| (s == shape).then_some(p))
+                .unwrap_or(0.0);
+            if p == 0.0 {
+                break;
+            }
+            dealt.push(shape);
+        }
+        p
+    }
+
+    /// Every queue of `len` pieces that can be dealt after `dealt`, with its chance. There are
+    /// up to 7 to the power of `len` of them.
+    pub fn distribution(self, dealt: &[Shape], len: usize) -> QueueDistribution {
+        let mut queues = Vec::new();
+        let mut dealt = dealt.to_vec();
+        let start = dealt.len();
+        self.deal_all(&mut dealt, start, len, 1.0, &mut queues);
+        QueueDistribution { queues }
+    }
+
+    /// As [`Randomizer::distribution`], for queues starting with the pieces of `seen`, with the
+    /// chance of each once `seen` has been dealt.
+    pub fn distribution_after(self, dealt: &[Shape], seen: &str, len: usize) -> QueueDistribution {
+        let seen: Vec<Shape> = seen.chars().filter_map(parse_shape).take(len).collect();
+        let dealt: Vec<Shape> = dealt.iter().chain(&seen).copied().collect();
+        let seen: String = seen.iter().map(|s| s.name()).collect();
+        let mut dist = self.distribution(&dealt, len - seen.len());
+        for (queue, _) in &mut dist.queues {
+            queue.insert_str(0, &seen);
+        }
+        dist
+    }
+
+    fn deal_all(
+        self,
+        dealt: &mut Vec<Shape>,
+        start: usize,
+        len: usize,
+        p: f64,
+        queues: &mut Vec<(String, f64)>,
+    ) {
+        if dealt.len() - start == len {
+            queues.push((dealt[start..].iter().map(|s| s.name()).collect(), p));
+            return;
+        }
+        for (shape, q) in self.next(dealt) {
+            if q > 0.0 {
+                dealt.push(shape);
+                self.deal_all(dealt, start, len, p * q, queues);
+                dealt.pop();
+            }
+        }
+    }
+
+    /// `len` pieces dealt after `dealt`.
+    pub fn sample(self, dealt: &[Shape], len: usize, rng: &mut Rng) -> Vec<Shape> {
+        let mut dealt = dealt.to_vec();
+        let start = dealt.len();
+        for _ in 0..len {
+            let next = self.next(&dealt);
+            let mut pick = (rng.next_u64() >> 11) as f64 / (1u64 << 53) as f64;
+            let shape = next
+                .iter()
+                .find(|&&(_, p)| {
+                    pick -= p;
+                    pick < 0.0
+                })
+                .or(next.last())
+                .map(|&(s, _)| s);
+            match shape {
+                Some(shape) => dealt.push(shape),
+                None => break,
+            }
+        }
+        dealt.split_off(start)
+    }
+}
+
+impl fmt::Display for Randomizer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Chance of each piece coming from a bag of `copies` of every piece once `used` has been drawn
+/// from it.
+fn bag_next(used: &[Shape], copies: usize) -> Vec<(Shape, f64)> {
+    let size = Shape::ALL.len() * copies;
+    let left = (size - used.len()) as f64;
+    Shape::ALL
+        .into_iter()
+        .map(|s| {
+            let n = copies - used.iter().filter(|&&u| u == s).count().min(copies);
+            (s, n as f64 / left)
+        })
+        .filter(|&(_, p)| p > 0.0)
+        .collect()
+}
+
+/// Chance of each piece coming next from a 14-bag after `dealt`, over every number of pieces of
+/// the first bag drawn before it. The pieces of a bag come in a random order, so pieces drawn
+/// unseen weigh the same as pieces still to come.
+fn bag14_next(dealt: &[Shape]) -> Vec<(Shape, f64)> {
+    let mut chances = [0.0; 7];
+    let mut total = 0.0;
+    for drawn_before in 0..14 {
+        let mut next_bag = 14 - drawn_before;
+        let mut bag_start = 0;
+        let mut weight = 1.0;
+        for (i, &shape) in dealt.iter().enumerate() {
+            if i == next_bag {
+                bag_start = i;
+                next_bag += 14;
+            }
+            weight *= bag_next(&dealt[bag_start..i], 2)
+                .into_iter()
+                .find_map(|(s, p)| (s == shape).then_some(p))
+                .unwrap_or(0.0);
+        }
+        if dealt.len() == next_bag {
+            bag_start = dealt.len();
+        }
+        for (shape, p) in bag_next(&dealt[bag_start..], 2) {
+            chances[shape as usize] += weight * p;
+        }
+        total += weight;
+    }
+    if total == 0.0 {
+        return vec![];
+    }
+    Shape::ALL
+        .into_iter()
+        .map(|s| (s, chances[s as usize] / total))
+        .filter(|&(_, p)| p > 0.0)
+        .collect()
+}
+
+/// Where the solve queues after a build come from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SolveSource<'a> {
+    /// Every queue of a pattern, all as likely.
+    Pattern(&'a str),
+    /// `seen`, the solve pieces already in the previews, then whatever `randomizer` deals
+    /// after them, as likely as it deals it.
+    Dealt {
+        randomizer: Randomizer,
+        seen: &'a str,
+    },
+}
+
+/// Queues of the same length with the chance of each, from [`Randomizer::distribution`].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct QueueDistribution {
+    pub queues: Vec<(String, f64)>,
+}
+
+impl QueueDistribution {
+    /// A pattern expanding to exactly these queues, one per line.
+    pub fn pattern(&self) -> String {
+        self.queues
+            .iter()
+            .map(|(q, _)| q.chars().join(","))
+            .join("\n")
+    }
+
+    /// Chance of the queue each of `queues` ends with, so that pieces in front of it such as
+    /// those a setup leaves don't matter.
+    pub fn weights<'a>(&self, queues: impl IntoIterator<Item = &'a str>) -> Vec<f64> {
+        let len = self.queues.first().map_or(0, |(q, _)| q.len());
+        let chances: FxHashMap<&str, f64> =
+            self.queues.iter().map(|(q, p)| (q.as_str(), *p)).collect();
+        queues
+            .into_iter()
+            .map(|queue| {
+                queue
+                    .get(queue.len().saturating_sub(len)..)
+                    .and_then(|end| chances.get(end))
+                    .copied()
+                    .unwrap_or(0.0)
+            })
+            .collect()
+    }
+}
+
+impl QBFinder {
+    /// The solve queues `randomizer` can deal after the first queue of `build_queue` from
+    /// `start`, each starting with `seen`, the solve pieces already in the previews.
+    pub fn solve_distribution(
+        &self,
+        randomizer: Randomizer,
+        build_queue: &str,
+        start: &QueueStart,
+        seen: &str,
+    ) -> QueueDistribution {
+        let build_pattern = start.apply(build_queue);
+        let build: Vec<Shape> = build_queues(&QueueStart::default(), &build_pattern)
+            .first()
+            .map(|&q| q.collect())
+            .unwrap_or_default();
+        let build_len = build.len() + start.hold.iter().count();
+        let p_count = self.pc_piece_count().saturating_sub(build_len);
+        let dealt: Vec<Shape> = start.bag_used.iter().chain(&build).copied().collect();
+        randomizer.distribution_after(&dealt, seen, p_count)
+    }
+
+    /// Chance of `setup` meeting each tier of `saves` first over the queues of `dist`, taking
+    /// every order of the pieces it leaves unplaced as equally likely.
+    pub fn setup_probabilities(
+        &self,
+        setup: &Setup,
+        dist: &QueueDistribution,
+        saves: &SavePolicy,
+    ) -> Vec<f64> {
+        let queues: Vec<String> = dist.queues.iter().map(|(q, _)| q.clone()).collect();
+        let p_count = queues.first().map_or(0, String::len);
+        let board = BrokenBoard::from_garbage(setup.board.to_broken_bitboard().0);
+        let solve_queues = setup_solve_queues(setup, &queues, p_count);
+        // every order of the unplaced pieces comes before every queue, in that order
+        let orders = solve_queues.len() / queues.len().max(1);
+        let tiers: Vec<Option<usize>> = solve_queues
+            .par_iter()
+            .map(|bags| {
+                if self.task.is_cancelled() {
+                    return None;
+                }
+                self.queue_tier(&board, bags, saves)
+            })
+            .collect();
+
+        let mut res = vec![0.0; saves.tiers.len()];
+        for (i, tier) in tiers.into_iter().enumerate() {
+            if let Some(tier) = tier {
+                res[tier] += dist.queues[i % queues.len()].1 / orders as f64;
+            }
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shapes(pieces: &str) -> Vec<Shape> {
+        pieces.chars().map(|c| parse_shape(c).unwrap()).collect()
+    }
+
+    fn assert_sums_to_one(randomizer: Randomizer, dealt: &[Shape]) {
+        let sum: f64 = randomizer.next(dealt).iter().map(|(_, p)| p).sum();
+        assert!(
+            (sum - 1.0).abs() < 1e-9,
+            "{randomizer} after {dealt:?}: {sum}"
+        );
+    }
+
+    #[test]
+    fn next_sums_to_one() {
+        for randomizer in Randomizer::ALL {
+            for dealt in [
+                "",
+                "I",
+                "IJLOSZ",
+                "IJLOSZT",
+                "TIJLOSZTIJL",
+                "IIJJLLOOSSTTZZI",
+            ] {
+                assert_sums_to_one(randomizer, &shapes(dealt));
+            }
+        }
+    }
+
+    #[test]
+    fn tgm_history() {
+        let first = Randomizer::Tgm.next(&[]);
+        assert_eq!(first.len(), 4);
+        assert!(
+            first
+                .iter()
+                .all(|&(s, _)| ![Shape::S, Shape::Z, Shape::O].contains(&s))
+        );
+        // the starting history of ZSSZ makes S and Z less likely than the rest
+        let next = Randomizer::Tgm.next(&shapes("I"));
+        let chance = |shape| next.iter().find(|&&(s, _)| s == shape).unwrap().1;
+        assert!(chance(Shape::S) < chance(Shape::T));
+        assert!(chance(Shape::I) < chance(Shape::T));
+        for dealt in ["IJ", "IJLO", "IIII", "TTIJ"] {
+            assert_sums_to_one(Randomizer::Tgm, &shapes(dealt));
+        }
+    }
+
+    #[test]
+    fn bags_deal_each_piece_once() {
+        let next = Randomizer::Bag7.next(&shapes("IJLOSZ"));
+        assert_eq!(next, vec![(Shape::T, 1.0)]);
+        assert!(Randomizer::Bag7.next(&shapes("IJLOSZT")).len() == 7);
+        // three Is in a row span two 14-bags, but five can't
+        assert!(Randomizer::Bag14.probability(&[], &shapes("III")) > 0.0);
+        assert!(Randomizer::Bag14.probability(&[], &shapes("IIIII")) == 0.0);
+    }
+
+    #[test]
+    fn state_decides_distribution() {
+        let seen = "S";
+        for randomizer in [Randomizer::Bag7, Randomizer::Random, Randomizer::Tgm] {
+            let dealt = shapes("IJLOSZTIJ");
+            let state = randomizer.state(&dealt);
+            assert_eq!(
+                randomizer.distribution_after(&dealt, seen, 3),
+                randomizer.distribution_after(state, seen, 3),
+                "{randomizer}"
+            );
+        }
+    }
+}
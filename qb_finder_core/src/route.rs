@@ -0,0 +1,271 @@
+use std::{
+    cmp::Ordering,
+    sync::{
+        Mutex,
+        atomic::{self, AtomicUsize},
+    },
+};
+
+use itertools::Itertools;
+use rayon::prelude::{IntoParallelRefIterator, ParallelIterator};
+use rustc_hash::FxHashMap;
+use srs_4l::{brokenboard::BrokenBoard, gameplay::Shape, queue::Queue};
+
+use crate::{
+    QBFinder,
+    build::Setup,
+    build_len, build_queues, expand_pattern, parse_shape,
+    queue::{Bag, QueueStart},
+    randomizer::{QueueDistribution, Randomizer, SolveSource},
+    saves::SavePolicy,
+};
+
+/// Which setup a route builds from each build queue.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RoutePlan {
+    /// The setup to build from each build queue, keyed as in
+    /// [`crate::build::BuildCover::missing`]. Build queues without one, or that can't build
+    /// theirs, lose.
+    PerBuild(FxHashMap<String, Setup>),
+    /// Of these setups, the one a build queue can build that does best over the solve queues
+    /// that can follow it.
+    Best(Vec<Setup>),
+    /// As `Best`, over every setup the build can reach.
+    BestAvailable,
+}
+
+/// How a route does over every sequence of a build queue followed by a solve queue, from
+/// [`QBFinder::route`].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RouteResult {
+    /// Sequences in all.
+    pub total: usize,
+    /// Sequences meeting each tier of the policy first.
+    pub tiers: Vec<usize>,
+    /// Chance of the sequences in all, and of those meeting each tier first. Every sequence
+    /// weighs 1 when they are all as likely.
+    pub total_weight: f64,
+    pub tier_weights: Vec<f64>,
+    /// Sequences meeting no tier, as build queue and solve queue.
+    pub losing: Vec<(String, String)>,
+    /// The setup built from each build queue, if there is one it can build.
+    pub choices: Vec<(String, Option<Setup>)>,
+}
+
+impl RouteResult {
+    /// Sequences meeting any tier.
+    pub fn wins(&self) -> usize {
+        self.tiers.iter().sum()
+    }
+
+    /// Chance of meeting any tier, from 0 to 1.
+    pub fn probability(&self) -> f64 {
+        self.tier_probabilities().iter().sum()
+    }
+
+    /// Chance of meeting each tier first, from 0 to 1.
+    pub fn tier_probabilities(&self) -> Vec<f64> {
+        self.tier_weights
+            .iter()
+            .map(|&w| {
+                if self.total_weight == 0.0 {
+                    0.0
+                } else {
+                    w / self.total_weight
+                }
+            })
+            .collect()
+    }
+}
+
+impl QBFinder {
+    /// Follows `plan` over every build queue of `build_queue` after `start`, then every solve
+    /// queue `solves` gives for it, and weighs how often the PC meets each tier of `saves`. A
+    /// randomizer dealing the solve queues weighs the build queues too. A solve pattern leaves
+    /// out the solve queues a 7-bag can't deal after a build, so every sequence left weighs the
+    /// same.
+    pub fn route(
+        &self,
+        build_queue: &str,
+        start: &QueueStart,
+        solves: SolveSource,
+        saves: &SavePolicy,
+        plan: &RoutePlan,
+    ) -> RouteResult {
+        let build_pattern = start.apply(build_queue);
+        let p_count = self
+            .pc_piece_count()
+            .saturating_sub(build_len(start, &build_pattern));
+        let builds = build_queues(start, &build_pattern);
+        let pattern_solves: Vec<(String, Vec<Shape>)> = match solves {
+            SolveSource::Pattern(pattern) => expand_pattern(pattern)
+                .into_iter()
+                .map(|q| {
+                    let shapes = q.chars().take(p_count).filter_map(parse_shape).collect();
+                    (q, shapes)
+                })
+                .collect(),
+            SolveSource::Dealt { .. } => vec![],
+        };
+        // builds leaving the randomizer in the same state share their solve queues
+        let dists: Mutex<FxHashMap<Vec<Shape>, QueueDistribution>> = Mutex::default();
+        // the solve queues that can follow a build, with the chance of each sequence
+        let solves_for = |build: &Queue| -> Vec<(String, f64)> {
+            let dealt: Vec<Shape> = start
+                .bag_used
+                .iter()
+                .copied()
+                .chain(build.skip(start.hold.iter().count()))
+                .collect();
+            // a pattern's sequences are dealt from 7-bags, every one as likely as any other
+            let (randomizer, chance) = match solves {
+                SolveSource::Pattern(_) => (Randomizer::Bag7, 1.0),
+                SolveSource::Dealt { randomizer, .. } => (
+                    randomizer,
+                    randomizer.probability(&start.bag_used, &dealt[start.bag_used.len()..]),
+                ),
+            };
+            let state = randomizer.state(&dealt).to_vec();
+            let cached = dists.lock().unwrap().get(&state).cloned();
+            let dist = cached.unwrap_or_else(|| {
+                let dist = match solves {
+                    SolveSource::Pattern(_) => QueueDistribution {
+                        queues: pattern_solves
+                            .iter()
+                            .filter(|(_, shapes)| randomizer.probability(&state, shapes) > 0.0)
+                            .map(|(q, _)| (q.clone(), 1.0))
+                            .collect(),
+                    },
+                    SolveSource::Dealt { seen, .. } => {
+                        randomizer.distribution_after(&dealt, seen, p_count)
+                    }
+                };
+                dists.lock().unwrap().insert(state, dist.clone());
+                dist
+            });
+            dist.queues
+                .into_iter()
+                .map(|(q, p)| (q, chance * p))
+                .collect()
+        };
+
+        let candidates: Vec<Setup> = match plan {
+            RoutePlan::PerBuild(setups) => setups.values().cloned().unique().collect(),
+            RoutePlan::Best(setups) => setups.clone(),
+            RoutePlan::BestAvailable => self
+                .build_mode
+                .lefts(None)
+                .flat_map(|left| self.build_setups(&build_pattern, start.hold, left, None))
+                .unique()
+                .collect(),
+        };
+        let orders: Vec<_> = candidates
+            .iter()
+            .map(|setup| {
+                let (in_order, with_hold) = self.build_orders(setup);
+                if self.hold { with_hold } else { in_order }
+            })
+            .collect();
+
+        let done = AtomicUsize::new(0);
+        self.task.report(0, builds.len());
+        #[allow(clippy::type_complexity)]
+        let per_build: Vec<(
+            String,
+            Option<Setup>,
+            Vec<(String, f64)>,
+            Vec<Option<usize>>,
+        )> = builds
+            .par_iter()
+            .map(|build| {
+                let key = build.to_string();
+                if self.task.is_cancelled() {
+                    return (key, None, vec![], vec![]);
+                }
+                let queues = solves_for(build);
+                let weights: Vec<f64> = queues.iter().map(|(_, w)| *w).collect();
+                let can_build = |i: usize| {
+                    let prefix: Queue = build.take(candidates[i].drawn()).collect();
+                    orders[i].contains(&prefix)
+                };
+                let tiers_of = |i: usize| {
+                    let setup = &candidates[i];
+                    let board = BrokenBoard::from_garbage(setup.board.to_broken_bitboard().0);
+                    queues
+                        .iter()
+                        .map(|(solve, _)| {
+                            let bags: Vec<Bag> = setup
+                                .held
+                                .into_iter()
+                                .chain(build.skip(setup.drawn()))
+                                .chain(solve.chars().take(p_count).filter_map(parse_shape))
+                                .map(|s| Bag::new(&[s], 1))
+                                .collect();
+                            self.queue_tier(&board, &bags, saves)
+                        })
+                        .collect::<Vec<_>>()
+                };
+
+                let chosen = match plan {
+                    RoutePlan::PerBuild(setups) => setups.get(&key).and_then(|setup| {
+                        let i = candidates.iter().position(|c| c == setup)?;
+                        can_build(i).then(|| (i, tiers_of(i)))
+                    }),
+                    _ => (0..candidates.len())
+                        .filter(|&i| can_build(i))
+                        .map(|i| (i, tiers_of(i)))
+                        .max_by(|(_, a), (_, b)| {
+                            let len = saves.tiers.len();
+                            tier_weights(a, &weights, len)
+                                .partial_cmp(&tier_weights(b, &weights, len))
+                                .unwrap_or(Ordering::Equal)
+                        }),
+                };
+                self.task.report(
+                    done.fetch_add(1, atomic::Ordering::Relaxed) + 1,
+                    builds.len(),
+                );
+                match chosen {
+                    Some((i, tiers)) => (key, Some(candidates[i].clone()), queues, tiers),
+                    None => {
+                        let tiers = vec![None; queues.len()];
+                        (key, None, queues, tiers)
+                    }
+                }
+            })
+            .collect();
+
+        let mut res = RouteResult {
+            tiers: vec![0; saves.tiers.len()],
+            tier_weights: vec![0.0; saves.tiers.len()],
+            ..RouteResult::default()
+        };
+        for (build, setup, queues, tiers) in per_build {
+            for ((solve, weight), tier) in queues.into_iter().zip(tiers) {
+                res.total += 1;
+                res.total_weight += weight;
+                match tier {
+                    Some(tier) => {
+                        res.tiers[tier] += 1;
+                        res.tier_weights[tier] += weight;
+                    }
+                    None => res.losing.push((build.clone(), solve)),
+                }
+            }
+            res.choices.push((build, setup));
+        }
+        res
+    }
+}
+
+/// Chance of the queues meeting each tier first, which compare lexicographically like a save
+/// score.
+fn tier_weights(tiers: &[Option<usize>], weights: &[f64], len: usize) -> Vec<f64> {
+    let mut res = vec![0.0; len];
+    for (tier, weight) in tiers.iter().zip(weights) {
+        if let Some(tier) = tier {
+            res[*tier] += weight;
+        }
+    }
+    res
+}
@@ -0,0 +1,403 @@
+use std::fmt;
+
+use itertools::Itertools;
+use srs_4l::{brokenboard::BrokenBoard, gameplay::Shape};
+
+use crate::{
+    QBFinder, encode_board, expand_pattern, parse_shape, queue::Bag, randomizer::QueueDistribution,
+    store::ResultStore,
+};
+
+/// One tier of a [`SavePolicy`].
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum SaveTier {
+    /// Finishing the PC with one of these pieces in hold.
+    Keep(Vec<Shape>),
+    /// Finishing the PC with every piece placed and nothing in hold.
+    Empty,
+    /// Finishing the PC at all.
+    Anything,
+}
+
+impl SaveTier {
+    /// What [`crate::solver::compute`] is asked to keep to find solves meeting this tier. `None`
+    /// keeps nothing when the pieces fill the field exactly.
+    pub fn saves(&self) -> Vec<Option<Shape>> {
+        match self {
+            SaveTier::Keep(shapes) => shapes.iter().copied().map(Some).collect(),
+            SaveTier::Empty => vec![None],
+            SaveTier::Anything => [None]
+                .into_iter()
+                .chain(Shape::ALL.into_iter().map(Some))
+                .collect(),
+        }
+    }
+}
+
+impl fmt::Display for SaveTier {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SaveTier::Keep(shapes) if shapes.len() == Shape::ALL.len() => write!(f, "*"),
+            SaveTier::Keep(shapes) if shapes.len() > Shape::ALL.len() / 2 => {
+                let excluded = Shape::ALL.iter().filter(|s| !shapes.contains(s));
+                write!(f, "^{}", excluded.map(|s| s.name()).join(""))
+            }
+            SaveTier::Keep(shapes) => write!(f, "{}", shapes.iter().map(|s| s.name()).join("")),
+            SaveTier::Empty => write!(f, "_"),
+            SaveTier::Anything => write!(f, "-"),
+        }
+    }
+}
+
+/// What a solve has to keep in hold, as tiers from highest to lowest priority. Analyses count
+/// each solve queue towards the first tier it meets.
+///
+/// Written as tiers separated by commas, such as `T,IO,^O,-`. Each tier is one of:
+/// - pieces, such as `IO`: keeping any of them
+/// - `*`: keeping any piece
+/// - `^` followed by pieces, such as `^O`: keeping any piece but those
+/// - `_`: an empty hold, with every piece placed
+/// - `-`: no requirement, only finishing the PC
+///
+/// An empty policy is `-`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct SavePolicy {
+    pub tiers: Vec<SaveTier>,
+}
+
+impl SavePolicy {
+    /// Reads a policy in the format above, or `None` if a tier is not one of them.
+    pub fn parse(saves: &str) -> Option<SavePolicy> {
+        let mut tiers = Vec::new();
+        for tier in saves.split(',').map(str::trim) {
+            let tier = match tier {
+                "" => continue,
+                "*" => SaveTier::Keep(Shape::ALL.to_vec()),
+                "_" => SaveTier::Empty,
+                "-" => SaveTier::Anything,
+                _ => {
+                    let (excluding, pieces) = match tier.strip_prefix('^') {
+                        Some(pieces) => (true, pieces),
+                        None => (false, tier),
+                    };
+                    let shapes: Vec<Shape> = pieces
+                        .chars()
+                        .map(parse_shape)
+                        .collect::<Option<Vec<_>>>()?;
+                    // in the order of `Shape::ALL`, so a tier reads and compares the same
+                    // whatever order its pieces are written in
+                    let shapes: Vec<Shape> = Shape::ALL
+                        .into_iter()
+                        .filter(|s| shapes.contains(s) != excluding)
+                        .collect();
+                    if shapes.is_empty() {
+                        return None;
+                    }
+                    SaveTier::Keep(shapes)
+                }
+            };
+            tiers.push(tier);
+        }
+        if tiers.is_empty() {
+            tiers.push(SaveTier::Anything);
+        }
+        Some(SavePolicy { tiers })
+    }
+}
+
+impl Default for SavePolicy {
+    fn default() -> SavePolicy {
+        SavePolicy {
+            tiers: vec![SaveTier::Anything],
+        }
+    }
+}
+
+impl fmt::Display for SavePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.tiers.iter().join(","))
+    }
+}
+
+/// Solve queues meeting each tier first, in tier order. Compares lexicographically, so a
+/// setup is better if it keeps the highest tier in more queues, and so on down.
+#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SaveScore(pub Vec<usize>);
+
+impl SaveScore {
+    /// The lowest score keeping the first tier in `count` queues.
+    pub fn at_least(count: usize) -> SaveScore {
+        SaveScore(vec![count])
+    }
+
+    /// Queues keeping the first tier.
+    pub fn primary(&self) -> usize {
+        self.0.first().copied().unwrap_or(0)
+    }
+
+    /// Queues meeting any tier.
+    pub fn total(&self) -> usize {
+        self.0.iter().sum()
+    }
+}
+
+impl fmt::Display for SaveScore {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0.iter().join("+"))
+    }
+}
+
+/// How a setup does on one solve queue.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QueueSaves {
+    pub queue: String,
+    /// Every piece the PC can finish with in hold.
+    pub keepable: Vec<Shape>,
+    /// The first tier of the policy the PC meets, if any.
+    pub tier: Option<usize>,
+}
+
+/// What a setup can keep on each solve queue, from [`QBFinder::saves_stats`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct SaveStats {
+    pub tiers: Vec<SaveTier>,
+    pub queues: Vec<QueueSaves>,
+    /// Chance of each queue, all the same unless set by [`SaveStats::weigh`].
+    pub weights: Vec<f64>,
+}
+
+impl SaveStats {
+    /// Queues meeting each tier first.
+    pub fn counts(&self) -> Vec<usize> {
+        let mut counts = vec![0; self.tiers.len()];
+        for tier in self.queues.iter().filter_map(|q| q.tier) {
+            counts[tier] += 1;
+        }
+        counts
+    }
+
+    /// Chance of meeting each tier first, from 0 to 1.
+    pub fn probabilities(&self) -> Vec<f64> {
+        let mut res = vec![0.0; self.tiers.len()];
+        for (q, w) in self.queues.iter().zip(&self.weights) {
+            if let Some(tier) = q.tier {
+                res[tier] += w;
+            }
+        }
+        res
+    }
+
+    /// Percentage of the queues meeting any tier that meet each tier first, weighted by their
+    /// chance.
+    pub fn percentages(&self) -> Vec<f64> {
+        let probabilities = self.probabilities();
+        let met: f64 = probabilities.iter().sum();
+        probabilities
+            .into_iter()
+            .map(|p| if met == 0.0 { 0.0 } else { 100.0 * p / met })
+            .collect()
+    }
+
+    /// Weights every queue by the chance of the queue of `dist` it ends with.
+    pub fn weigh(&mut self, dist: &QueueDistribution) {
+        let weights = dist.weights(self.queues.iter().map(|q| q.queue.as_str()));
+        let total: f64 = weights.iter().sum();
+        self.weights = weights
+            .into_iter()
+            .map(|w| if total == 0.0 { 0.0 } else { w / total })
+            .collect();
+    }
+}
+
+/// The start of `queue` with just enough pieces to fill the field, so that solving it without
+/// a save places every piece.
+fn exact_fill(setup: &BrokenBoard, queue: &[Bag]) -> Option<Vec<Bag>> {
+    let mut needed = (40 - setup.board.0.count_ones() as u8) / 4;
+    let mut res = Vec::new();
+    for bag in queue {
+        if needed == 0 {
+            break;
+        }
+        let count = bag.count.min(needed);
+        needed -= count;
+        res.push(Bag {
+            count,
+            ..bag.clone()
+        });
+    }
+    (needed == 0).then_some(res)
+}
+
+impl QBFinder {
+    /// Whether `setup` finishes the PC from `queue` meeting `tier`.
+    pub(crate) fn meets_tier(&self, setup: &BrokenBoard, queue: &[Bag], tier: &SaveTier) -> bool {
+        match tier {
+            SaveTier::Keep(shapes) => shapes
+                .iter()
+                .filter(|&&s| queue.iter().any(|bag| bag.contains(s)))
+                .any(|&s| !self.solve(setup, queue, None, Some(s)).is_empty()),
+            SaveTier::Empty => exact_fill(setup, queue)
+                .is_some_and(|queue| !self.solve(setup, &queue, None, None).is_empty()),
+            SaveTier::Anything => !self.solve(setup, queue, None, None).is_empty(),
+        }
+    }
+
+    /// Every solve of `setup` from `queue` meeting `tier`.
+    pub(crate) fn tier_solves(
+        &self,
+        setup: &BrokenBoard,
+        queue: &[Bag],
+        tier: &SaveTier,
+    ) -> Vec<BrokenBoard> {
+        tier.saves()
+            .into_iter()
+            .filter(|save| save.is_none_or(|s| queue.iter().any(|bag| bag.contains(s))))
+            .flat_map(|save| match (tier, save) {
+                (SaveTier::Empty, _) => exact_fill(setup, queue)
+                    .map(|queue| self.solve(setup, &queue, None, None))
+                    .unwrap_or_default(),
+                _ => self.solve(setup, queue, None, save),
+            })
+            .collect()
+    }
+
+    /// The first tier of `policy` that `setup` meets for `queue`.
+    pub(crate) fn queue_tier(
+        &self,
+        setup: &BrokenBoard,
+        queue: &[Bag],
+        policy: &SavePolicy,
+    ) -> Option<usize> {
+        policy
+            .tiers
+            .iter()
+            .position(|tier| self.meets_tier(setup, queue, tier))
+    }
+
+    /// Scores `setup` over `solve_queues`, or returns `None` once it can't reach `bound`.
+    pub(crate) fn save_score(
+        &self,
+        setup: &BrokenBoard,
+        solve_queues: &[Vec<Bag>],
+        policy: &SavePolicy,
+        bound: &SaveScore,
+    ) -> Option<SaveScore> {
+        let mut score = SaveScore(vec![0; policy.tiers.len()]);
+
+        for (i, q) in solve_queues.iter().enumerate() {
+            if self.task.is_cancelled() {
+                return None;
+            }
+            // the best it can still do is keep the first tier in every queue left
+            let mut best = score.clone();
+            if let Some(first) = best.0.first_mut() {
+                *first += solve_queues.len() - i;
+            }
+            if best < *bound {
+                return None;
+            }
+            if let Some(tier) = self.queue_tier(setup, q, policy) {
+                score.0[tier] += 1;
+            }
+        }
+        (score >= *bound).then_some(score)
+    }
+
+    /// Every piece `setup` can keep and the first tier of `saves` it meets on each queue of
+    /// `solve_queue`, or `None` if the pattern has something other than pieces.
+    pub fn saves_stats(
+        &self,
+        setup: &BrokenBoard,
+        solve_queue: &str,
+        saves: &SavePolicy,
+    ) -> Option<SaveStats> {
+        let solve_queues: Vec<(String, Vec<Bag>)> = expand_pattern(solve_queue)
+            .into_iter()
+            .map(|q| {
+                let bags = q
+                    .chars()
+                    .map(|c| Some(Bag::new(&[parse_shape(c)?], 1)))
+                    .collect::<Option<_>>()?;
+                Some((q, bags))
+            })
+            .collect::<Option<_>>()?;
+
+        let queues = self.stored(
+            &[
+                "saves_stats",
+                &encode_board(setup),
+                solve_queue,
+                &saves.to_string(),
+            ],
+            ResultStore::load_queue_saves,
+            |store, key, queues| store.save_queue_saves(key, queues),
+            || {
+                let total = solve_queues.len();
+                self.task.report(0, total);
+                let mut queues = Vec::new();
+                for (i, (queue, bags)) in solve_queues.iter().enumerate() {
+                    if self.task.is_cancelled() {
+                        break;
+                    }
+                    let keepable = Shape::ALL
+                        .into_iter()
+                        .filter(|&s| bags.iter().any(|bag| bag.contains(s)))
+                        .filter(|&s| !self.solve(setup, bags, None, Some(s)).is_empty())
+                        .collect();
+                    queues.push(QueueSaves {
+                        queue: queue.clone(),
+                        keepable,
+                        tier: self.queue_tier(setup, bags, saves),
+                    });
+                    self.task.report(i + 1, total);
+                }
+                queues
+            },
+        );
+        let weights = vec![1.0 / queues.len().max(1) as f64; queues.len()];
+        Some(SaveStats {
+            tiers: saves.tiers.clone(),
+            queues,
+            weights,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn policy_round_trip() {
+        for saves in ["T", "T,IO,^O,*,_,-", "^IJ,_", "-"] {
+            let policy = SavePolicy::parse(saves).unwrap();
+            assert_eq!(policy.to_string(), saves);
+            assert_eq!(SavePolicy::parse(&policy.to_string()), Some(policy));
+        }
+        // more than half the pieces are written as the ones left out
+        let policy = SavePolicy::parse("IJLO, T ,TT").unwrap();
+        assert_eq!(policy.to_string(), "^STZ,T,T");
+        assert_eq!(SavePolicy::parse(&policy.to_string()), Some(policy));
+    }
+
+    #[test]
+    fn policy_tiers() {
+        let policy = SavePolicy::parse("T,IO,^O,*,_,-").unwrap();
+        assert_eq!(
+            policy.tiers,
+            vec![
+                SaveTier::Keep(vec![Shape::T]),
+                SaveTier::Keep(vec![Shape::I, Shape::O]),
+                SaveTier::Keep(Shape::ALL.into_iter().filter(|&s| s != Shape::O).collect()),
+                SaveTier::Keep(Shape::ALL.to_vec()),
+                SaveTier::Empty,
+                SaveTier::Anything,
+            ]
+        );
+        assert_eq!(SavePolicy::parse("TI"), SavePolicy::parse("IT"));
+        assert_eq!(SavePolicy::parse("TI").unwrap().to_string(), "IT");
+        assert_eq!(SavePolicy::parse(""), Some(SavePolicy::default()));
+        assert_eq!(SavePolicy::parse("T,X"), None);
+        assert_eq!(SavePolicy::parse("^IJLOSTZ"), None);
+    }
+}
@@ -0,0 +1,462 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use rayon::prelude::{IntoParallelIterator, ParallelIterator};
+use rustc_hash::FxHashSet;
+use srs_4l::{brokenboard::BrokenBoard, gameplay::Shape, queue::Queue};
+
+use crate::{
+    QBFinder,
+    build::Setup,
+    build_len, expand_pattern, parse_group, parse_shape,
+    queue::{Bag, QueueStart},
+    randomizer::SolveSource,
+    saves::{SavePolicy, SaveTier},
+    store::mix,
+};
+
+/// A SplitMix64 generator, so that a seed gives the same sequences on every platform.
+#[derive(Clone, Debug)]
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        Rng(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        mix(self.0)
+    }
+
+    /// A number below `n`, which must not be 0.
+    pub fn below(&mut self, n: usize) -> usize {
+        ((self.next_u64() as u128 * n as u128) >> 64) as usize
+    }
+
+    pub fn shuffle<T>(&mut self, items: &mut [T]) {
+        for i in (1..items.len()).rev() {
+            items.swap(i, self.below(i + 1));
+        }
+    }
+}
+
+/// One of `pattern`'s queues, each line as likely as the queues it expands to and each group
+/// shuffled.
+pub(crate) fn sample_pattern(pattern: &str, rng: &mut Rng) -> Vec<Shape> {
+    let lines: Vec<Vec<(Vec<Shape>, usize)>> = pattern
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .map(|line| {
+            line.split(",")
+                .map(|group| parse_group(group).unwrap_or_default())
+                .collect()
+        })
+        .collect();
+    // each line weighs as many queues as it expands to
+    let weights: Vec<u128> = lines
+        .iter()
+        .map(|groups| {
+            groups
+                .iter()
+                .map(|(g, count)| {
+                    (g.len() - count + 1..=g.len())
+                        .map(|n| n as u128)
+                        .product::<u128>()
+                })
+                .product()
+        })
+        .collect();
+    let total: u128 = weights.iter().sum();
+    if total == 0 {
+        return vec![];
+    }
+    let mut pick = (rng.next_u64() as u128 * total) >> 64;
+    let line = weights
+        .iter()
+        .position(|&w| {
+            if pick < w {
+                return true;
+            }
+            pick -= w;
+            false
+        })
+        .unwrap_or(0);
+
+    lines[line]
+        .iter()
+        .flat_map(|(group, count)| {
+            let mut group = group.clone();
+            rng.shuffle(&mut group);
+            group.truncate(*count);
+            group
+        })
+        .collect()
+}
+
+/// A setup to build and the solutions memorised for it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PlannedSetup {
+    pub setup: Setup,
+    /// Solutions of the setup the player knows, or every solution if empty.
+    pub solves: Vec<BrokenBoard>,
+}
+
+/// Setups to build, each taken only when the ones before it can't be built.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SimPlan {
+    pub setups: Vec<PlannedSetup>,
+}
+
+/// Why a simulated sequence met no tier.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum SimFailure {
+    /// No setup of the plan can be built from the build pieces.
+    Unbuildable,
+    /// The setup built has no PC from the solve pieces.
+    NoPc,
+    /// The setup has a PC from the solve pieces, but none meeting a tier.
+    NoSave,
+    /// The setup has a PC from the solve pieces, but not one the plan knows.
+    OffPlan,
+}
+
+impl SimFailure {
+    pub fn name(self) -> &'static str {
+        match self {
+            SimFailure::Unbuildable => "unbuildable",
+            SimFailure::NoPc => "no_pc",
+            SimFailure::NoSave => "no_save",
+            SimFailure::OffPlan => "off_plan",
+        }
+    }
+}
+
+/// How a plan did over random sequences, from [`QBFinder::simulate`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SimResult {
+    pub runs: usize,
+    /// Runs meeting each tier of the policy first.
+    pub tiers: Vec<usize>,
+    pub unbuildable: usize,
+    pub no_pc: usize,
+    pub no_save: usize,
+    pub off_plan: usize,
+    /// The first few failing runs, as build and solve pieces.
+    pub examples: Vec<(SimFailure, String, String)>,
+}
+
+/// Failing runs [`SimResult::examples`] keeps.
+const EXAMPLES_KEPT: usize = 20;
+
+/// z for a 95% confidence interval.
+const Z_95: f64 = 1.959964;
+
+/// 95% Wilson score interval for `hits` out of `runs`.
+pub fn wilson_interval(hits: usize, runs: usize) -> (f64, f64) {
+    if runs == 0 {
+        return (0.0, 1.0);
+    }
+    let n = runs as f64;
+    let p = hits as f64 / n;
+    let z2 = Z_95 * Z_95;
+    let centre = (p + z2 / (2.0 * n)) / (1.0 + z2 / n);
+    let spread = Z_95 * (p * (1.0 - p) / n + z2 / (4.0 * n * n)).sqrt() / (1.0 + z2 / n);
+    ((centre - spread).max(0.0), (centre + spread).min(1.0))
+}
+
+impl SimResult {
+    /// Runs meeting any tier.
+    pub fn wins(&self) -> usize {
+        self.tiers.iter().sum()
+    }
+
+    /// Share of runs meeting any tier, from 0 to 1.
+    pub fn probability(&self) -> f64 {
+        if self.runs == 0 {
+            0.0
+        } else {
+            self.wins() as f64 / self.runs as f64
+        }
+    }
+
+    /// 95% confidence interval of [`Self::probability`].
+    pub fn interval(&self) -> (f64, f64) {
+        wilson_interval(self.wins(), self.runs)
+    }
+
+    /// Share of runs meeting each tier first, with its 95% confidence interval.
+    pub fn tier_rates(&self) -> Vec<(f64, (f64, f64))> {
+        self.tiers
+            .iter()
+            .map(|&n| {
+                let rate = if self.runs == 0 {
+                    0.0
+                } else {
+                    n as f64 / self.runs as f64
+                };
+                (rate, wilson_interval(n, self.runs))
+            })
+            .collect()
+    }
+
+    /// Runs failing for each cause.
+    pub fn failures(&self) -> [(SimFailure, usize); 4] {
+        [
+            (SimFailure::Unbuildable, self.unbuildable),
+            (SimFailure::NoPc, self.no_pc),
+            (SimFailure::NoSave, self.no_save),
+            (SimFailure::OffPlan, self.off_plan),
+        ]
+    }
+}
+
+/// How one run went: the tier met first, or why none was.
+type RunOutcome = Result<usize, (SimFailure, String, String)>;
+
+impl QBFinder {
+    /// A plan building `setups` in order, each with the solutions of its first minimal set.
+    pub fn sim_plan(&self, setups: &[Setup], solve_queue: &str, saves: &SavePolicy) -> SimPlan {
+        // each setup's minimal sets report progress of their own
+        let mut quiet = self.clone();
+        quiet.task = self.task.quiet();
+        let setups = setups
+            .iter()
+            .map(|setup| {
+                let pattern = setup.solve_pattern(solve_queue);
+                let universe = expand_pattern(&pattern).into_iter().collect();
+                let (solves, sets, _) =
+                    quiet.all_min_sets(&setup.board, &pattern, &universe, saves);
+                PlannedSetup {
+                    setup: setup.clone(),
+                    solves: sets
+                        .first()
+                        .map(|set| set.iter().map(|&i| solves[i].clone()).collect())
+                        .unwrap_or_default(),
+                }
+            })
+            .collect();
+        SimPlan { setups }
+    }
+
+    /// A plan building the setups of the best combination from
+    /// [`QBFinder::build_minimals`], weighted by saves.
+    pub fn best_sim_plan(
+        &self,
+        build_queue: &str,
+        start: &QueueStart,
+        solve_queue: &str,
+        saves: &SavePolicy,
+    ) -> SimPlan {
+        let minimals = self.build_minimals(build_queue, start, solve_queue, saves, true);
+        let setups: Vec<Setup> = minimals
+            .sets
+            .first()
+            .map(|set| set.iter().map(|&i| minimals.setups[i].0.clone()).collect())
+            .unwrap_or_default();
+        self.sim_plan(&setups, solve_queue, saves)
+    }
+
+    /// Plays `runs` random sequences of a build queue of `build_queue` after `start` followed
+    /// by a solve queue from `solves` through `plan`, and counts how often the PC meets each
+    /// tier of `saves`. Build queues are all as likely whatever `solves` is. Run `i` draws from
+    /// `seed` and `i` alone, so results don't depend on the thread count.
+    #[allow(clippy::too_many_arguments)]
+    pub fn simulate(
+        &self,
+        build_queue: &str,
+        start: &QueueStart,
+        solves: SolveSource,
+        saves: &SavePolicy,
+        plan: &SimPlan,
+        runs: usize,
+        seed: u64,
+    ) -> SimResult {
+        let build_pattern = start.apply(build_queue);
+        let p_count = self
+            .pc_piece_count()
+            .saturating_sub(build_len(start, &build_pattern));
+        let orders: Vec<FxHashSet<Queue>> = plan
+            .setups
+            .iter()
+            .map(|planned| {
+                let (in_order, with_hold) = self.build_orders(&planned.setup);
+                if self.hold { with_hold } else { in_order }
+            })
+            .collect();
+        let known: Vec<FxHashSet<&BrokenBoard>> = plan
+            .setups
+            .iter()
+            .map(|planned| planned.solves.iter().collect())
+            .collect();
+
+        let done = AtomicUsize::new(0);
+        self.task.report(0, runs);
+        let outcomes: Vec<Option<RunOutcome>> = (0..runs)
+            .into_par_iter()
+            .map(|run| {
+                if self.task.is_cancelled() {
+                    return None;
+                }
+                let mut rng = Rng::new(mix(seed ^ mix(run as u64)));
+                let build: Vec<Shape> = start
+                    .hold
+                    .into_iter()
+                    .chain(sample_pattern(&build_pattern, &mut rng))
+                    .collect();
+                let solve = match solves {
+                    SolveSource::Pattern(pattern) => sample_pattern(pattern, &mut rng),
+                    SolveSource::Dealt { randomizer, seen } => {
+                        let seen: Vec<Shape> =
+                            seen.chars().filter_map(parse_shape).take(p_count).collect();
+                        let dealt: Vec<Shape> = start
+                            .bag_used
+                            .iter()
+                            .chain(&build[start.hold.iter().count()..])
+                            .chain(&seen)
+                            .copied()
+                            .collect();
+                        let rest = randomizer.sample(&dealt, p_count - seen.len(), &mut rng);
+                        seen.into_iter().chain(rest).collect()
+                    }
+                };
+                let outcome = self.simulate_run(&build, &solve, saves, plan, &orders, &known);
+                let done = done.fetch_add(1, Ordering::Relaxed) + 1;
+                if done.is_multiple_of(64) || done == runs {
+                    self.task.report(done, runs);
+                }
+                Some(outcome)
+            })
+            .collect();
+
+        let mut res = SimResult {
+            tiers: vec![0; saves.tiers.len()],
+            ..SimResult::default()
+        };
+        for outcome in outcomes.into_iter().flatten() {
+            res.runs += 1;
+            match outcome {
+                Ok(tier) => res.tiers[tier] += 1,
+                Err((cause, build, solve)) => {
+                    *match cause {
+                        SimFailure::Unbuildable => &mut res.unbuildable,
+                        SimFailure::NoPc => &mut res.no_pc,
+                        SimFailure::NoSave => &mut res.no_save,
+                        SimFailure::OffPlan => &mut res.off_plan,
+                    } += 1;
+                    if res.examples.len() < EXAMPLES_KEPT {
+                        res.examples.push((cause, build, solve));
+                    }
+                }
+            }
+        }
+        res
+    }
+
+    fn simulate_run(
+        &self,
+        build: &[Shape],
+        solve: &[Shape],
+        saves: &SavePolicy,
+        plan: &SimPlan,
+        orders: &[FxHashSet<Queue>],
+        known: &[FxHashSet<&BrokenBoard>],
+    ) -> RunOutcome {
+        let names = |shapes: &[Shape]| shapes.iter().map(|s| s.name()).collect::<String>();
+        let fail = |cause| Err((cause, names(build), names(solve)));
+
+        let Some(i) = plan
+            .setups
+            .iter()
+            .zip(orders)
+            .position(|(planned, orders)| {
+                let prefix: Queue = build.iter().copied().take(planned.setup.drawn()).collect();
+                orders.contains(&prefix)
+            })
+        else {
+            return fail(SimFailure::Unbuildable);
+        };
+        let setup = &plan.setups[i].setup;
+        let board = BrokenBoard::from_garbage(setup.board.to_broken_bitboard().0);
+        let p_count = self.pc_piece_count().saturating_sub(build.len());
+        let bags: Vec<Bag> = setup
+            .held
+            .into_iter()
+            .chain(build.iter().copied().skip(setup.drawn()))
+            .chain(solve.iter().copied().take(p_count))
+            .map(|s| Bag::new(&[s], 1))
+            .collect();
+
+        let mut any_pc = false;
+        for (tier_index, tier) in saves.tiers.iter().enumerate() {
+            let solves = self.tier_solves(&board, &bags, tier);
+            any_pc |= !solves.is_empty();
+            if solves
+                .iter()
+                .any(|solve| known[i].is_empty() || known[i].contains(solve))
+            {
+                return Ok(tier_index);
+            }
+        }
+        if any_pc {
+            fail(SimFailure::OffPlan)
+        } else if self.meets_tier(&board, &bags, &SaveTier::Anything) {
+            fail(SimFailure::NoSave)
+        } else {
+            fail(SimFailure::NoPc)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use itertools::Itertools;
+
+    use super::*;
+
+    #[test]
+    fn same_seed_same_sequence() {
+        let sequence = |seed| {
+            let mut rng = Rng::new(seed);
+            (0..8).map(|_| rng.next_u64()).collect::<Vec<_>>()
+        };
+        assert_eq!(sequence(1), sequence(1));
+        assert_ne!(sequence(1), sequence(2));
+
+        let mut rng = Rng::new(7);
+        assert!((0..1000).all(|_| rng.below(7) < 7));
+        let mut a = Shape::ALL;
+        let mut b = Shape::ALL;
+        Rng::new(3).shuffle(&mut a);
+        Rng::new(3).shuffle(&mut b);
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn interval_bounds() {
+        assert_eq!(wilson_interval(0, 0), (0.0, 1.0));
+        for (hits, runs) in [(0, 10), (5, 10), (10, 10), (1, 1000), (999, 1000)] {
+            let p = hits as f64 / runs as f64;
+            let (low, high) = wilson_interval(hits, runs);
+            // within rounding of the share of hits
+            assert!(0.0 <= low && low <= p + 1e-12, "{hits}/{runs}");
+            assert!(p - 1e-12 <= high && high <= 1.0, "{hits}/{runs}");
+        }
+        // more runs narrow the interval
+        let (low, high) = wilson_interval(50, 100);
+        let (low_more, high_more) = wilson_interval(5000, 10000);
+        assert!(high_more - low_more < high - low);
+    }
+
+    #[test]
+    fn samples_follow_pattern() {
+        let mut rng = Rng::new(0);
+        for _ in 0..100 {
+            let queue = sample_pattern("T,IJLOSZp3", &mut rng);
+            assert_eq!(queue.len(), 4);
+            assert_eq!(queue[0], Shape::T);
+            assert!(queue[1..].iter().all_unique());
+            assert!(!queue[1..].contains(&Shape::T));
+        }
+    }
+}
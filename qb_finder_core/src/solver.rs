@@ -1,3 +1,9 @@
+#[cfg(feature = "parallel")]
+use std::collections::hash_map::Entry;
+use std::hash::Hash;
+
+#[cfg(feature = "parallel")]
+use rayon::prelude::{IntoParallelRefIterator, ParallelIterator};
 use rustc_hash::{FxHashMap, FxHashSet};
 use smallvec::SmallVec;
 
@@ -7,70 +13,133 @@ use srs_4l::{
     vector::Placements,
 };
 
-use crate::queue::{Bag, QueueState};
+use crate::queue::{BIT_BAG_SIZE, Bag, BitQueues, QueueBits, QueueSet, QueueState};
+
+/// Queue states before the first piece is placed, and how many pieces of the bags that took.
+fn init_queues<S: QueueSet>(bags: &[(S::Bag, u8)], hold: Option<Shape>) -> (S, usize) {
+    match hold {
+        Some(shape) => (S::held(shape), 0),
+        None => (S::init_hold(&bags.first().unwrap().0), 1),
+    }
+}
+
+type ScanStage<S> = FxHashMap<Board, (S, SmallVec<[Board; 6]>)>;
+
+fn merge_scanned<S: QueueSet>(
+    (queues, preds): &mut (S, SmallVec<[Board; 6]>),
+    (other_queues, other_preds): (S, SmallVec<[Board; 6]>),
+) {
+    queues.union(&other_queues);
+    for pred in other_preds {
+        if !preds.contains(&pred) {
+            preds.push(pred);
+        }
+    }
+}
+
+/// Builds the next stage by running `step` on every entry of `prev`.
+#[cfg(not(feature = "parallel"))]
+fn expand<K, V, NK: Eq + Hash, NV>(
+    prev: &FxHashMap<K, V>,
+    step: impl Fn(&mut FxHashMap<NK, NV>, &K, &V),
+    _merge: impl Fn(&mut NV, NV),
+) -> FxHashMap<NK, NV> {
+    let mut next = FxHashMap::with_capacity_and_hasher(prev.len(), Default::default());
+    for (key, value) in prev {
+        step(&mut next, key, value);
+    }
+    next
+}
 
-type ScanStage = FxHashMap<Board, (SmallVec<[QueueState; 7]>, SmallVec<[Board; 6]>)>;
+/// Builds the next stage by running `step` on every entry of `prev` across threads, then
+/// combining entries that several threads reached with `merge`.
+#[cfg(feature = "parallel")]
+fn expand<K: Sync, V: Sync, NK: Eq + Hash + Send, NV: Send>(
+    prev: &FxHashMap<K, V>,
+    step: impl Fn(&mut FxHashMap<NK, NV>, &K, &V) + Sync,
+    merge: impl Fn(&mut NV, NV) + Sync,
+) -> FxHashMap<NK, NV> {
+    prev.par_iter()
+        .fold(FxHashMap::default, |mut next, (key, value)| {
+            step(&mut next, key, value);
+            next
+        })
+        .reduce(FxHashMap::default, |a, b| {
+            let (mut into, from) = if a.len() >= b.len() { (a, b) } else { (b, a) };
+            for (key, value) in from {
+                match into.entry(key) {
+                    Entry::Occupied(mut entry) => merge(entry.get_mut(), value),
+                    Entry::Vacant(entry) => {
+                        entry.insert(value);
+                    }
+                }
+            }
+            into
+        })
+}
 
-fn scan(
+#[allow(clippy::too_many_arguments)]
+fn scan<S: QueueSet>(
     legal_boards: &FxHashSet<Board>,
     start: Board,
-    bags: &[Bag],
+    bags: &[(S::Bag, u8)],
+    hold: Option<Shape>,
     can_hold: bool,
     place_last: bool,
     physics: Physics,
     save: Option<Shape>,
-) -> Vec<ScanStage> {
+) -> Vec<ScanStage<S>> {
     let mut stages = Vec::new();
 
-    let mut prev: ScanStage = FxHashMap::default();
-    prev.insert(start, (bags.first().unwrap().init_hold(), SmallVec::new()));
+    let (queues, drawn) = init_queues::<S>(bags, hold);
+    let mut prev: ScanStage<S> = FxHashMap::default();
+    prev.insert(start, (queues, SmallVec::new()));
 
     for (bag, i) in bags
         .iter()
-        .flat_map(|b| (0..b.count).map(move |i| (b, i)))
-        .skip(1)
+        .flat_map(|(b, count)| (0..*count).map(move |i| (b, i)))
+        .skip(drawn)
     {
-        let mut next: ScanStage =
-            FxHashMap::with_capacity_and_hasher(prev.len(), Default::default());
-
-        for (&old_board, (old_queues, _preds)) in prev.iter() {
-            for shape in Shape::ALL {
-                let is_first = i == 0;
-                let new_queues = bag.take(old_queues, shape, is_first, can_hold);
-
-                if new_queues.is_empty() {
-                    continue;
-                }
-
-                for (_, new_board) in Placements::place(old_board, shape, physics).canonical() {
-                    if !legal_boards.is_empty() && !legal_boards.contains(&new_board) {
+        let next = expand(
+            &prev,
+            |next: &mut ScanStage<S>, &old_board, (old_queues, _preds)| {
+                for shape in Shape::ALL {
+                    let is_first = i == 0;
+                    let new_queues = old_queues.take(bag, shape, is_first, can_hold);
+
+                    if new_queues.is_empty() {
                         continue;
                     }
 
-                    let (queues, preds) = next.entry(new_board).or_default();
-                    if !preds.contains(&old_board) {
-                        preds.push(old_board);
-                    }
-                    for &queue in &new_queues {
-                        if !queues.contains(&queue) {
-                            queues.push(queue);
+                    for (_, new_board) in Placements::place(old_board, shape, physics).canonical() {
+                        if !legal_boards.is_empty() && !legal_boards.contains(&new_board) {
+                            continue;
+                        }
+
+                        let (queues, preds) = next.entry(new_board).or_default();
+                        if !preds.contains(&old_board) {
+                            preds.push(old_board);
                         }
+                        queues.union(&new_queues);
                     }
                 }
-            }
-        }
+            },
+            merge_scanned,
+        );
 
         stages.push(prev);
         prev = next;
     }
 
     if place_last {
-        let mut next: ScanStage =
-            FxHashMap::with_capacity_and_hasher(prev.len(), Default::default());
+        let next = expand(
+            &prev,
+            |next: &mut ScanStage<S>, &old_board, (old_queues, _preds)| {
+                for shape in Shape::ALL {
+                    if !old_queues.holds(shape) {
+                        continue;
+                    }
 
-        for (&old_board, (old_queues, _preds)) in prev.iter() {
-            for shape in Shape::ALL {
-                if old_queues.iter().any(|queue| queue.hold() == Some(shape)) {
                     for (_, new_board) in Placements::place(old_board, shape, physics).canonical() {
                         if !legal_boards.is_empty() && !legal_boards.contains(&new_board) {
                             continue;
@@ -82,22 +151,23 @@ fn scan(
                         }
                     }
                 }
-            }
-        }
+            },
+            merge_scanned,
+        );
 
         stages.push(prev);
         prev = next;
     }
 
     if !place_last && let Some(s) = save {
-        prev.retain(|_, (old_queues, _)| old_queues.iter().any(|q| q.hold() == Some(s)));
+        prev.retain(|_, (old_queues, _)| old_queues.holds(s));
     }
 
     stages.push(prev);
     stages
 }
 
-fn cull(scanned: &[ScanStage]) -> FxHashSet<Board> {
+fn cull<S>(scanned: &[ScanStage<S>]) -> FxHashSet<Board> {
     let mut culled = FxHashSet::with_capacity_and_hasher(scanned.len(), Default::default());
 
     let mut iter = scanned.iter().rev();
@@ -120,85 +190,135 @@ fn cull(scanned: &[ScanStage]) -> FxHashSet<Board> {
     culled
 }
 
-fn place(
+#[allow(clippy::too_many_arguments)]
+fn place<S: QueueSet>(
     culled: &FxHashSet<Board>,
     start: BrokenBoard,
-    bags: &[Bag],
+    bags: &[(S::Bag, u8)],
+    hold: Option<Shape>,
     can_hold: bool,
     place_last: bool,
     physics: Physics,
     save: Option<Shape>,
-) -> FxHashMap<BrokenBoard, SmallVec<[QueueState; 7]>> {
+) -> FxHashMap<BrokenBoard, S> {
+    let (queues, drawn) = init_queues::<S>(bags, hold);
     let mut prev = FxHashMap::default();
-    prev.insert(start, bags.first().unwrap().init_hold());
+    prev.insert(start, queues);
 
     for (bag, i) in bags
         .iter()
-        .flat_map(|b| (0..b.count).map(move |i| (b, i)))
-        .skip(1)
+        .flat_map(|(b, count)| (0..*count).map(move |i| (b, i)))
+        .skip(drawn)
     {
-        let mut next: FxHashMap<BrokenBoard, SmallVec<[QueueState; 7]>> =
-            FxHashMap::with_capacity_and_hasher(prev.len(), Default::default());
-
-        for (old_board, old_queues) in prev.iter() {
-            for shape in Shape::ALL {
-                let is_first = i == 0;
-                let new_queues = bag.take(old_queues, shape, is_first, can_hold);
-
-                if new_queues.is_empty() {
-                    continue;
-                }
+        prev = expand(
+            &prev,
+            |next: &mut FxHashMap<BrokenBoard, S>, old_board, old_queues| {
+                for shape in Shape::ALL {
+                    let is_first = i == 0;
+                    let new_queues = old_queues.take(bag, shape, is_first, can_hold);
+
+                    if new_queues.is_empty() {
+                        continue;
+                    }
 
-                for (piece, new_board) in
-                    Placements::place(old_board.board, shape, physics).canonical()
-                {
-                    if culled.contains(&new_board) {
-                        let queues = next.entry(old_board.place(piece)).or_default();
-                        for &queue in &new_queues {
-                            if !queues.contains(&queue) {
-                                queues.push(queue);
-                            }
+                    for (piece, new_board) in
+                        Placements::place(old_board.board, shape, physics).canonical()
+                    {
+                        if culled.contains(&new_board) {
+                            next.entry(old_board.place(piece))
+                                .or_default()
+                                .union(&new_queues);
                         }
                     }
                 }
-            }
-        }
-
-        prev = next;
+            },
+            |queues, other| queues.union(&other),
+        );
     }
 
     if place_last {
-        let mut next: FxHashMap<BrokenBoard, SmallVec<[QueueState; 7]>> =
-            FxHashMap::with_capacity_and_hasher(prev.len(), Default::default());
+        prev = expand(
+            &prev,
+            |next: &mut FxHashMap<BrokenBoard, S>, old_board, old_queues| {
+                for shape in Shape::ALL {
+                    if !old_queues.holds(shape) {
+                        continue;
+                    }
 
-        for (old_board, old_queues) in prev.iter() {
-            for shape in Shape::ALL {
-                if old_queues.iter().any(|queue| queue.hold() == Some(shape)) {
                     for (piece, new_board) in
                         Placements::place(old_board.board, shape, physics).canonical()
                     {
                         if culled.contains(&new_board) {
-                            next.insert(old_board.place(piece), SmallVec::new());
+                            next.insert(old_board.place(piece), S::default());
                         }
                     }
                 }
-            }
-        }
-
-        prev = next;
+            },
+            |_, _| {},
+        );
     }
 
     if let Some(s) = save {
-        prev.retain(|_, queues| queues.iter().any(|q| q.hold() == Some(s)));
+        prev.retain(|_, queues| queues.holds(s));
     }
 
     prev
 }
 
+/// Every way to place the pieces of `bags` on `start`, keeping `save` in hold if given.
+/// `hold` is a piece already held before the first bag, which should then hold only the
+/// pieces still to be drawn from it.
 pub fn compute(
     legal_boards: &FxHashSet<Board>,
     start: &BrokenBoard,
     bags: &[Bag],
+    hold: Option<Shape>,
+    can_hold: bool,
+    physics: Physics,
+    save: Option<Shape>,
+) -> Vec<BrokenBoard> {
+    // queue states stay as small as the largest bag allows
+    let largest = bags.iter().map(Bag::size).max().unwrap_or(0);
+    if largest <= BIT_BAG_SIZE {
+        compute_with::<BitQueues>(legal_boards, start, bags, hold, can_hold, physics, save)
+    } else if largest <= u16::PIECE_BITS {
+        compute_with::<SmallVec<[QueueState<u16>; 7]>>(
+            legal_boards,
+            start,
+            bags,
+            hold,
+            can_hold,
+            physics,
+            save,
+        )
+    } else if largest <= u32::PIECE_BITS {
+        compute_with::<SmallVec<[QueueState<u32>; 7]>>(
+            legal_boards,
+            start,
+            bags,
+            hold,
+            can_hold,
+            physics,
+            save,
+        )
+    } else {
+        compute_with::<SmallVec<[QueueState<u64>; 7]>>(
+            legal_boards,
+            start,
+            bags,
+            hold,
+            can_hold,
+            physics,
+            save,
+        )
+    }
+}
+
+fn compute_with<S: QueueSet>(
+    legal_boards: &FxHashSet<Board>,
+    start: &BrokenBoard,
+    bags: &[Bag],
+    hold: Option<Shape>,
     can_hold: bool,
     physics: Physics,
     save: Option<Shape>,
@@ -207,26 +327,30 @@ pub fn compute(
         return vec![start.clone()];
     }
 
-    let has_save = save.is_some_and(|s| bags.iter().any(|b| b.contains(s)));
+    let prepared: Vec<(S::Bag, u8)> = bags.iter().map(|b| (S::prepare(b), b.count)).collect();
+
+    let has_save = save.is_some_and(|s| hold == Some(s) || bags.iter().any(|b| b.contains(s)));
 
-    let piece_count: usize = bags.iter().map(|b| b.count as usize).sum();
+    let piece_count = bags.iter().map(|b| b.count as usize).sum::<usize>() + hold.iter().count();
     let new_mino_count = piece_count as u32 * 4;
     let place_last = !has_save && start.board.0.count_ones() + new_mino_count <= 40;
 
-    let scanned = scan(
+    let scanned = scan::<S>(
         legal_boards,
         start.board,
-        bags,
+        &prepared,
+        hold,
         can_hold,
         place_last,
         physics,
         save,
     );
     let culled = cull(&scanned);
-    let mut placed = place(
+    let mut placed = place::<S>(
         &culled,
         start.clone(),
-        bags,
+        &prepared,
+        hold,
         can_hold,
         place_last,
         physics,
@@ -265,3 +389,21 @@ pub fn print(board: &BrokenBoard, to: &mut String) {
         }
     }
 }
+
+/// Reads a board in the format written by [`print`] back into garbage bits.
+pub fn parse_garbage(field: &str) -> Option<u64> {
+    let cells: Vec<char> = field.chars().filter(|c| !c.is_whitespace()).collect();
+    if cells.len() > 40 || !cells.len().is_multiple_of(10) {
+        return None;
+    }
+
+    let rows = cells.len() / 10;
+    let mut garbage = 0;
+    for (i, &c) in cells.iter().enumerate() {
+        let row = rows - 1 - i / 10;
+        if c != '_' {
+            garbage |= 1 << (row * 10 + i % 10);
+        }
+    }
+    Some(garbage)
+}
@@ -0,0 +1,236 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use crate::{
+    build::Setup,
+    parse_shape,
+    saves::{QueueSaves, SaveScore},
+};
+use rustc_hash::{FxHashMap, FxHashSet};
+use srs_4l::{
+    base64::{base64_decode, base64_encode},
+    brokenboard::BrokenBoard,
+    gameplay::Board,
+};
+
+/// Bumped whenever a stored format or what goes into a key changes.
+const FORMAT_VERSION: u32 = 6;
+
+/// Numbers the temporary files of [`ResultStore::save`], so that threads writing the same key
+/// at once don't write to the same file.
+static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// 64-bit FNV-1a, which unlike the std hashers is stable across builds.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+pub(crate) fn mix(mut x: u64) -> u64 {
+    x ^= x >> 33;
+    x = x.wrapping_mul(0xff51afd7ed558ccd);
+    x ^= x >> 33;
+    x = x.wrapping_mul(0xc4ceb9fe1a85ec53);
+    x ^ x >> 33
+}
+
+/// Checksum of a legal board set that does not depend on iteration order.
+pub fn boards_checksum(boards: &FxHashSet<Board>) -> u64 {
+    boards.iter().fold(boards.len() as u64, |sum, board| {
+        sum.wrapping_add(mix(board.0))
+    })
+}
+
+/// Where a query is stored: the hash of its canonical description names the file, and the
+/// description itself heads the file to tell apart queries whose hashes collide.
+pub(crate) struct StoreKey {
+    name: String,
+    canonical: String,
+}
+
+/// The canonical description on one line.
+fn escape(canonical: &str) -> String {
+    canonical.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+/// Results of finished analyses kept as one file per query in a directory, so they can be
+/// shared between runs and frontends.
+pub struct ResultStore {
+    dir: PathBuf,
+}
+
+impl ResultStore {
+    pub fn open(dir: impl AsRef<Path>) -> io::Result<ResultStore> {
+        fs::create_dir_all(&dir)?;
+        Ok(ResultStore {
+            dir: dir.as_ref().to_owned(),
+        })
+    }
+
+    /// Key for a query described by `parts`, which must already include the finder's config.
+    pub(crate) fn key(parts: &[&str]) -> StoreKey {
+        let mut canonical = format!("v{FORMAT_VERSION}");
+        for part in parts {
+            canonical.push('\n');
+            canonical.push_str(part);
+        }
+        StoreKey {
+            name: format!("{:016x}", fnv1a(canonical.as_bytes())),
+            canonical,
+        }
+    }
+
+    /// The stored result for `key`, or `None` if there is none or it belongs to another query
+    /// with the same hash.
+    fn load(&self, key: &StoreKey) -> Option<String> {
+        let contents = fs::read_to_string(self.dir.join(&key.name)).ok()?;
+        let (header, rest) = contents.split_once('\n')?;
+        (header.strip_prefix("k ")? == escape(&key.canonical)).then(|| rest.to_owned())
+    }
+
+    /// Writes to a temporary file first so readers never see half a result.
+    fn save(&self, key: &StoreKey, contents: &str) {
+        let tmp = self.dir.join(format!(
+            "{}.{}.{}.tmp",
+            key.name,
+            std::process::id(),
+            TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let contents = format!("k {}\n{contents}", escape(&key.canonical));
+        if fs::write(&tmp, contents).is_ok() && fs::rename(&tmp, self.dir.join(&key.name)).is_err()
+        {
+            let _ = fs::remove_file(&tmp);
+        }
+    }
+
+    pub(crate) fn load_find(&self, key: &StoreKey) -> Option<(Vec<Setup>, SaveScore)> {
+        let contents = self.load(key)?;
+        let mut setups = Vec::new();
+        let mut score = None;
+        for line in contents.lines() {
+            let (kind, rest) = line.split_once(' ').unwrap_or((line, ""));
+            match kind {
+                "n" => score = Some(SaveScore(parse_indices(rest)?)),
+                "s" => setups.push(Setup::decode(rest)?),
+                _ => return None,
+            }
+        }
+        Some((setups, score?))
+    }
+
+    pub(crate) fn save_find(&self, key: &StoreKey, (setups, score): &(Vec<Setup>, SaveScore)) {
+        let mut contents = String::new();
+        push_indices(&mut contents, "n", None, &score.0);
+        for setup in setups {
+            contents.push_str("s ");
+            contents.push_str(&setup.encode());
+            contents.push('\n');
+        }
+        self.save(key, &contents);
+    }
+
+    pub(crate) fn load_min_sets(&self, key: &StoreKey) -> Option<crate::SetupMinimals> {
+        let contents = self.load(key)?;
+        let mut solves = Vec::new();
+        let mut sets = Vec::new();
+        let mut equivalent = FxHashMap::default();
+        for line in contents.lines() {
+            let (kind, rest) = line.split_once(' ').unwrap_or((line, ""));
+            match kind {
+                "b" => solves.push(decode_board(rest)?),
+                "s" => sets.push(parse_indices(rest)?),
+                "e" => {
+                    let mut indices = parse_indices(rest)?;
+                    if indices.is_empty() {
+                        return None;
+                    }
+                    let solve = indices.remove(0);
+                    equivalent.insert(solve, indices);
+                }
+                _ => return None,
+            }
+        }
+        Some((solves, sets, equivalent))
+    }
+
+    pub(crate) fn save_min_sets(
+        &self,
+        key: &StoreKey,
+        (solves, sets, equivalent): &crate::SetupMinimals,
+    ) {
+        let mut contents = String::new();
+        for solve in solves {
+            push_board(&mut contents, solve);
+        }
+        for set in sets {
+            push_indices(&mut contents, "s", None, set);
+        }
+        for (solve, others) in equivalent {
+            push_indices(&mut contents, "e", Some(*solve), others);
+        }
+        self.save(key, &contents);
+    }
+
+    pub(crate) fn load_queue_saves(&self, key: &StoreKey) -> Option<Vec<QueueSaves>> {
+        let contents = self.load(key)?;
+        contents
+            .lines()
+            .map(|line| {
+                let mut parts = line.strip_prefix("q ")?.split(' ');
+                let queue = parts.next()?.to_owned();
+                let keepable = parts
+                    .next()?
+                    .chars()
+                    .filter(|&c| c != '-')
+                    .map(parse_shape)
+                    .collect::<Option<_>>()?;
+                let tier = match parts.next()? {
+                    "-" => None,
+                    tier => Some(tier.parse().ok()?),
+                };
+                Some(QueueSaves {
+                    queue,
+                    keepable,
+                    tier,
+                })
+            })
+            .collect()
+    }
+
+    pub(crate) fn save_queue_saves(&self, key: &StoreKey, queues: &[QueueSaves]) {
+        let mut contents = String::new();
+        for q in queues {
+            let keepable: String = q.keepable.iter().map(|s| s.name()).collect();
+            let tier = q.tier.map_or("-".to_owned(), |t| t.to_string());
+            contents.push_str(&format!("q {} -{keepable} {tier}\n", q.queue));
+        }
+        self.save(key, &contents);
+    }
+}
+
+fn decode_board(encoded: &str) -> Option<BrokenBoard> {
+    BrokenBoard::decode(&base64_decode(encoded)?)
+}
+
+fn push_board(to: &mut String, board: &BrokenBoard) {
+    to.push_str("b ");
+    base64_encode(&board.encode(), to);
+    to.push('\n');
+}
+
+fn parse_indices(line: &str) -> Option<Vec<usize>> {
+    line.split_whitespace().map(|i| i.parse().ok()).collect()
+}
+
+fn push_indices(to: &mut String, kind: &str, first: Option<usize>, rest: &[usize]) {
+    to.push_str(kind);
+    for i in first.iter().chain(rest) {
+        to.push(' ');
+        to.push_str(&i.to_string());
+    }
+    to.push('\n');
+}
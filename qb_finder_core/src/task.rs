@@ -0,0 +1,55 @@
+use std::sync::{
+    Arc,
+    atomic::{AtomicBool, Ordering},
+};
+
+type ProgressFn = dyn Fn(usize, usize) -> bool + Send + Sync;
+
+/// Lets a caller follow a long query and stop it early. Clones share the same state.
+///
+/// A cancelled query returns whatever it had found so far, which is not stored or cached.
+#[derive(Clone, Default)]
+pub struct Task {
+    cancelled: Arc<AtomicBool>,
+    progress: Option<Arc<ProgressFn>>,
+}
+
+impl Task {
+    pub fn new() -> Task {
+        Task::default()
+    }
+
+    /// Calls `progress` with the items evaluated so far and the total. Returning `false`
+    /// cancels the task.
+    pub fn with_progress(
+        mut self,
+        progress: impl Fn(usize, usize) -> bool + Send + Sync + 'static,
+    ) -> Task {
+        self.progress = Some(Arc::new(progress));
+        self
+    }
+
+    /// A task cancelled along with this one that reports no progress, for nested queries.
+    pub fn quiet(&self) -> Task {
+        Task {
+            cancelled: self.cancelled.clone(),
+            progress: None,
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    pub fn report(&self, done: usize, total: usize) {
+        if let Some(progress) = &self.progress
+            && !progress(done, total)
+        {
+            self.cancel();
+        }
+    }
+}
@@ -0,0 +1,598 @@
+use itertools::Itertools;
+use qb_finder_core::{
+    QBFinder,
+    build::{BuildCover, BuildMode, Setup},
+    expand_pattern, fumen, parse_group, parse_shape,
+    queue::QueueStart,
+    randomizer::{Randomizer, SolveSource},
+    route::RoutePlan,
+    saves::SavePolicy,
+    solver,
+};
+use rustc_hash::{FxHashMap, FxHashSet};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use serde_json::{Value, json};
+use srs_4l::{base64::base64_encode, brokenboard::BrokenBoard};
+
+pub enum ApiError {
+    NotFound,
+    BadRequest(String),
+}
+
+#[derive(Serialize)]
+struct BoardJson {
+    board: String,
+    fumen: String,
+    setup: String,
+}
+
+impl From<&BrokenBoard> for BoardJson {
+    fn from(board: &BrokenBoard) -> BoardJson {
+        let mut board_str = String::with_capacity(40);
+        solver::print(board, &mut board_str);
+        let mut setup = String::new();
+        base64_encode(&board.encode(), &mut setup);
+        BoardJson {
+            board: board_str,
+            fumen: fumen::encode(board),
+            setup,
+        }
+    }
+}
+
+fn boards_json(boards: &[BrokenBoard]) -> Vec<BoardJson> {
+    boards.iter().map(BoardJson::from).collect()
+}
+
+/// A board from `find`, with the build pieces it leaves for the solve.
+#[derive(Serialize)]
+struct SetupJson {
+    #[serde(flatten)]
+    board: BoardJson,
+    held: Option<&'static str>,
+    unplaced: String,
+    queue_count: usize,
+    build_cover: BuildCoverJson,
+    /// Chance of meeting any save tier when a randomizer deals the solve queues.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    probability: Option<f64>,
+}
+
+#[derive(Serialize)]
+struct BuildCoverJson {
+    total: usize,
+    with_hold: usize,
+    without_hold: usize,
+    missing: Vec<String>,
+}
+
+fn setups_json(setups: Vec<(Setup, BuildCover, Option<f64>)>, solve_queue: &str) -> Vec<SetupJson> {
+    setups
+        .into_iter()
+        .map(|(setup, cover, probability)| SetupJson {
+            board: BoardJson {
+                setup: setup.encode(),
+                ..BoardJson::from(&setup.board)
+            },
+            held: setup.held.map(|s| s.name()),
+            queue_count: expand_pattern(&setup.solve_pattern(solve_queue)).len(),
+            unplaced: setup.unplaced,
+            build_cover: BuildCoverJson {
+                total: cover.total,
+                with_hold: cover.with_hold,
+                without_hold: cover.without_hold,
+                missing: cover.missing,
+            },
+            probability,
+        })
+        .collect()
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct Options {
+    hold: Option<bool>,
+    skip_4p: bool,
+    /// Most build pieces a setup may leave unplaced, 1 if not given.
+    max_left: Option<usize>,
+    full_cover: bool,
+    /// `7bag`, `14bag`, `random` or `tgm` to deal the solve queues after the pieces of
+    /// `solve_queue`, rather than expanding it as a pattern.
+    randomizer: Option<String>,
+}
+
+/// Piece already held and pieces already drawn from the current bag.
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct StartJson {
+    held: Option<char>,
+    bag_used: String,
+}
+
+#[derive(Deserialize)]
+struct FindRequest {
+    build_queue: String,
+    #[serde(default)]
+    build_save: Option<char>,
+    solve_queue: String,
+    #[serde(default = "default_saves")]
+    saves: String,
+    #[serde(default = "default_min_saves")]
+    min_saves: usize,
+    /// `"cover"` lists the setups most build queues can build first.
+    #[serde(default)]
+    sort: Option<String>,
+    #[serde(flatten)]
+    start: StartJson,
+    #[serde(flatten)]
+    options: Options,
+}
+
+#[derive(Deserialize)]
+struct ComputeRequest {
+    queue: String,
+    #[serde(default)]
+    save: Option<char>,
+    #[serde(default)]
+    garbage: u64,
+    #[serde(flatten)]
+    start: StartJson,
+    #[serde(flatten)]
+    options: Options,
+}
+
+#[derive(Deserialize)]
+struct MinimalsRequest {
+    setup: String,
+    pattern: String,
+    #[serde(default)]
+    saves: String,
+    #[serde(flatten)]
+    options: Options,
+}
+
+#[derive(Deserialize)]
+struct SavesStatsRequest {
+    setup: String,
+    solve_queue: String,
+    saves: String,
+    #[serde(flatten)]
+    options: Options,
+}
+
+#[derive(Deserialize)]
+struct BuildMinimalsRequest {
+    build_queue: String,
+    solve_queue: String,
+    #[serde(default = "default_saves")]
+    saves: String,
+    /// Only the smallest combination keeping the most queues, rather than all of them.
+    #[serde(default)]
+    by_saves: bool,
+    #[serde(flatten)]
+    start: StartJson,
+    #[serde(flatten)]
+    options: Options,
+}
+
+#[derive(Deserialize)]
+struct RouteRequest {
+    build_queue: String,
+    solve_queue: String,
+    #[serde(default = "default_saves")]
+    saves: String,
+    /// The setup to build from each build queue, encoded as `setup` in `find` results, or the
+    /// best setup for each if not given.
+    #[serde(default)]
+    plan: Option<FxHashMap<String, String>>,
+    #[serde(flatten)]
+    start: StartJson,
+    #[serde(flatten)]
+    options: Options,
+}
+
+#[derive(Deserialize)]
+struct SimulateRequest {
+    build_queue: String,
+    solve_queue: String,
+    #[serde(default = "default_saves")]
+    saves: String,
+    /// Setups to build in order, encoded as `setup` in `find` results, or the best build
+    /// minimals if not given.
+    #[serde(default)]
+    plan: Option<Vec<String>>,
+    #[serde(default = "default_runs")]
+    runs: usize,
+    #[serde(default)]
+    seed: u64,
+    #[serde(flatten)]
+    start: StartJson,
+    #[serde(flatten)]
+    options: Options,
+}
+
+#[derive(Deserialize)]
+struct BestsavesRequest {
+    fifth: String,
+    #[serde(default = "default_saves")]
+    saves: String,
+}
+
+fn default_saves() -> String {
+    "T".to_owned()
+}
+
+fn default_runs() -> usize {
+    10_000
+}
+
+/// Most runs one `simulate` request may ask for.
+const MAX_RUNS: usize = 1_000_000;
+
+fn default_min_saves() -> usize {
+    1
+}
+
+fn parse<T: DeserializeOwned>(body: &Value) -> Result<T, ApiError> {
+    T::deserialize(body).map_err(|e| ApiError::BadRequest(e.to_string()))
+}
+
+fn check_pattern(name: &str, pattern: &str) -> Result<String, ApiError> {
+    let pattern = pattern.to_uppercase();
+    match pattern
+        .lines()
+        .flat_map(|line| line.split(','))
+        .find(|group| !group.is_empty() && parse_group(group).is_none())
+    {
+        Some(group) => Err(ApiError::BadRequest(format!(
+            "Invalid group {group:?} in {name}"
+        ))),
+        None => Ok(pattern),
+    }
+}
+
+fn check_saves(saves: &str) -> Result<SavePolicy, ApiError> {
+    SavePolicy::parse(&saves.to_uppercase())
+        .ok_or_else(|| ApiError::BadRequest(format!("Invalid save policy {saves:?}")))
+}
+
+fn check_save(save: Option<char>) -> Result<Option<srs_4l::gameplay::Shape>, ApiError> {
+    save.map(|c| {
+        parse_shape(c.to_ascii_uppercase())
+            .ok_or_else(|| ApiError::BadRequest(format!("Invalid piece {c:?}")))
+    })
+    .transpose()
+}
+
+fn check_start(start: &StartJson) -> Result<QueueStart, ApiError> {
+    let bag_used = check_pattern("bag_used", &start.bag_used)?;
+    Ok(QueueStart {
+        hold: check_save(start.held)?,
+        bag_used: bag_used.chars().filter_map(parse_shape).collect(),
+    })
+}
+
+fn decode_setup(setup: &str) -> Result<BrokenBoard, ApiError> {
+    Ok(decode_plan_setup(setup)?.board)
+}
+
+/// A setup as `setup` in `find` results, with the build pieces it leaves.
+fn decode_plan_setup(setup: &str) -> Result<Setup, ApiError> {
+    Setup::decode(setup).ok_or_else(|| ApiError::BadRequest("Invalid setup".to_owned()))
+}
+
+fn check_randomizer(options: &Options) -> Result<Option<Randomizer>, ApiError> {
+    options
+        .randomizer
+        .as_deref()
+        .map(|name| {
+            Randomizer::parse(name)
+                .ok_or_else(|| ApiError::BadRequest(format!("Invalid randomizer {name:?}")))
+        })
+        .transpose()
+}
+
+/// `solve_queue` as a pattern, or the solve pieces already seen before `randomizer` deals the
+/// rest.
+fn solve_source(solve_queue: &str, randomizer: Option<Randomizer>) -> SolveSource<'_> {
+    match randomizer {
+        Some(randomizer) => SolveSource::Dealt {
+            randomizer,
+            seen: solve_queue,
+        },
+        None => SolveSource::Pattern(solve_queue),
+    }
+}
+
+fn configure(qbf: &QBFinder, options: &Options) -> QBFinder {
+    let mut qbf = qbf.clone();
+    if let Some(hold) = options.hold {
+        qbf.hold = hold;
+    }
+    let min_left = options.skip_4p as usize;
+    qbf.build_mode = BuildMode {
+        min_left,
+        max_left: options.max_left.unwrap_or(1).max(min_left),
+    };
+    qbf.full_cover = options.full_cover;
+    qbf
+}
+
+pub fn handle(qbf: &QBFinder, endpoint: &str, body: &Value) -> Result<Value, ApiError> {
+    match endpoint {
+        "find" => {
+            let req: FindRequest = parse(body)?;
+            let build_queue = check_pattern("build_queue", &req.build_queue)?;
+            let solve_queue = check_pattern("solve_queue", &req.solve_queue)?;
+            let saves = check_saves(&req.saves)?;
+            let by_cover = match req.sort.as_deref() {
+                None | Some("saves") => false,
+                Some("cover") => true,
+                Some(_) => {
+                    return Err(ApiError::BadRequest(
+                        "sort must be saves or cover".to_owned(),
+                    ));
+                }
+            };
+            let start = check_start(&req.start)?;
+            let qbf = configure(qbf, &req.options);
+            let dist = check_randomizer(&req.options)?
+                .map(|r| qbf.solve_distribution(r, &build_queue, &start, &solve_queue));
+            let solve_queue = dist.as_ref().map_or(solve_queue, |d| d.pattern());
+            let (setups, score) = qbf.find(
+                &build_queue,
+                &start,
+                check_save(req.build_save)?,
+                &solve_queue,
+                &saves,
+                req.min_saves,
+            );
+            let covers = qbf.build_covers(&setups, &build_queue, &start);
+            let mut setups: Vec<_> = setups
+                .into_iter()
+                .zip(covers)
+                .map(|(setup, cover)| {
+                    let probability = dist
+                        .as_ref()
+                        .map(|dist| qbf.setup_probabilities(&setup, dist, &saves).iter().sum());
+                    (setup, cover, probability)
+                })
+                .collect();
+            if by_cover {
+                setups.sort_by(|(_, a, _), (_, b, _)| b.cmp_cover(a));
+            }
+            Ok(json!({
+                "save_count": score.primary(),
+                "save_score": score.0,
+                "queue_count": expand_pattern(&solve_queue).len(),
+                "setups": setups_json(setups, &solve_queue),
+            }))
+        }
+        "compute" => {
+            let req: ComputeRequest = parse(body)?;
+            let queue = check_pattern("queue", &req.queue)?;
+            let solves = configure(qbf, &req.options).compute(
+                &queue,
+                &BrokenBoard::from_garbage(req.garbage),
+                &check_start(&req.start)?,
+                check_save(req.save)?,
+            );
+            Ok(json!({ "solves": boards_json(&solves) }))
+        }
+        "min_count" => {
+            let req: MinimalsRequest = parse(body)?;
+            let setup = decode_setup(&req.setup)?;
+            let pattern = check_pattern("pattern", &req.pattern)?;
+            let saves = check_saves(&req.saves)?;
+            let universe: FxHashSet<String> = expand_pattern(&pattern).into_iter().collect();
+            let min_count =
+                configure(qbf, &req.options).min_count(&setup, &pattern, &universe, &saves);
+            Ok(json!({ "min_count": min_count }))
+        }
+        "all_min_sets" => {
+            let req: MinimalsRequest = parse(body)?;
+            let setup = decode_setup(&req.setup)?;
+            let pattern = check_pattern("pattern", &req.pattern)?;
+            let saves = check_saves(&req.saves)?;
+            let universe: FxHashSet<String> = expand_pattern(&pattern).into_iter().collect();
+            let (solves, sets, equiv) =
+                configure(qbf, &req.options).all_min_sets(&setup, &pattern, &universe, &saves);
+            let equivalent: Vec<Vec<usize>> = equiv
+                .into_iter()
+                .sorted()
+                .map(|(solve, others)| std::iter::once(solve).chain(others).collect())
+                .collect();
+            Ok(json!({
+                "solves": boards_json(&solves),
+                "sets": sets,
+                "equivalent": equivalent,
+            }))
+        }
+        "saves_stats" => {
+            let req: SavesStatsRequest = parse(body)?;
+            let setup = decode_setup(&req.setup)?;
+            let solve_queue = check_pattern("solve_queue", &req.solve_queue)?;
+            let saves = check_saves(&req.saves)?;
+            let qbf = configure(qbf, &req.options);
+            // a randomizer deals as many pieces as the setup needs, counting the one kept
+            let dist = check_randomizer(&req.options)?.map(|r| {
+                let len = (40 - setup.board.0.count_ones() as usize) / 4 + qbf.hold as usize;
+                r.distribution_after(&[], &solve_queue, len)
+            });
+            let solve_queue = dist.as_ref().map_or(solve_queue, |d| d.pattern());
+            let mut stats = qbf
+                .saves_stats(&setup, &solve_queue, &saves)
+                .ok_or_else(|| ApiError::BadRequest("Invalid solve_queue".to_owned()))?;
+            if let Some(dist) = &dist {
+                stats.weigh(dist);
+            }
+            let groups: Vec<Value> = stats
+                .tiers
+                .iter()
+                .zip(stats.counts())
+                .zip(stats.percentages())
+                .zip(stats.probabilities())
+                .map(|(((tier, count), percent), probability)| {
+                    json!({
+                        "group": tier.to_string(),
+                        "count": count,
+                        "percent": percent,
+                        "probability": probability,
+                    })
+                })
+                .collect();
+            let queues: Vec<Value> = stats
+                .queues
+                .iter()
+                .map(|q| {
+                    let keepable: String = q.keepable.iter().map(|s| s.name()).collect();
+                    json!({ "queue": q.queue, "keepable": keepable, "tier": q.tier })
+                })
+                .collect();
+            Ok(json!({ "stats": groups, "queues": queues }))
+        }
+        "build_minimals" => {
+            let req: BuildMinimalsRequest = parse(body)?;
+            let build_queue = check_pattern("build_queue", &req.build_queue)?;
+            let solve_queue = check_pattern("solve_queue", &req.solve_queue)?;
+            let saves = check_saves(&req.saves)?;
+            let start = check_start(&req.start)?;
+            let qbf = configure(qbf, &req.options);
+            let minimals =
+                qbf.build_minimals(&build_queue, &start, &solve_queue, &saves, req.by_saves);
+            let setups: Vec<Setup> = minimals.setups.iter().map(|(s, _)| s.clone()).collect();
+            let covers = qbf.build_covers(&setups, &build_queue, &start);
+            let scores: Vec<&Vec<usize>> = minimals.setups.iter().map(|(_, s)| &s.0).collect();
+            Ok(json!({
+                "setups": setups_json(
+                    setups.into_iter().zip(covers).map(|(s, c)| (s, c, None)).collect(),
+                    &solve_queue,
+                ),
+                "save_scores": scores,
+                "sets": minimals.sets,
+                "uncovered": minimals.uncovered,
+            }))
+        }
+        "route" => {
+            let req: RouteRequest = parse(body)?;
+            let build_queue = check_pattern("build_queue", &req.build_queue)?;
+            let solve_queue = check_pattern("solve_queue", &req.solve_queue)?;
+            let saves = check_saves(&req.saves)?;
+            let plan = match &req.plan {
+                Some(plan) => RoutePlan::PerBuild(
+                    plan.iter()
+                        .map(|(build, setup)| Ok((build.clone(), decode_plan_setup(setup)?)))
+                        .collect::<Result<_, ApiError>>()?,
+                ),
+                None => RoutePlan::BestAvailable,
+            };
+            let route = configure(qbf, &req.options).route(
+                &build_queue,
+                &check_start(&req.start)?,
+                solve_source(&solve_queue, check_randomizer(&req.options)?),
+                &saves,
+                &plan,
+            );
+            let choices: Vec<Value> = route
+                .choices
+                .iter()
+                .map(|(build, setup)| {
+                    json!({
+                        "build_queue": build,
+                        "setup": setup.as_ref().map(|s| BoardJson::from(&s.board)),
+                        "held": setup.as_ref().and_then(|s| s.held).map(|s| s.name()),
+                    })
+                })
+                .collect();
+            Ok(json!({
+                "probability": route.probability(),
+                "tier_probabilities": route.tier_probabilities(),
+                "total": route.total,
+                "tiers": route.tiers,
+                "losing": route.losing,
+                "choices": choices,
+            }))
+        }
+        "simulate" => {
+            let req: SimulateRequest = parse(body)?;
+            if req.runs > MAX_RUNS {
+                return Err(ApiError::BadRequest(format!(
+                    "runs must be at most {MAX_RUNS}"
+                )));
+            }
+            let build_queue = check_pattern("build_queue", &req.build_queue)?;
+            let solve_queue = check_pattern("solve_queue", &req.solve_queue)?;
+            let saves = check_saves(&req.saves)?;
+            let start = check_start(&req.start)?;
+            let qbf = configure(qbf, &req.options);
+            let randomizer = check_randomizer(&req.options)?;
+            let pattern = match randomizer {
+                Some(r) => qbf
+                    .solve_distribution(r, &build_queue, &start, &solve_queue)
+                    .pattern(),
+                None => solve_queue.clone(),
+            };
+            let plan = match &req.plan {
+                Some(plan) => {
+                    let setups: Vec<Setup> = plan
+                        .iter()
+                        .map(|setup| decode_plan_setup(setup))
+                        .collect::<Result<_, _>>()?;
+                    qbf.sim_plan(&setups, &pattern, &saves)
+                }
+                None => qbf.best_sim_plan(&build_queue, &start, &pattern, &saves),
+            };
+            let sim = qbf.simulate(
+                &build_queue,
+                &start,
+                solve_source(&solve_queue, randomizer),
+                &saves,
+                &plan,
+                req.runs,
+                req.seed,
+            );
+            let tiers: Vec<Value> = saves
+                .tiers
+                .iter()
+                .zip(sim.tier_rates())
+                .map(|(tier, (rate, interval))| {
+                    json!({ "group": tier.to_string(), "rate": rate, "interval": interval })
+                })
+                .collect();
+            let failures: serde_json::Map<String, Value> = sim
+                .failures()
+                .into_iter()
+                .map(|(cause, count)| (cause.name().to_owned(), json!(count)))
+                .collect();
+            let examples: Vec<Value> = sim
+                .examples
+                .iter()
+                .map(|(cause, build, solve)| {
+                    json!({ "cause": cause.name(), "build_queue": build, "solve_queue": solve })
+                })
+                .collect();
+            let setups: Vec<String> = plan.setups.iter().map(|p| p.setup.encode()).collect();
+            Ok(json!({
+                "runs": sim.runs,
+                "probability": sim.probability(),
+                "interval": sim.interval(),
+                "tiers": tiers,
+                "failures": failures,
+                "examples": examples,
+                "plan": setups,
+            }))
+        }
+        "bestsaves" => {
+            let req: BestsavesRequest = parse(body)?;
+            let fifth = check_pattern("fifth", &req.fifth)?;
+            let saves = check_saves(&req.saves)?;
+            let queues: serde_json::Map<String, Value> = qbf
+                .bestsaves(&fifth, &saves)
+                .into_iter()
+                .map(|(queue, setups)| (queue, json!(boards_json(&setups))))
+                .collect();
+            Ok(json!({ "queues": queues }))
+        }
+        _ => Err(ApiError::NotFound),
+    }
+}
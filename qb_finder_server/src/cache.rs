@@ -0,0 +1,39 @@
+use std::{collections::VecDeque, sync::Mutex};
+
+use rustc_hash::FxHashMap;
+
+/// Response cache keyed on endpoint and canonical request body, evicting the oldest entry first.
+pub struct Cache {
+    capacity: usize,
+    entries: Mutex<(FxHashMap<String, String>, VecDeque<String>)>,
+}
+
+impl Cache {
+    pub fn new(capacity: usize) -> Cache {
+        Cache {
+            capacity,
+            entries: Mutex::new(Default::default()),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        let entries = self.entries.lock().unwrap();
+        entries.0.get(key).cloned()
+    }
+
+    pub fn insert(&self, key: String, value: String) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        let (map, order) = &mut *entries;
+        if map.insert(key.clone(), value).is_none() {
+            order.push_back(key);
+        }
+        while map.len() > self.capacity
+            && let Some(oldest) = order.pop_front()
+        {
+            map.remove(&oldest);
+        }
+    }
+}
@@ -0,0 +1,285 @@
+mod api;
+mod cache;
+
+use std::{
+    fs::{self, File},
+    io::{Cursor, Read},
+    path::{Component, Path, PathBuf},
+    process,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, RecvTimeoutError},
+    },
+    thread,
+    time::Duration,
+};
+
+use qb_finder_core::{QBFinder, task::Task};
+use rustc_hash::FxHashSet;
+use serde_json::{Value, json};
+use srs_4l::{board_list, gameplay::Board};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::{api::ApiError, cache::Cache};
+
+struct Config {
+    addr: String,
+    boards: PathBuf,
+    web_dir: PathBuf,
+    max_body: usize,
+    timeout: Duration,
+    cache_size: usize,
+    compute_cache_size: usize,
+    store: Option<PathBuf>,
+    threads: usize,
+    jobs: usize,
+}
+
+struct State {
+    qbf: QBFinder,
+    config: Config,
+    cache: Cache,
+    /// Queries running, including timed out ones still winding down.
+    running: AtomicUsize,
+}
+
+/// A query slot taken from [`State::running`], given back on drop.
+struct Job(Arc<State>);
+
+impl Job {
+    fn start(state: &Arc<State>) -> Option<Job> {
+        state
+            .running
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                (n < state.config.jobs).then_some(n + 1)
+            })
+            .ok()
+            .map(|_| Job(state.clone()))
+    }
+}
+
+impl Drop for Job {
+    fn drop(&mut self) {
+        self.0.running.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+type HttpResponse = Response<Cursor<Vec<u8>>>;
+
+fn usage() -> ! {
+    eprintln!(
+        "Usage: qb_finder_server [--addr HOST:PORT] [--boards FILE] [--web DIR] \
+         [--max-body BYTES] [--timeout SECS] [--cache ENTRIES] [--compute-cache ENTRIES] \
+         [--store DIR] [--threads N] [--jobs N]"
+    );
+    process::exit(1);
+}
+
+fn parse_args() -> Config {
+    let mut config = Config {
+        addr: "127.0.0.1:8080".to_owned(),
+        boards: PathBuf::from("./legal-boards.leb128"),
+        web_dir: PathBuf::from("./qb_finder_web"),
+        max_body: 64 * 1024,
+        timeout: Duration::from_secs(120),
+        cache_size: 256,
+        compute_cache_size: 4096,
+        store: None,
+        threads: 4,
+        jobs: 4,
+    };
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let Some(value) = args.next() else { usage() };
+        let number = || value.parse::<u64>().unwrap_or_else(|_| usage());
+        match arg.as_str() {
+            "--addr" => config.addr = value,
+            "--boards" => config.boards = PathBuf::from(value),
+            "--web" => config.web_dir = PathBuf::from(value),
+            "--max-body" => config.max_body = number() as usize,
+            "--timeout" => config.timeout = Duration::from_secs(number()),
+            "--cache" => config.cache_size = number() as usize,
+            "--compute-cache" => config.compute_cache_size = number() as usize,
+            "--store" => config.store = Some(PathBuf::from(value)),
+            "--threads" => config.threads = (number() as usize).max(1),
+            "--jobs" => config.jobs = (number() as usize).max(1),
+            _ => usage(),
+        }
+    }
+    config
+}
+
+fn json_response(status: u16, body: String) -> HttpResponse {
+    Response::from_string(body)
+        .with_status_code(status)
+        .with_header(Header::from_bytes("Content-Type", "application/json").unwrap())
+}
+
+fn error_response(status: u16, message: &str) -> HttpResponse {
+    json_response(status, json!({ "error": message }).to_string())
+}
+
+fn content_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("html") => "text/html; charset=utf-8",
+        Some("js") => "text/javascript",
+        Some("css") => "text/css",
+        Some("wasm") => "application/wasm",
+        Some("json") => "application/json",
+        Some("ico") => "image/x-icon",
+        _ => "application/octet-stream",
+    }
+}
+
+fn static_response(state: &State, url_path: &str) -> HttpResponse {
+    let relative = url_path.trim_start_matches('/');
+    let path = if relative == "legal-boards.leb128" {
+        state.config.boards.clone()
+    } else {
+        let relative = Path::new(if relative.is_empty() {
+            "index.html"
+        } else {
+            relative
+        });
+        if !relative
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+        {
+            return error_response(404, "Not found");
+        }
+        state.config.web_dir.join(relative)
+    };
+
+    match fs::read(&path) {
+        Ok(data) => Response::from_data(data)
+            .with_header(Header::from_bytes("Content-Type", content_type(&path)).unwrap()),
+        Err(_) => error_response(404, "Not found"),
+    }
+}
+
+fn api_response(state: &Arc<State>, endpoint: &str, request: &mut Request) -> HttpResponse {
+    let mut body = Vec::new();
+    let limit = state.config.max_body;
+    if request
+        .as_reader()
+        .take(limit as u64 + 1)
+        .read_to_end(&mut body)
+        .is_err()
+    {
+        return error_response(400, "Failed to read request body");
+    }
+    if body.len() > limit {
+        return error_response(413, "Request body too large");
+    }
+
+    let query: Value = match serde_json::from_slice(&body) {
+        Ok(v) => v,
+        Err(e) => return error_response(400, &e.to_string()),
+    };
+    let key = format!("{endpoint} {query}");
+    if let Some(cached) = state.cache.get(&key) {
+        return json_response(200, cached);
+    }
+
+    let Some(job) = Job::start(state) else {
+        return error_response(503, "Too many queries running, try again later");
+    };
+    let mut qbf = state.qbf.clone();
+    qbf.task = Task::new();
+    let task = qbf.task.clone();
+    let (tx, rx) = mpsc::channel();
+    let endpoint = endpoint.to_owned();
+    thread::spawn(move || {
+        let res = api::handle(&qbf, &endpoint, &query).map(|v| v.to_string());
+        // a cancelled query only has part of its result
+        if let Ok(res) = &res
+            && !qbf.task.is_cancelled()
+        {
+            job.0.cache.insert(key, res.clone());
+        }
+        let _ = tx.send(res);
+        drop(job);
+    });
+
+    match rx.recv_timeout(state.config.timeout) {
+        Ok(Ok(res)) => json_response(200, res),
+        Ok(Err(ApiError::NotFound)) => error_response(404, "Unknown endpoint"),
+        Ok(Err(ApiError::BadRequest(message))) => error_response(400, &message),
+        Err(RecvTimeoutError::Timeout) => {
+            task.cancel();
+            error_response(504, "Query timed out")
+        }
+        Err(RecvTimeoutError::Disconnected) => error_response(500, "Query failed"),
+    }
+}
+
+fn respond(state: &Arc<State>, mut request: Request) {
+    let url = request.url().to_owned();
+    let path = url.split('?').next().unwrap_or_default();
+
+    let response = match (request.method(), path.strip_prefix("/api/")) {
+        (Method::Get, Some("cache_stats")) => {
+            let stats = state.qbf.cache_stats();
+            let body = json!({
+                "hits": stats.hits,
+                "misses": stats.misses,
+                "entries": stats.entries,
+                "capacity": stats.capacity,
+            });
+            json_response(200, body.to_string())
+        }
+        (Method::Post, Some(endpoint)) => api_response(state, endpoint, &mut request),
+        (Method::Get, None) => static_response(state, path),
+        _ => error_response(405, "Method not allowed"),
+    };
+
+    let _ = request.respond(response);
+}
+
+fn main() {
+    let config = parse_args();
+
+    let mut file = File::open(&config.boards).expect("Failed to open legal_boards");
+    let mut buffer = Vec::new();
+    file.read_to_end(&mut buffer)
+        .expect("Failed to read legal_boards");
+
+    let legal_boards: FxHashSet<Board> = board_list::read(Cursor::new(buffer))
+        .unwrap()
+        .into_iter()
+        .collect();
+
+    let server = Arc::new(Server::http(&config.addr).expect("Failed to bind address"));
+    println!("Listening on http://{}", config.addr);
+
+    let mut qbf = QBFinder::new(legal_boards);
+    qbf.set_cache_size(config.compute_cache_size);
+    if let Some(dir) = &config.store {
+        qbf.set_store(dir).expect("Failed to open result store");
+    }
+
+    let state = Arc::new(State {
+        qbf,
+        cache: Cache::new(config.cache_size),
+        running: AtomicUsize::new(0),
+        config,
+    });
+
+    let workers: Vec<_> = (0..state.config.threads)
+        .map(|_| {
+            let server = server.clone();
+            let state = state.clone();
+            thread::spawn(move || {
+                while let Ok(request) = server.recv() {
+                    respond(&state, request);
+                }
+            })
+        })
+        .collect();
+
+    for worker in workers {
+        let _ = worker.join();
+    }
+}
@@ -0,0 +1,395 @@
+use std::collections::VecDeque;
+
+use itertools::Itertools;
+use qb_finder_core::{
+    QBFinder, parse_shape,
+    queue::{Bag, QueueStart},
+    saves::SavePolicy,
+};
+use srs_4l::{
+    brokenboard::BrokenBoard,
+    gameplay::{Board, Physics, Shape},
+    vector::Placements,
+};
+
+use crate::protocol::{Location, Move, Randomizer, Start};
+
+/// Pieces in a build queue when the next PC is still too far ahead to solve directly.
+const BUILD_LEN: usize = 4;
+
+struct Game {
+    /// Current PC in the unbroken 4 row frame, or `None` once the board has left it.
+    frame: Option<BrokenBoard>,
+    hold: Option<Shape>,
+    queue: VecDeque<Shape>,
+    /// Pieces of the current bag not yet in the queue.
+    bag: Vec<Shape>,
+    /// Setup or PC the last suggestion was working towards.
+    target: Option<BrokenBoard>,
+}
+
+pub struct Bot {
+    qbf: QBFinder,
+    saves: SavePolicy,
+    game: Option<Game>,
+}
+
+fn shape_char(shape: Shape) -> char {
+    shape.name().chars().next().unwrap_or_default()
+}
+
+fn board_cells(board: Board) -> Vec<(i32, i32)> {
+    (0..4)
+        .cartesian_product(0..10)
+        .filter(|&(row, col)| board.get(row, col))
+        .map(|(row, col)| (col as i32, row as i32))
+        .collect()
+}
+
+fn full_rows(board: Board) -> Vec<usize> {
+    (0..4)
+        .filter(|&row| (0..10).all(|col| board.get(row, col)))
+        .collect()
+}
+
+fn same_pieces(a: &BrokenBoard, b: &BrokenBoard) -> bool {
+    a.pieces.len() == b.pieces.len() && a.pieces.iter().all(|p| b.pieces.contains(p))
+}
+
+fn consume(bag: &mut Vec<Shape>, shape: Shape) {
+    if !bag.contains(&shape) {
+        *bag = Shape::ALL.to_vec();
+    }
+    bag.retain(|&s| s != shape);
+}
+
+impl Game {
+    fn new(start: Start) -> Game {
+        let queue: VecDeque<Shape> = start.queue.iter().filter_map(|&c| parse_shape(c)).collect();
+
+        let bag = match start.randomizer {
+            Some(Randomizer::SevenBag { bag_state }) => {
+                bag_state.into_iter().filter_map(parse_shape).collect()
+            }
+            _ => {
+                // assume the queue starts on a bag boundary
+                let mut bag = Shape::ALL.to_vec();
+                for &shape in &queue {
+                    consume(&mut bag, shape);
+                }
+                bag
+            }
+        };
+
+        let mut garbage = 0u64;
+        let mut fits = true;
+        for (row, cells) in start.board.iter().enumerate() {
+            for (col, cell) in cells.iter().enumerate() {
+                if cell.is_some() {
+                    if row >= 4 || col >= 10 {
+                        fits = false;
+                    } else {
+                        garbage |= 1 << (row * 10 + col);
+                    }
+                }
+            }
+        }
+
+        Game {
+            frame: fits.then(|| BrokenBoard::from_garbage(garbage)),
+            hold: start.hold.and_then(parse_shape),
+            queue,
+            bag,
+            target: None,
+        }
+    }
+
+    /// Pieces in the order the solver takes them, with the held piece first.
+    fn known(&self) -> Vec<Shape> {
+        self.hold.iter().chain(&self.queue).copied().collect()
+    }
+
+    /// Bags for `total` pieces starting from `known`, filling the rest from the 7-bag.
+    fn bags(&self, known: &[Shape], total: usize) -> Vec<Bag> {
+        let mut bags: Vec<Bag> = known
+            .iter()
+            .take(total)
+            .map(|&s| Bag::new(&[s], 1))
+            .collect();
+        let mut rest = total.saturating_sub(known.len());
+        if rest > 0 && !self.bag.is_empty() {
+            let count = rest.min(self.bag.len());
+            bags.push(Bag::new(&self.bag, count as u8));
+            rest -= count;
+        }
+        while rest > 0 {
+            let count = rest.min(7);
+            bags.push(Bag::new(&Shape::ALL, count as u8));
+            rest -= count;
+        }
+        bags
+    }
+
+    /// Frame cells of a placement mapped onto the visible board, where full rows are cleared.
+    fn location(&self, shape: Shape, placed: Board) -> Option<Location> {
+        let frame = self.frame.as_ref()?;
+        let cleared = full_rows(frame.board);
+        let cells: Vec<(i32, i32)> = board_cells(Board(placed.0 & !frame.board.0))
+            .into_iter()
+            .map(|(x, y)| {
+                (
+                    x,
+                    y - cleared.iter().filter(|&&r| (r as i32) < y).count() as i32,
+                )
+            })
+            .collect();
+        Location::from_cells(shape, &cells)
+    }
+
+    fn play(&mut self, mv: &Move, physics: Physics) {
+        let Some(shape) = parse_shape(mv.location.kind) else {
+            self.frame = None;
+            return;
+        };
+
+        self.frame = self.frame.as_ref().and_then(|frame| {
+            Placements::place(frame.board, shape, physics)
+                .canonical()
+                .find(|&(_, board)| {
+                    self.location(shape, board)
+                        .is_some_and(|l| l.cells(shape) == mv.location.cells(shape))
+                })
+                .map(|(piece, _)| frame.place(piece))
+        });
+        if let Some(frame) = &self.frame
+            && frame.board.0.count_ones() == 40
+        {
+            self.frame = Some(BrokenBoard::from_garbage(0));
+        }
+
+        if self.queue.front() == Some(&shape) {
+            self.queue.pop_front();
+        } else if self.hold == Some(shape) {
+            self.hold = self.queue.pop_front();
+        } else if self.hold.is_none() && self.queue.get(1) == Some(&shape) {
+            self.hold = self.queue.pop_front();
+            self.queue.pop_front();
+        } else {
+            self.frame = None;
+        }
+    }
+}
+
+impl Bot {
+    pub fn new(qbf: QBFinder, saves: SavePolicy) -> Bot {
+        Bot {
+            qbf,
+            saves,
+            game: None,
+        }
+    }
+
+    pub fn start(&mut self, start: Start) {
+        self.game = Some(Game::new(start));
+    }
+
+    pub fn stop(&mut self) {
+        self.game = None;
+    }
+
+    pub fn new_piece(&mut self, c: char) {
+        if let (Some(game), Some(shape)) = (&mut self.game, parse_shape(c)) {
+            game.queue.push_back(shape);
+            consume(&mut game.bag, shape);
+        }
+    }
+
+    pub fn play(&mut self, mv: &Move) {
+        if let Some(game) = &mut self.game {
+            game.play(mv, self.qbf.physics());
+        }
+    }
+
+    /// Pieces placed and left in hold when completing `target` from `from`.
+    fn piece_total(target: &BrokenBoard, from: &BrokenBoard) -> usize {
+        let to_place = target.pieces.len() - from.pieces.len();
+        if target.board.0.count_ones() == 40 {
+            to_place + 1
+        } else {
+            to_place
+        }
+    }
+
+    /// Targets for the current frame, best first: PCs keeping the highest priority save, or
+    /// when the queue is too short to see the PC, the setups `find` rates highest.
+    fn targets(&self, game: &Game, frame: &BrokenBoard) -> Vec<BrokenBoard> {
+        let filled = frame.board.0.count_ones() as usize;
+        if !filled.is_multiple_of(4) {
+            return vec![];
+        }
+        let known = game.known();
+        let pc_pieces = (40 - filled) / 4 + 1;
+
+        if frame.board.0 == 0 && known.len() < pc_pieces && known.len() >= BUILD_LEN {
+            let build_queue = known[..BUILD_LEN].iter().map(|&s| shape_char(s)).join(",");
+            let p_count = pc_pieces - BUILD_LEN;
+            let rest = Game {
+                frame: None,
+                hold: None,
+                queue: known[BUILD_LEN..].iter().copied().collect(),
+                bag: game.bag.clone(),
+                target: None,
+            };
+            let solve_queue = solve_queues(&rest, p_count);
+            let (setups, _) = self.qbf.find(
+                &build_queue,
+                &QueueStart::default(),
+                None,
+                &solve_queue,
+                &self.saves,
+                1,
+            );
+            if !setups.is_empty() {
+                return setups.into_iter().map(|setup| setup.board).collect();
+            }
+        }
+
+        let bags = game.bags(&known, pc_pieces);
+        for tier in &self.saves.tiers {
+            let solves: Vec<BrokenBoard> = tier
+                .saves()
+                .into_iter()
+                .flat_map(|save| self.qbf.compute_bags(&bags, frame, save))
+                .unique()
+                .collect();
+            if !solves.is_empty() {
+                return solves;
+            }
+        }
+        self.qbf.compute_bags(&bags, frame, None)
+    }
+
+    fn reachable(
+        &self,
+        game: &Game,
+        from: &BrokenBoard,
+        known: &[Shape],
+        target: &BrokenBoard,
+    ) -> bool {
+        if !from.pieces.iter().all(|p| target.pieces.contains(p)) {
+            return false;
+        }
+        if from.pieces.len() == target.pieces.len() {
+            return true;
+        }
+        let bags = game.bags(known, Self::piece_total(target, from));
+        self.qbf
+            .compute_bags(&bags, from, None)
+            .iter()
+            .any(|solve| same_pieces(solve, target))
+    }
+
+    /// First move towards `target` that keeps it reachable with the remaining pieces.
+    fn move_towards(
+        &self,
+        game: &Game,
+        frame: &BrokenBoard,
+        options: &[(Shape, Vec<Shape>)],
+        target: &BrokenBoard,
+    ) -> Option<Location> {
+        for (shape, rest) in options {
+            for (piece, board) in
+                Placements::place(frame.board, *shape, self.qbf.physics()).canonical()
+            {
+                if self.reachable(game, &frame.place(piece), rest, target)
+                    && let Some(location) = game.location(*shape, board)
+                {
+                    return Some(location);
+                }
+            }
+        }
+        None
+    }
+
+    pub fn suggest(&mut self) -> Vec<Move> {
+        let Some(game) = &self.game else {
+            return vec![];
+        };
+        let Some(frame) = &game.frame else {
+            return vec![];
+        };
+        let known = game.known();
+
+        // placing the first known piece keeps the rest, placing the second keeps the first in hold
+        let options: Vec<(Shape, Vec<Shape>)> = (0..known.len().min(2))
+            .map(|i| {
+                let mut rest = known.clone();
+                let shape = rest.remove(i);
+                (shape, rest)
+            })
+            .collect();
+
+        let planned = game
+            .target
+            .as_ref()
+            .filter(|t| t.pieces.len() > frame.pieces.len())
+            .and_then(|t| Some((self.move_towards(game, frame, &options, t)?, t.clone())))
+            .or_else(|| {
+                self.targets(game, frame)
+                    .into_iter()
+                    .take(8)
+                    .find_map(|t| Some((self.move_towards(game, frame, &options, &t)?, t)))
+            });
+
+        // no PC in sight, so just keep the board inside the frame
+        let location = match planned {
+            Some((location, target)) => {
+                if let Some(game) = &mut self.game {
+                    game.target = Some(target);
+                }
+                Some(location)
+            }
+            None => options.first().and_then(|(shape, _)| {
+                Placements::place(frame.board, *shape, self.qbf.physics())
+                    .canonical()
+                    .find_map(|(_, board)| game.location(*shape, board))
+            }),
+        };
+
+        location
+            .map(|location| Move {
+                location,
+                spin: "none".to_owned(),
+            })
+            .into_iter()
+            .collect()
+    }
+}
+
+/// Every distinct order of the next `count` pieces after the queue, as a solve pattern.
+fn solve_queues(game: &Game, count: usize) -> String {
+    let mut groups: Vec<(Vec<Shape>, usize)> = game
+        .queue
+        .iter()
+        .take(count)
+        .map(|&s| (vec![s], 1))
+        .collect();
+    let mut rest = count.saturating_sub(groups.len());
+    if rest > 0 && !game.bag.is_empty() {
+        let taken = rest.min(game.bag.len());
+        groups.push((game.bag.clone(), taken));
+        rest -= taken;
+    }
+    while rest > 0 {
+        let taken = rest.min(7);
+        groups.push((Shape::ALL.to_vec(), taken));
+        rest -= taken;
+    }
+
+    groups
+        .into_iter()
+        .map(|(shapes, taken)| shapes.into_iter().permutations(taken).collect_vec())
+        .multi_cartesian_product()
+        .map(|queue| queue.concat().into_iter().map(shape_char).join(","))
+        .join("\n")
+}
@@ -0,0 +1,107 @@
+mod bot;
+mod protocol;
+
+use std::{
+    fs::File,
+    io::{self, BufRead, Cursor, Read, Write},
+    process,
+};
+
+use qb_finder_core::{QBFinder, saves::SavePolicy};
+use rustc_hash::FxHashSet;
+use srs_4l::{board_list, gameplay::Board};
+
+use crate::{
+    bot::Bot,
+    protocol::{BotMessage, FrontendMessage},
+};
+
+fn send(message: &BotMessage) {
+    let mut stdout = io::stdout().lock();
+    let _ = writeln!(stdout, "{}", serde_json::to_string(message).unwrap());
+    let _ = stdout.flush();
+}
+
+fn main() {
+    let mut boards = "./legal-boards.leb128".to_owned();
+    let mut saves = "T".to_owned();
+    let mut cache_size = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match (arg.as_str(), args.next()) {
+            ("--boards", Some(path)) => boards = path,
+            ("--saves", Some(s)) => saves = s.to_uppercase(),
+            ("--cache", Some(n)) if n.parse::<usize>().is_ok() => cache_size = n.parse().ok(),
+            _ => {
+                eprintln!(
+                    "Usage: qb_finder_tbp [--boards FILE] [--saves POLICY] [--cache ENTRIES]"
+                );
+                process::exit(1);
+            }
+        }
+    }
+
+    let Some(saves) = SavePolicy::parse(&saves) else {
+        eprintln!("Invalid save policy {saves:?}, expected tiers such as T,IO,^O,-");
+        process::exit(1);
+    };
+
+    let mut file = File::open(&boards).expect("Failed to open legal_boards");
+    let mut buffer = Vec::new();
+    file.read_to_end(&mut buffer)
+        .expect("Failed to read legal_boards");
+
+    let legal_boards: FxHashSet<Board> = board_list::read(Cursor::new(buffer))
+        .unwrap()
+        .into_iter()
+        .collect();
+
+    let qbf = QBFinder::new(legal_boards);
+    if let Some(size) = cache_size {
+        qbf.set_cache_size(size);
+    }
+
+    let mut bot = Bot::new(qbf, saves);
+
+    send(&BotMessage::Info {
+        name: "qb_finder",
+        version: env!("CARGO_PKG_VERSION"),
+        author: "MonkieeBoi",
+        features: vec![],
+    });
+
+    for line in io::stdin().lock().lines() {
+        let Ok(line) = line else {
+            break;
+        };
+        let message = match serde_json::from_str(&line) {
+            Ok(m) => m,
+            Err(e) => {
+                eprintln!("Invalid message: {e}");
+                continue;
+            }
+        };
+
+        match message {
+            FrontendMessage::Rules { randomizer } => {
+                // every probability the finder computes assumes a 7-bag
+                if randomizer.is_none_or(|r| r == "seven_bag") {
+                    send(&BotMessage::Ready)
+                } else {
+                    send(&BotMessage::Error {
+                        reason: "unsupported_rules",
+                    })
+                }
+            }
+            FrontendMessage::Start(start) => bot.start(start),
+            FrontendMessage::Suggest => send(&BotMessage::Suggestion {
+                moves: bot.suggest(),
+            }),
+            FrontendMessage::Play { mv } => bot.play(&mv),
+            FrontendMessage::NewPiece { piece } => bot.new_piece(piece),
+            FrontendMessage::Stop => bot.stop(),
+            FrontendMessage::Quit => break,
+            FrontendMessage::Unknown => {}
+        }
+    }
+}
@@ -0,0 +1,150 @@
+use serde::{Deserialize, Serialize};
+use srs_4l::gameplay::Shape;
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum FrontendMessage {
+    Rules {
+        #[serde(default)]
+        randomizer: Option<String>,
+    },
+    Start(Start),
+    Suggest,
+    Play {
+        #[serde(rename = "move")]
+        mv: Move,
+    },
+    NewPiece {
+        piece: char,
+    },
+    Stop,
+    Quit,
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Deserialize)]
+pub struct Start {
+    pub hold: Option<char>,
+    pub queue: Vec<char>,
+    pub board: Vec<Vec<Option<char>>>,
+    #[serde(default)]
+    pub randomizer: Option<Randomizer>,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Randomizer {
+    SevenBag {
+        bag_state: Vec<char>,
+    },
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum BotMessage {
+    Info {
+        name: &'static str,
+        version: &'static str,
+        author: &'static str,
+        features: Vec<&'static str>,
+    },
+    Ready,
+    Suggestion {
+        moves: Vec<Move>,
+    },
+    Error {
+        reason: &'static str,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Orientation {
+    North,
+    East,
+    South,
+    West,
+}
+
+impl Orientation {
+    const ALL: [Orientation; 4] = [
+        Orientation::North,
+        Orientation::East,
+        Orientation::South,
+        Orientation::West,
+    ];
+
+    fn rotate(self, (x, y): (i32, i32)) -> (i32, i32) {
+        match self {
+            Orientation::North => (x, y),
+            Orientation::East => (y, -x),
+            Orientation::South => (-x, -y),
+            Orientation::West => (-y, x),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Location {
+    #[serde(rename = "type")]
+    pub kind: char,
+    pub orientation: Orientation,
+    pub x: i32,
+    pub y: i32,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Move {
+    pub location: Location,
+    pub spin: String,
+}
+
+/// Cells of each shape facing north, relative to its rotation centre as defined by TBP.
+fn north_cells(shape: Shape) -> [(i32, i32); 4] {
+    match shape {
+        Shape::I => [(-1, 0), (0, 0), (1, 0), (2, 0)],
+        Shape::J => [(-1, 0), (0, 0), (1, 0), (-1, 1)],
+        Shape::L => [(-1, 0), (0, 0), (1, 0), (1, 1)],
+        Shape::O => [(0, 0), (1, 0), (0, 1), (1, 1)],
+        Shape::S => [(-1, 0), (0, 0), (0, 1), (1, 1)],
+        Shape::T => [(-1, 0), (0, 0), (1, 0), (0, 1)],
+        Shape::Z => [(-1, 1), (0, 1), (0, 0), (1, 0)],
+    }
+}
+
+impl Location {
+    pub fn cells(&self, shape: Shape) -> Vec<(i32, i32)> {
+        north_cells(shape)
+            .into_iter()
+            .map(|cell| {
+                let (dx, dy) = self.orientation.rotate(cell);
+                (self.x + dx, self.y + dy)
+            })
+            .collect()
+    }
+
+    /// Finds the location whose cells are exactly `cells`, given as `(x, y)` board coordinates.
+    pub fn from_cells(shape: Shape, cells: &[(i32, i32)]) -> Option<Location> {
+        for orientation in Orientation::ALL {
+            let offsets = north_cells(shape).map(|c| orientation.rotate(c));
+            for &(cx, cy) in cells {
+                let (x, y) = (cx - offsets[0].0, cy - offsets[0].1);
+                if offsets
+                    .iter()
+                    .all(|&(dx, dy)| cells.contains(&(x + dx, y + dy)))
+                {
+                    return Some(Location {
+                        kind: shape.name().chars().next().unwrap_or_default(),
+                        orientation,
+                        x,
+                        y,
+                    });
+                }
+            }
+        }
+        None
+    }
+}
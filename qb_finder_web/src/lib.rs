@@ -1,17 +1,80 @@
 use itertools::Itertools;
-use js_sys::Uint8Array;
-use qb_finder_core::{QBFinder, expand_pattern, solver};
+use js_sys::{Array, Function, Object, Reflect, Uint8Array};
+use qb_finder_core::{
+    FindEvent, QBFinder,
+    build::{BuildMode, Setup},
+    expand_pattern, parse_shape,
+    pc::pc_bag_offset,
+    queue::QueueStart,
+    randomizer::Randomizer,
+    saves::SavePolicy,
+    solver,
+    task::Task,
+};
 use rustc_hash::FxHashSet;
+use send_wrapper::SendWrapper;
 use std::fmt::Write;
 use std::io::Cursor;
 
 use srs_4l::{
-    base64::{base64_decode, base64_encode},
+    base64::base64_decode,
     board_list,
     brokenboard::BrokenBoard,
-    gameplay::Board,
+    gameplay::{Board, Shape},
 };
-use wasm_bindgen::prelude::wasm_bindgen;
+use wasm_bindgen::{JsValue, prelude::wasm_bindgen};
+
+fn queue_start(hold: &str, bag_used: &str) -> QueueStart {
+    QueueStart {
+        hold: hold.chars().find_map(parse_shape),
+        bag_used: bag_used.chars().filter_map(parse_shape).collect(),
+    }
+}
+
+/// Throws in JS if `saves` is not a save policy.
+fn save_policy(saves: &str) -> Result<SavePolicy, JsValue> {
+    SavePolicy::parse(saves)
+        .ok_or_else(|| JsValue::from_str(&format!("Invalid save policy {saves:?}")))
+}
+
+/// Throws in JS if `name` is given but is not a randomizer.
+fn randomizer(name: Option<&str>) -> Result<Option<Randomizer>, JsValue> {
+    name.map(|name| Randomizer::parse(name).ok_or_else(|| JsValue::from_str("Invalid randomizer")))
+        .transpose()
+}
+
+fn js_object(fields: &[(&str, JsValue)]) -> Result<JsValue, JsValue> {
+    let object = Object::new();
+    for (key, value) in fields {
+        Reflect::set(&object, &(*key).into(), value)?;
+    }
+    Ok(object.into())
+}
+
+/// A JS callback taking two arguments. Returning `false` from it means stop. It panics if it is
+/// used or dropped off the thread that passed it in.
+struct JsCallback(SendWrapper<Function>);
+
+impl JsCallback {
+    fn call(&self, a: &JsValue, b: &JsValue) -> bool {
+        self.0
+            .call2(&JsValue::NULL, a, b)
+            .is_ok_and(|keep_going| keep_going.as_bool() != Some(false))
+    }
+}
+
+/// `progress` is called with `(done, total)`.
+fn js_task(progress: Option<Function>) -> Task {
+    match progress {
+        Some(progress) => {
+            let progress = JsCallback(SendWrapper::new(progress));
+            Task::new().with_progress(move |done, total| {
+                progress.call(&(done as u32).into(), &(total as u32).into())
+            })
+        }
+        None => Task::new(),
+    }
+}
 
 #[wasm_bindgen]
 pub struct QBF {
@@ -35,125 +98,236 @@ impl QBF {
         }
     }
 
-    pub fn set_skip_4p(&mut self, skip_4p: bool) {
-        self.qbf.skip_4p = skip_4p;
+    /// Build and solve patterns for the `pc`th PC of the game, from 1 to 7, as `build|solve`.
+    /// `build` is the pieces seen that the setup is built from and `solve` the ones seen after
+    /// them. Throws if they can't come from a 7-bag at that PC.
+    pub fn pc_queues(
+        &self,
+        pc: usize,
+        build: &str,
+        solve: &str,
+        hold: &str,
+        bag_used: &str,
+    ) -> Result<String, JsValue> {
+        let seen: Option<Vec<Shape>> = build
+            .chars()
+            .chain(solve.chars())
+            .map(parse_shape)
+            .collect();
+        pc_bag_offset(pc)
+            .zip(seen)
+            .and_then(|(offset, seen)| {
+                self.qbf
+                    .pc_queues(offset, &queue_start(hold, bag_used), &seen, build.len())
+            })
+            .map(|pc| format!("{}|{}", pc.build_queue, pc.solve_queue))
+            .ok_or_else(|| JsValue::from_str("Queue can't come from a 7-bag at this PC"))
+    }
+
+    /// Fewest and most build pieces setups may leave unplaced, one of them held.
+    pub fn set_build_mode(&mut self, min_left: usize, max_left: usize) {
+        self.qbf.build_mode = BuildMode {
+            min_left,
+            max_left: max_left.max(min_left),
+        };
     }
 
     pub fn set_full_cover(&mut self, full_cover: bool) {
         self.qbf.full_cover = full_cover;
     }
 
-    pub fn find(&self, build_queue: &str, solve_queue: &str, saves: &str) -> String {
-        let (setups, _) = self.qbf.find(build_queue, None, solve_queue, saves, 1);
-        let solve_queues: FxHashSet<String> = expand_pattern(solve_queue).into_iter().collect();
-        let build_xor = build_queue
-            .replace(",", "")
-            .chars()
-            .fold(0, |a, c| a ^ (c as u8));
-        let build_len = build_queue.replace(",", "").len();
+    pub fn set_cache_size(&self, size: usize) {
+        self.qbf.set_cache_size(size);
+    }
+
+    /// Hits, misses, entries and capacity of the solver cache.
+    pub fn cache_stats(&self) -> Vec<u32> {
+        let stats = self.qbf.cache_stats();
+        [stats.hits, stats.misses, stats.entries, stats.capacity]
+            .map(|n| n as u32)
+            .to_vec()
+    }
+
+    /// Setups as `board,min count,encoded,saves,secondary saves,% of build queues covered`
+    /// entries joined by `|`. Returns an empty string if `progress` cancelled the search.
+    ///
+    /// With a `randomizer` such as `7bag`, `solve_queue` is the solve pieces already seen and
+    /// it deals the rest, and each entry ends with `,% chance of the PC`.
+    ///
+    /// `on_setup` is called with `(setup, saves)` for each setup as soon as it is found, as
+    /// `board,0,encoded`, and with `(null, saves)` when the setups
+    /// before no longer have the best score. `saves` counts queues per save tier, as `5+2`.
+    ///
+    /// Save tiers are separated by commas: the old `TIO`, saving T or else I or O, is now
+    /// `T,IO`, while `TIO` is a single tier saving any of the three.
+    #[allow(clippy::too_many_arguments)]
+    pub fn find(
+        &mut self,
+        build_queue: &str,
+        solve_queue: &str,
+        saves: &str,
+        hold: &str,
+        bag_used: &str,
+        progress: Option<Function>,
+        on_setup: Option<Function>,
+        randomizer: Option<String>,
+    ) -> Result<String, JsValue> {
+        let saves = save_policy(saves)?;
+        let randomizer = self::randomizer(randomizer.as_deref())?;
+        self.qbf.task = js_task(progress);
+        let on_setup = on_setup.map(|f| JsCallback(SendWrapper::new(f)));
+        let start = queue_start(hold, bag_used);
+        let dist = randomizer.map(|r| {
+            self.qbf
+                .solve_distribution(r, build_queue, &start, solve_queue)
+        });
+        let solve_queue = dist
+            .as_ref()
+            .map_or_else(|| solve_queue.to_owned(), |d| d.pattern());
+        let solve_queue = solve_queue.as_str();
+        let (setups, score) =
+            self.qbf
+                .find_with(build_queue, &start, None, solve_queue, &saves, 1, |event| {
+                    let Some(on_setup) = &on_setup else {
+                        return;
+                    };
+                    match event {
+                        FindEvent::Setup { setup, saves } => {
+                            let mut entry = String::new();
+                            solver::print(&setup.board, &mut entry);
+                            entry.push_str(",0,");
+                            entry.push_str(&setup.encode());
+                            on_setup.call(&entry.into(), &saves.to_string().into());
+                        }
+                        FindEvent::Superseded { saves } => {
+                            on_setup.call(&JsValue::NULL, &saves.to_string().into());
+                        }
+                    }
+                });
+        if self.qbf.task.is_cancelled() {
+            return Ok(String::new());
+        }
+        let covers = self.qbf.build_covers(&setups, build_queue, &start);
         let min_setups: Vec<_> = setups
             .iter()
-            .map(|b| {
-                (
-                    b,
-                    match b.pieces.len() {
-                        n if n < 3 => 0,
-                        n if n == build_len - 1 => {
-                            let r: String = ((build_xor
-                                ^ b.pieces
-                                    .iter()
-                                    .map(|p| p.shape.name().chars().nth(0).unwrap_or_default())
-                                    .fold(0, |a, c| a ^ (c as u8)))
-                                as char)
-                                .into();
-                            self.qbf.min_count(
-                                b,
-                                &solve_queue
-                                    .lines()
-                                    .map(|line| format!("{},{}", r, line))
-                                    .collect::<Vec<_>>()
-                                    .join("\n"),
-                                &solve_queues.clone().iter().map(|q| r.clone() + q).collect(),
-                                saves,
-                            )
-                        }
-                        _ => self.qbf.min_count(b, solve_queue, &solve_queues, saves),
-                    },
-                )
+            .zip(covers)
+            .map(|(setup, cover)| {
+                let min_count = if setup.board.pieces.len() < 3 {
+                    0
+                } else {
+                    let pattern = setup.solve_pattern(solve_queue);
+                    let universe = expand_pattern(&pattern).into_iter().collect();
+                    self.qbf
+                        .min_count(&setup.board, &pattern, &universe, &saves)
+                };
+                (setup, min_count, cover)
             })
-            .sorted_by_key(|(_, count)| *count)
+            .sorted_by_key(|(_, count, _)| *count)
             .collect();
+        if self.qbf.task.is_cancelled() {
+            return Ok(String::new());
+        }
 
         let mut res = String::new();
 
-        for (board, min_count) in &min_setups {
-            solver::print(board, &mut res);
-            write!(res, ",{},", min_count).ok();
-            base64_encode(&board.encode(), &mut res);
+        for (setup, min_count, cover) in &min_setups {
+            solver::print(&setup.board, &mut res);
+            write!(
+                res,
+                ",{},{},{},{},{:.1}",
+                min_count,
+                setup.encode(),
+                score.primary(),
+                score.total() - score.primary(),
+                cover.fraction() * 100.0
+            )
+            .ok();
+            if let Some(dist) = &dist {
+                let probability: f64 = self
+                    .qbf
+                    .setup_probabilities(setup, dist, &saves)
+                    .iter()
+                    .sum();
+                write!(res, ",{:.1}", probability * 100.0).ok();
+            }
             res.push('|');
         }
 
         res.pop();
-        res
+        Ok(res)
     }
 
-    pub fn find_min_sets(
-        &self,
-        setup: &str,
+    /// Setups no other setup beats on every objective, in the format of `find`.
+    /// Returns an empty string if `progress` cancelled the search.
+    pub fn find_pareto(
+        &mut self,
         build_queue: &str,
         solve_queue: &str,
         saves: &str,
-    ) -> String {
+        hold: &str,
+        bag_used: &str,
+        progress: Option<Function>,
+    ) -> Result<String, JsValue> {
+        let saves = save_policy(saves)?;
+        self.qbf.task = js_task(progress);
+        let start = queue_start(hold, bag_used);
+        let front = self
+            .qbf
+            .find_pareto(build_queue, &start, solve_queue, &saves);
+        let build_count = expand_pattern(&start.apply(build_queue)).len().max(1);
+        if self.qbf.task.is_cancelled() {
+            return Ok(String::new());
+        }
+
         let mut res = String::new();
+        for (setup, score) in &front {
+            solver::print(&setup.board, &mut res);
+            write!(
+                res,
+                ",{},{},{},{},{:.1}",
+                score.min_count.unwrap_or(0),
+                setup.encode(),
+                score.primary_saves,
+                score.secondary_saves,
+                score.build_cover as f64 * 100.0 / build_count as f64
+            )
+            .ok();
+            res.push('|');
+        }
 
-        let bits = match base64_decode(setup) {
-            Some(b) => b,
-            None => return res,
-        };
+        res.pop();
+        Ok(res)
+    }
+
+    /// `setup` is encoded as in the results of `find`, with the build pieces it leaves.
+    /// Returns an empty string if `progress` cancelled the search.
+    pub fn find_min_sets(
+        &mut self,
+        setup: &str,
+        solve_queue: &str,
+        saves: &str,
+        progress: Option<Function>,
+    ) -> Result<String, JsValue> {
+        let saves = save_policy(saves)?;
+        self.qbf.task = js_task(progress);
+        let mut res = String::new();
 
-        let board = match BrokenBoard::decode(&bits) {
-            Some(b) => b,
-            None => return res,
+        let Some(setup) = Setup::decode(setup) else {
+            return Ok(res);
         };
+        let board = &setup.board;
 
-        solver::print(&board, &mut res);
+        solver::print(board, &mut res);
         res.push('|');
 
-        let solve_queues: FxHashSet<String> = expand_pattern(solve_queue).into_iter().collect();
-
-        let build_save = if board.pieces.len() == build_queue.replace(",", "").len() - 1 {
-            let xor = build_queue
-                .replace(",", "")
-                .chars()
-                .fold(0, |a, c| a ^ (c as u8));
-
-            let r: String = ((xor
-                ^ board
-                    .pieces
-                    .iter()
-                    .map(|p| p.shape.name().chars().nth(0).unwrap_or_default())
-                    .fold(0, |a, c| a ^ (c as u8))) as char)
-                .into();
-            Some(r)
-        } else {
-            None
-        };
-
-        let (solves, covers, equiv) = if let Some(r) = build_save.clone() {
-            self.qbf.all_min_sets(
-                &board,
-                &solve_queue
-                    .lines()
-                    .map(|line| format!("{},{}", r, line))
-                    .collect::<Vec<_>>()
-                    .join("\n"),
-                &solve_queues.clone().iter().map(|q| r.clone() + q).collect(),
-                saves,
-            )
-        } else {
-            self.qbf
-                .all_min_sets(&board, solve_queue, &solve_queues, saves)
-        };
+        let pattern = setup.solve_pattern(solve_queue);
+        let solve_queues: FxHashSet<String> = expand_pattern(&pattern).into_iter().collect();
+        let (solves, covers, equiv) = self
+            .qbf
+            .all_min_sets(board, &pattern, &solve_queues, &saves);
+        if self.qbf.task.is_cancelled() {
+            return Ok(String::new());
+        }
 
         let mut common: FxHashSet<usize> = covers[0].iter().cloned().collect();
 
@@ -195,21 +369,77 @@ impl QBF {
 
         res.push('&');
 
-        if saves.contains(",") {
-            let solve_queue_prefixed = solve_queue
-                .split("\n")
-                .map(|g| format!("{},{}", build_save.clone().unwrap_or_default(), g))
+        if saves.tiers.len() > 1
+            && let Some(stats) = self.qbf.saves_stats(board, &pattern, &saves)
+        {
+            res += &stats
+                .tiers
+                .iter()
+                .zip(stats.percentages())
+                .map(|(tier, percent)| format!("{tier}: {percent:.2}%"))
                 .join("\n");
+        }
+        Ok(res)
+    }
 
-            let save_stats = self.qbf.saves_stats(&board, &solve_queue_prefixed, saves);
-            let sum_stats: f64 = (save_stats.iter().sum::<usize>()) as f64 / 100.0;
+    /// What `setup` can keep on each solve queue, as
+    /// `{ tiers: [{ tier, count, percent, probability }], queues: [{ queue, keepable, tier }] }`
+    /// where a queue's `tier` indexes `tiers` or is `null`. With a `randomizer` such as `tgm`,
+    /// the solve queues are `solve_queue` followed by whatever it deals. Throws if an argument is
+    /// invalid.
+    pub fn saves_stats(
+        &mut self,
+        setup: &str,
+        solve_queue: &str,
+        saves: &str,
+        progress: Option<Function>,
+        randomizer: Option<String>,
+    ) -> Result<JsValue, JsValue> {
+        let saves = save_policy(saves)?;
+        self.qbf.task = js_task(progress);
+        let board = base64_decode(setup)
+            .and_then(|bits| BrokenBoard::decode(&bits))
+            .ok_or_else(|| JsValue::from_str("Invalid setup"))?;
+        let dist = self::randomizer(randomizer.as_deref())?.map(|randomizer| {
+            // as many pieces as the setup needs, counting the one kept
+            let len = (40 - board.board.0.count_ones() as usize) / 4 + self.qbf.hold as usize;
+            randomizer.distribution_after(&[], solve_queue, len)
+        });
+        let solve_queue = dist
+            .as_ref()
+            .map_or_else(|| solve_queue.to_owned(), |d| d.pattern());
+        let mut stats = self
+            .qbf
+            .saves_stats(&board, &solve_queue, &saves)
+            .ok_or_else(|| JsValue::from_str("Invalid solve queue"))?;
+        if let Some(dist) = &dist {
+            stats.weigh(dist);
+        }
 
-            res += &saves
-                .split(",")
-                .enumerate()
-                .map(|(i, g)| format!("{}: {:.2}%", g, (save_stats[i] as f64) / sum_stats))
-                .join("\n");
+        let tiers = Array::new();
+        for (((tier, count), percent), probability) in stats
+            .tiers
+            .iter()
+            .zip(stats.counts())
+            .zip(stats.percentages())
+            .zip(stats.probabilities())
+        {
+            tiers.push(&js_object(&[
+                ("tier", tier.to_string().into()),
+                ("count", (count as u32).into()),
+                ("percent", percent.into()),
+                ("probability", probability.into()),
+            ])?);
+        }
+        let queues = Array::new();
+        for q in &stats.queues {
+            let keepable: String = q.keepable.iter().map(|s| s.name()).collect();
+            queues.push(&js_object(&[
+                ("queue", q.queue.as_str().into()),
+                ("keepable", keepable.into()),
+                ("tier", q.tier.map_or(JsValue::NULL, |t| (t as u32).into())),
+            ])?);
         }
-        res
+        js_object(&[("tiers", tiers.into()), ("queues", queues.into())])
     }
 }
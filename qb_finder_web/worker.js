@@ -3,9 +3,13 @@ importScripts("pkg/qb_finder_web.js");
 async function main() {
     let legal_boards;
 
-    let response = await fetch(
-        "https://wirelyre.github.io/tetra-tools/legal-boards.leb128",
-    );
+    // served alongside the page by qb_finder_server, so the finder also works offline
+    let response = await fetch("legal-boards.leb128").catch(() => null);
+    if (!response?.ok) {
+        response = await fetch(
+            "https://wirelyre.github.io/tetra-tools/legal-boards.leb128",
+        );
+    }
     if (response.ok) {
         legal_boards = new Uint8Array(await response.arrayBuffer());
     } else {
@@ -16,32 +20,105 @@ async function main() {
     let qbf = new wasm_bindgen.QBF(legal_boards);
     postMessage({ kind: "ready" });
 
+    // set by the page through a shared buffer, since messages can't arrive mid-search
+    let abort = null;
+    let percent = -1;
+    const aborted = () => abort != null && Atomics.load(abort, 0) != 0;
+    let flushSetups = () => {};
+    const progress = (done, total) => {
+        flushSetups();
+        const now = Math.floor(100 * done / total);
+        if (now != percent) {
+            percent = now;
+            postMessage({ kind: "progress", done, total });
+        }
+        return !aborted();
+    };
+
+    // the queues seen at a PC number stand for the patterns worked out from them
+    const patterns = (query) => {
+        const hold = (query.hold ?? "").toUpperCase();
+        const bag_used = (query.bag_used ?? "").toUpperCase();
+        if (!query.pc) {
+            return [query.build_queue.toUpperCase(), query.solve_queue.toUpperCase(), hold, bag_used];
+        }
+        const [build_queue, solve_queue] = qbf.pc_queues(
+            query.pc,
+            query.build_queue.toUpperCase(),
+            query.solve_queue.toUpperCase(),
+            hold,
+            bag_used,
+        ).split("|");
+        return [build_queue, solve_queue, hold, ""];
+    };
+
     onmessage = (msg) => {
         let query = msg.data;
+        abort = query.abort ? new Int32Array(query.abort) : null;
+        percent = -1;
+        flushSetups = () => {};
 
         if (query.setup != undefined) {
-            postMessage({
-                kind: "ok",
-                res: qbf.find_min_sets(
-                    query.setup,
-                    query.build_queue,
-                    query.solve_queue,
-                    query.save,
-                ),
-            });
+            try {
+                const [, solve_queue] = patterns(query);
+                let res = qbf.find_min_sets(query.setup, solve_queue, query.save, progress);
+                postMessage(aborted() ? { kind: "cancelled", query } : { kind: "ok", res });
+            } catch (err) {
+                console.error(err);
+                postMessage({ kind: "err", err: err });
+            }
             return;
         }
 
-        qbf.set_skip_4p(query.skip_4p);
+        if (query.cache_size != undefined) {
+            qbf.set_cache_size(query.cache_size);
+        }
+        qbf.set_build_mode(query.skip_4p ? 1 : 0, Math.max(query.leave ?? 1, 1));
         qbf.set_full_cover(query.cover);
 
+        // setups are posted in batches as they are found, before min counts are known
+        let batch = [];
+        let posted = Date.now();
+        const flush = () => {
+            if (batch.length) {
+                postMessage({ kind: "partial", query, setups: batch });
+                batch = [];
+            }
+            posted = Date.now();
+        };
+        flushSetups = () => {
+            if (Date.now() - posted > 100) {
+                flush();
+            }
+        };
+        const onSetup = (setup, saves) => {
+            if (setup == null) {
+                batch = [];
+                postMessage({ kind: "superseded", query, saves });
+                return;
+            }
+            batch.push(setup);
+            flushSetups();
+        };
+
         try {
-            let setups = qbf.find(
-                query.build_queue.toUpperCase(),
-                query.solve_queue.toUpperCase(),
+            const [build_queue, solve_queue, hold, bag_used] = patterns(query);
+            let args = [
+                build_queue,
+                solve_queue,
                 query.save.toUpperCase(),
-            )
+                hold,
+                bag_used,
+                progress,
+            ];
+            let setups = (query.pareto ? qbf.find_pareto(...args) : qbf.find(...args, onSetup))
                 .split("|");
+            flush();
+
+            if (aborted()) {
+                postMessage({ kind: "cancelled", query });
+                return;
+            }
 
             if (setups[0] == "") {
                 setups = [];